@@ -2,12 +2,17 @@ use std::{
     cell::LazyCell,
     fs,
     mem::swap,
+    num::{NonZeroU64, NonZeroUsize},
     path::{MAIN_SEPARATOR, MAIN_SEPARATOR_STR, PathBuf},
+    sync::{Arc, atomic::AtomicBool},
 };
 
-use clap::{ArgAction, Parser, ValueHint};
+use clap::{ArgAction, Parser, ValueEnum, ValueHint};
 use error_stack::Report;
-use fuc_engine::{CopyOp, Error};
+use fuc_engine::{
+    CachePolicy, CopyOp, Dereference, EntryOrder, Error, IoPriority, PreserveMode, SymlinkStyle,
+    SymlinkTargets, SyncMode,
+};
 
 /// A zippy alternative to `cp`, a tool to copy files and directories
 #[derive(Parser, Debug)]
@@ -45,22 +50,329 @@ struct Cpz {
     /// symlinks themselves
     #[arg(short = 'L', long, default_value_t = false)]
     #[arg(aliases = ["follow-symlinks"])]
+    #[arg(overrides_with_all = ["no_dereference", "dereference_command_line"])]
     // Ensure we don't try to create symlinks by default as doing so is considered a privileged
     // operation: https://doc.rust-lang.org/std/os/windows/fs/fn.symlink_file.html#limitations
     #[cfg_attr(windows, arg(default_value_t = true))]
     dereference: bool,
 
+    /// Copy symlinks as symlinks, even if they were passed in as files to copy
+    #[arg(short = 'P', long, default_value_t = false)]
+    #[arg(overrides_with_all = ["dereference", "dereference_command_line"])]
+    no_dereference: bool,
+
+    /// Only follow symlinks that were passed in as files to copy
+    #[arg(short = 'H', default_value_t = false)]
+    #[arg(overrides_with_all = ["dereference", "no_dereference"])]
+    dereference_command_line: bool,
+
+    /// Copy dangling symlinks as symlinks instead of failing when following
+    /// them
+    #[arg(long, default_value_t = false)]
+    copy_dangling: bool,
+
     /// Create hard links instead of copying file data
     #[arg(short = 'l', long, default_value_t = false)]
     #[arg(aliases = ["hard-link"])]
     link: bool,
 
+    /// Copy files which can't be hard linked (e.g. because they are on a
+    /// different file system) instead of failing
+    #[arg(long, default_value_t = false)]
+    #[arg(requires = "link")]
+    fallback_to_copy: bool,
+
+    /// Create symlinks to the source files instead of copying them
+    #[arg(short = 's', long, default_value_t = false)]
+    #[arg(conflicts_with = "link")]
+    symbolic_link: bool,
+
+    /// Make the symlinks created by `--symbolic-link` relative
+    #[arg(long, default_value_t = false)]
+    #[arg(requires = "symbolic_link")]
+    relative: bool,
+
+    /// Stay on the source file system, skipping the contents of mount points
+    #[arg(short = 'x', long, default_value_t = false)]
+    one_file_system: bool,
+
+    /// How to set the permissions of copied files and directories
+    #[arg(long, value_enum, default_value_t = Mode::Umask)]
+    mode: Mode,
+
+    /// Additional attributes to copy from the source files
+    ///
+    /// Copies which will later be passed to `--link-dest` need
+    /// `--preserve=timestamps`, otherwise none of their files will match.
+    #[arg(long, value_enum, value_name = "ATTRS", value_delimiter = ',')]
+    preserve: Vec<Preserve>,
+
+    /// Hard link files which are unchanged in this previous copy instead of
+    /// copying them (like `rsync --link-dest`)
+    ///
+    /// Files match if they have the same relative path, size, modification
+    /// time, and mode. May be repeated to check multiple previous copies.
+    /// Implies `--preserve=timestamps` so that the new copy can be linked
+    /// against in the future.
+    #[arg(long, value_name = "DIR")]
+    #[arg(value_hint = ValueHint::DirPath)]
+    link_dest: Vec<PathBuf>,
+
+    /// How to set the targets of copied symlinks
+    #[arg(long, value_enum, default_value_t = Targets::Verbatim)]
+    symlink_targets: Targets,
+
+    /// How copied data should use the page cache
+    ///
+    /// Only supported on Linux.
+    #[arg(long, value_enum, default_value_t = Cache::Normal)]
+    cache: Cache,
+
+    /// How much of the copy to flush to disk before exiting
+    #[arg(long, value_enum, default_value_t = Flush::None)]
+    sync_mode: Flush,
+
+    /// Reserve the space for each file before copying it, failing early if
+    /// the destination is full
+    ///
+    /// Avoids fragmenting large files. Sparse files only have their data's
+    /// worth of space reserved so that their holes are kept. File systems
+    /// which don't support this are left alone. Only supported on Linux.
+    #[arg(long, default_value_t = false)]
+    preallocate: bool,
+
+    /// The order in which to copy the entries of each directory
+    ///
+    /// Sorting helps avoid seeking back and forth on spinning disks. Only
+    /// supported on Linux.
+    #[arg(long, value_enum, default_value_t = Order::Unordered)]
+    order: Order,
+
+    /// The number of threads to copy with
+    ///
+    /// Defaults to the number of CPUs. Large files are also split across up
+    /// to this many threads. Only supported on Linux.
+    #[arg(long)]
+    threads: Option<NonZeroUsize>,
+
+    /// How many bytes of each directory to read at once
+    ///
+    /// Only supported on Linux.
+    #[arg(long)]
+    dir_buffer_size: Option<NonZeroUsize>,
+
+    /// Pick the thread count and buffer size based on the file system and
+    /// disk being copied
+    ///
+    /// Uses few threads for spinning disks and many for network file
+    /// systems. Explicit --threads and --dir-buffer-size values take
+    /// precedence. Only supported on Linux.
+    #[arg(long, default_value_t = false)]
+    auto_tune: bool,
+
+    /// Roughly how many bytes of directories waiting to be copied may be
+    /// queued
+    ///
+    /// Once reached, directories are copied as soon as they're found instead
+    /// of being queued, down to a fixed depth. Deeper directories and the
+    /// directories passed in are always queued. Unlimited by default. Only
+    /// supported on Linux.
+    #[arg(long, value_name = "BYTES")]
+    queue_memory_limit: Option<NonZeroUsize>,
+
+    /// The most bytes to copy per second
+    ///
+    /// The limit is shared by all threads. Only supported on Linux.
+    #[arg(long, value_name = "BYTES_PER_SEC")]
+    bwlimit: Option<NonZeroU64>,
+
+    /// The I/O scheduling class to copy with, like `ionice`
+    ///
+    /// Only supported on Linux.
+    #[arg(long, value_enum)]
+    io_class: Option<IoClass>,
+
+    /// The priority within the I/O scheduling class, from 0 (highest) to 7
+    ///
+    /// Defaults to 4 and has no effect on the idle class.
+    #[arg(long, requires = "io_class")]
+    #[arg(value_parser = clap::value_parser!(u8).range(0..=7))]
+    io_level: Option<u8>,
+
+    /// Record finished files and directories in `<TO>.fuc-journal` so that an
+    /// interrupted copy can be resumed
+    ///
+    /// Each entry is flushed to disk before it's recorded so that the copy can
+    /// even be resumed after a crash. The journal is deleted once the copy
+    /// succeeds. Only supported on Linux.
+    #[arg(long, default_value_t = false)]
+    journal: bool,
+
+    /// Resume an interrupted copy made with --journal
+    ///
+    /// Finished entries which haven't changed are skipped and everything else
+    /// is copied again. Finished directories are trusted as long as their own
+    /// size and modification time are unchanged. Implies --journal. Only
+    /// supported on Linux.
+    #[arg(long, default_value_t = false)]
+    resume: bool,
+
+    /// Remove everything the copy created if it fails
+    ///
+    /// Files and directories which already existed are left in place, even
+    /// if they were overwritten. On Unix, an interrupt (SIGINT or SIGTERM)
+    /// stops the copy and rolls it back too; a second one exits immediately.
+    #[arg(long, default_value_t = false)]
+    transactional: bool,
+
+    /// Copy small files one syscall at a time instead of in `io_uring` batches
+    ///
+    /// Only has an effect on Linux builds with the `io_uring` feature.
+    #[arg(long, default_value_t = false)]
+    no_io_uring: bool,
+
     #[arg(short, long, short_alias = '?', global = true)]
     #[arg(action = ArgAction::Help, help = "Print help (use `--help` for more detail)")]
     #[arg(long_help = "Print help (use `-h` for a summary)")]
     help: Option<bool>,
 }
 
+#[derive(ValueEnum, Copy, Clone, Debug)]
+enum Mode {
+    /// Use the source's permissions, minus the umask
+    Umask,
+    /// Use the source's exact permissions, including the setuid, setgid, and
+    /// sticky bits
+    Exact,
+    /// Use the default permissions, minus the umask
+    Ignore,
+}
+
+impl From<Mode> for PreserveMode {
+    fn from(mode: Mode) -> Self {
+        match mode {
+            Mode::Umask => Self::Umask,
+            Mode::Exact => Self::Exact,
+            Mode::Ignore => Self::Ignore,
+        }
+    }
+}
+
+#[derive(ValueEnum, Copy, Clone, Debug, Eq, PartialEq)]
+enum Preserve {
+    /// Modification times
+    Timestamps,
+}
+
+#[derive(ValueEnum, Copy, Clone, Debug)]
+enum Targets {
+    /// Copy symlink targets as-is
+    Verbatim,
+    /// Point symlinks which resolve inside a copied directory at the
+    /// equivalent location in the destination
+    Relocate,
+    /// Like `relocate`, but write the relocated targets as relative paths
+    Relative,
+}
+
+impl From<Targets> for SymlinkTargets {
+    fn from(targets: Targets) -> Self {
+        match targets {
+            Targets::Verbatim => Self::Verbatim,
+            Targets::Relocate => Self::Relocate,
+            Targets::Relative => Self::RelocateRelative,
+        }
+    }
+}
+
+#[derive(ValueEnum, Copy, Clone, Debug)]
+enum Cache {
+    /// Read and write through the page cache
+    Normal,
+    /// Evict copied data from the page cache as the copy progresses
+    DropBehind,
+    /// Bypass the page cache with `O_DIRECT`, falling back to `drop-behind`
+    /// when unsupported
+    Direct,
+}
+
+impl From<Cache> for CachePolicy {
+    fn from(cache: Cache) -> Self {
+        match cache {
+            Cache::Normal => Self::Normal,
+            Cache::DropBehind => Self::DropBehind,
+            Cache::Direct => Self::Direct,
+        }
+    }
+}
+
+#[derive(ValueEnum, Copy, Clone, Debug)]
+enum Flush {
+    /// Leave flushing to the OS
+    None,
+    /// Flush the contents of each copied file
+    Files,
+    /// Flush the contents of each copied file and every destination directory
+    Directories,
+    /// Flush the destination file systems once everything has been copied
+    /// (Linux only, same as `directories` elsewhere)
+    Filesystem,
+}
+
+impl From<Flush> for SyncMode {
+    fn from(flush: Flush) -> Self {
+        match flush {
+            Flush::None => Self::None,
+            Flush::Files => Self::Files,
+            Flush::Directories => Self::Directories,
+            Flush::Filesystem => Self::Filesystem,
+        }
+    }
+}
+
+#[derive(ValueEnum, Copy, Clone, Debug)]
+enum Order {
+    /// Use the order the file system lists entries in
+    Unordered,
+    /// Sort entries by inode number
+    Inode,
+    /// Sort files by where their data starts on disk
+    Physical,
+    /// Sort entries by inode number on rotational disks
+    Auto,
+}
+
+impl From<Order> for EntryOrder {
+    fn from(order: Order) -> Self {
+        match order {
+            Order::Unordered => Self::Unordered,
+            Order::Inode => Self::Inode,
+            Order::Physical => Self::Physical,
+            Order::Auto => Self::Auto,
+        }
+    }
+}
+
+#[derive(ValueEnum, Copy, Clone, Debug)]
+enum IoClass {
+    /// Get served before everyone else (requires root)
+    Realtime,
+    /// Share the disk with everyone else, the default for all processes
+    BestEffort,
+    /// Only get served when no one else needs the disk
+    Idle,
+}
+
+impl IoClass {
+    const fn with_level(self, level: u8) -> IoPriority {
+        match self {
+            Self::Realtime => IoPriority::RealTime(level),
+            Self::BestEffort => IoPriority::BestEffort(level),
+            Self::Idle => IoPriority::Idle,
+        }
+    }
+}
+
 #[derive(thiserror::Error, Debug)]
 enum CliError {
     #[error("{0}")]
@@ -201,8 +513,11 @@ fn main() -> Result<(), Report<CliError>> {
                     Ok(false) | Err(_) => report.attach("Use --force to overwrite."),
                 }
             }
-            Error::Join | Error::BadPath | Error::Internal => Report::from(wrapper),
+            Error::MountPoint { file: _ } => {
+                Report::from(wrapper).attach("Unmount the file system before removing it.")
+            }
             Error::PreserveRoot | Error::NotFound { file: _ } => unreachable!(),
+            _ => Report::from(wrapper),
         }
     })
 }
@@ -214,15 +529,57 @@ fn copy(
         force,
         reverse_args,
         dereference,
+        no_dereference,
+        dereference_command_line,
+        copy_dangling,
         link,
+        fallback_to_copy,
+        symbolic_link,
+        relative,
+        one_file_system,
+        mode,
+        preserve,
+        symlink_targets,
+        link_dest,
+        cache,
+        sync_mode,
+        preallocate,
+        order,
+        threads,
+        dir_buffer_size,
+        auto_tune,
+        queue_memory_limit,
+        bwlimit,
+        io_class,
+        io_level,
+        journal,
+        resume,
+        transactional,
+        no_io_uring,
         help: _,
     }: Cpz,
 ) -> Result<(), Error> {
     if reverse_args {
         swap(&mut to, &mut from[0]);
     }
+    let io_priority = io_class.map(|class| class.with_level(io_level.unwrap_or(4)));
+    let cancel = transactional.then(cancel_on_interrupt).flatten();
     let from = from;
     let to = to;
+    let symbolic_link = symbolic_link.then_some(if relative {
+        SymlinkStyle::Relative
+    } else {
+        SymlinkStyle::Absolute
+    });
+    let dereference = if no_dereference {
+        Dereference::Never
+    } else if dereference_command_line {
+        Dereference::CommandLine
+    } else if dereference {
+        Dereference::Always
+    } else {
+        Dereference::Never
+    };
 
     #[allow(clippy::unnested_or_patterns)]
     let is_into_directory = LazyCell::new(|| {
@@ -255,8 +612,31 @@ fn copy(
             CopyOp::builder()
                 .files($files)
                 .force(force)
-                .follow_symlinks(dereference)
+                .dereference(dereference)
+                .copy_dangling_symlinks(copy_dangling)
                 .hard_link(link)
+                .fallback_to_copy(fallback_to_copy)
+                .maybe_symbolic_link(symbolic_link)
+                .one_file_system(one_file_system)
+                .preserve_mode(mode.into())
+                .preserve_mtime(preserve.contains(&Preserve::Timestamps))
+                .symlink_targets(symlink_targets.into())
+                .link_dest(link_dest.clone())
+                .cache_policy(cache.into())
+                .sync_mode(sync_mode.into())
+                .preallocate(preallocate)
+                .entry_order(order.into())
+                .maybe_threads(threads)
+                .maybe_dir_buffer_size(dir_buffer_size)
+                .auto_tune(auto_tune)
+                .maybe_queue_memory_limit(queue_memory_limit)
+                .maybe_bandwidth_limit(bwlimit)
+                .maybe_io_priority(io_priority)
+                .journal(journal)
+                .resume(resume)
+                .transactional(transactional)
+                .maybe_cancel(cancel.clone())
+                .io_uring(!no_io_uring)
                 .build()
                 .run()
         };
@@ -285,6 +665,41 @@ fn copy(
     }
 }
 
+/// Returns a flag which is set on the first SIGINT or SIGTERM so that the copy
+/// can stop and roll back. The default handlers are restored at that point so
+/// a second signal exits immediately.
+#[cfg(unix)]
+fn cancel_on_interrupt() -> Option<Arc<AtomicBool>> {
+    use std::sync::{OnceLock, atomic::Ordering};
+
+    static CANCEL: OnceLock<Arc<AtomicBool>> = OnceLock::new();
+
+    extern "C" fn handle(_: libc::c_int) {
+        // Only async-signal-safe operations are allowed in here.
+        if let Some(cancel) = CANCEL.get() {
+            cancel.store(true, Ordering::Relaxed);
+        }
+        unsafe {
+            libc::signal(libc::SIGINT, libc::SIG_DFL);
+            libc::signal(libc::SIGTERM, libc::SIG_DFL);
+        }
+    }
+
+    let cancel = CANCEL.get_or_init(Arc::default).clone();
+    let handler = handle as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    for signal in [libc::SIGINT, libc::SIGTERM] {
+        if unsafe { libc::signal(signal, handler) } == libc::SIG_ERR {
+            return None;
+        }
+    }
+    Some(cancel)
+}
+
+#[cfg(not(unix))]
+fn cancel_on_interrupt() -> Option<Arc<AtomicBool>> {
+    None
+}
+
 #[cfg(test)]
 mod cli_tests {
     use clap::CommandFactory;
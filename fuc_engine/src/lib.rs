@@ -1,4 +1,3 @@
-#![cfg_attr(target_os = "linux", feature(lazy_cell_into_inner))]
 #![allow(clippy::needless_pass_by_value)]
 
 use std::{borrow::Cow, io, path::PathBuf};
@@ -6,13 +5,15 @@ use std::{borrow::Cow, io, path::PathBuf};
 use thiserror::Error;
 
 pub use crate::ops::{
-    CopyOp, CopyOpBuilder, RemoveOp, RemoveOpBuilder, copy_file, remove_file,
-    remove_file as remove_dir_all,
+    CachePolicy, CopyOp, CopyOpBuilder, Dereference, EntryOrder, IoPriority, PreserveMode,
+    RemoveOp, RemoveOpBuilder, Stats, SymlinkStyle, SymlinkTargets, SyncMode, copy_file,
+    remove_file, remove_file as remove_dir_all,
 };
 
 mod ops;
 
 #[derive(Error, Debug)]
+#[non_exhaustive]
 pub enum Error {
     #[error("An I/O error occurred")]
     Io {
@@ -29,6 +30,12 @@ pub enum Error {
     AlreadyExists { file: PathBuf },
     #[error("File or directory not found: {file:?}")]
     NotFound { file: PathBuf },
+    #[error("Refusing to cross into a mount point: {file:?}")]
+    MountPoint { file: PathBuf },
+    #[error("The operation was cancelled")]
+    Cancelled,
+    #[error("{error} (rolling back also failed: {failure})")]
+    RollbackFailed { error: Box<Self>, failure: String },
     #[error("An internal bug occurred, please report this")]
     Internal,
 }
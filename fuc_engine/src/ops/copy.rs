@@ -1,10 +1,25 @@
-use std::{borrow::Cow, fmt::Debug, fs, io, marker::PhantomData, path::Path};
+use std::{
+    borrow::Cow,
+    fmt::Debug,
+    fs, io,
+    marker::PhantomData,
+    mem,
+    num::{NonZeroU64, NonZeroUsize},
+    path::{Component, Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+};
 
 use bon::Builder;
 
 use crate::{
     Error,
-    ops::{IoErr, compat::DirectoryOp},
+    ops::{
+        EntryOrder, IoErr, IoPriority, Stats, Tuning, compat::DirectoryOp, throttle::RateLimiter,
+        transaction::Transaction,
+    },
 };
 
 /// Copies a file or directory at this path.
@@ -19,6 +34,96 @@ pub fn copy_file<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> Result<(), E
         .run()
 }
 
+/// Which symlinks are followed to copy their targets instead of the links
+/// themselves.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub enum Dereference {
+    /// Copy every symlink as a symlink (like `cp -P`).
+    #[default]
+    Never,
+    /// Follow every symlink (like `cp -L`).
+    Always,
+    /// Only follow symlinks that were passed in as files to copy (like `cp
+    /// -H`).
+    CommandLine,
+}
+
+/// How the targets of copied symlinks are chosen.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub enum SymlinkTargets {
+    /// Copy symlink targets verbatim.
+    #[default]
+    Verbatim,
+    /// Point symlinks which resolve inside a copied directory at the
+    /// equivalent location in the destination. This covers absolute targets
+    /// and relative targets which leave the directory before coming back into
+    /// it. Targets are resolved lexically, so paths going through other
+    /// symlinks are not recognized.
+    Relocate,
+    /// Like [`SymlinkTargets::Relocate`], but relocated targets are always
+    /// written as relative paths.
+    RelocateRelative,
+}
+
+/// How the symlinks created in place of copied files point at their sources.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SymlinkStyle {
+    /// Point at the absolute path of the source.
+    Absolute,
+    /// Point at the source relative to the symlink's directory.
+    Relative,
+}
+
+/// How the permission bits of copied files and directories are chosen.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub enum PreserveMode {
+    /// Create files with the source's mode, letting the umask apply.
+    #[default]
+    Umask,
+    /// Apply the source's exact mode once the file has been written, including
+    /// the setuid, setgid, and sticky bits, regardless of the umask.
+    Exact,
+    /// Ignore the source's mode and create files with the default permissions
+    /// minus the umask (like `cp --no-preserve=mode`).
+    Ignore,
+}
+
+/// How copied file contents interact with the page cache.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub enum CachePolicy {
+    /// Read and write through the page cache.
+    #[default]
+    Normal,
+    /// Evict copied data from the page cache as the copy progresses so that
+    /// it doesn't push out everything else.
+    DropBehind,
+    /// Bypass the page cache with `O_DIRECT`, falling back to dropping data
+    /// behind on file systems which don't support it.
+    Direct,
+}
+
+/// How much of a copy is flushed to stable storage before it completes.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub enum SyncMode {
+    /// Leave flushing to the OS, so a crash may lose recently copied files.
+    #[default]
+    None,
+    /// Flush each copied file's data before closing it.
+    Files,
+    /// Like [`SyncMode::Files`], but also flush every destination directory
+    /// once it has been filled in so that the new entries survive a crash.
+    Directories,
+    /// Flush the destination file systems once everything has been copied.
+    /// Behaves like [`SyncMode::Directories`] on platforms other than Linux.
+    Filesystem,
+}
+
+impl SyncMode {
+    const fn syncs_files(self) -> bool {
+        matches!(self, Self::Files | Self::Directories)
+    }
+}
+
 #[derive(Debug, Builder)]
 pub struct CopyOp<
     'a,
@@ -30,10 +135,111 @@ pub struct CopyOp<
     files: F,
     #[builder(default = false)]
     force: bool,
+    #[builder(default)]
+    dereference: Dereference,
+    /// Copy symlinks which would have been followed but point nowhere as
+    /// symlinks instead of failing.
     #[builder(default = false)]
-    follow_symlinks: bool,
+    copy_dangling_symlinks: bool,
     #[builder(default = false)]
     hard_link: bool,
+    /// Copy files which can't be hard linked because they live on another
+    /// file system or have run out of links.
+    #[builder(default = false)]
+    fallback_to_copy: bool,
+    /// Create symlinks to the source files instead of copying them, taking
+    /// precedence over hard links.
+    symbolic_link: Option<SymlinkStyle>,
+    #[builder(default = false)]
+    one_file_system: bool,
+    #[builder(default)]
+    preserve_mode: PreserveMode,
+    /// Give copied files their source's modification time (like `cp
+    /// --preserve=timestamps`). Copies which will later be passed to
+    /// [`CopyOpBuilder::link_dest`] need this, otherwise none of their files
+    /// will match.
+    #[builder(default = false)]
+    preserve_mtime: bool,
+    #[builder(default)]
+    symlink_targets: SymlinkTargets,
+    /// Previous snapshots of the copied directories (like `rsync
+    /// --link-dest`). Files which are unchanged in one of them (same relative
+    /// path, size, modification time, and mode) are hard linked instead of
+    /// copied. Implies [`CopyOpBuilder::preserve_mtime`] so that the new
+    /// snapshot can be linked against in turn.
+    #[builder(default)]
+    link_dest: Vec<PathBuf>,
+    /// Only applied on Linux.
+    #[builder(default)]
+    cache_policy: CachePolicy,
+    #[builder(default)]
+    sync_mode: SyncMode,
+    /// Allocate each copied file at its full size before writing to it so
+    /// that running out of space fails early and large files aren't
+    /// fragmented. Sparse files only have their data's worth of space reserved
+    /// past the end of the file (`FALLOC_FL_KEEP_SIZE`) to keep their holes.
+    /// File systems which can't allocate space up front are left alone. Only
+    /// applied on Linux.
+    #[builder(default = false)]
+    preallocate: bool,
+    #[builder(default)]
+    entry_order: EntryOrder,
+    /// The number of threads to copy with, defaulting to the number of CPUs.
+    /// Large files are also split across this many threads, with the extra
+    /// threads shared by all of them. Only applied on Linux.
+    threads: Option<NonZeroUsize>,
+    /// How much of a directory to read at once, defaulting to 32 KiB. Only
+    /// applied on Linux.
+    dir_buffer_size: Option<NonZeroUsize>,
+    /// Pick the thread count and buffer size from the file system and disk
+    /// type of the first directory copied, favoring few threads on spinning
+    /// disks and many on network file systems. Explicit values take
+    /// precedence.
+    #[builder(default = false)]
+    auto_tune: bool,
+    /// Roughly how many bytes of directories waiting to be processed may be
+    /// queued. Past this point, workers copy the directories they find right
+    /// away instead of queueing them, down to a fixed depth with a directory
+    /// buffer per level. Deeper directories and the directories passed in are
+    /// always queued. Unlimited by default. Only applied on Linux.
+    queue_memory_limit: Option<NonZeroUsize>,
+    /// The most bytes per second to copy, shared by all threads. Only applied
+    /// on Linux.
+    bandwidth_limit: Option<NonZeroU64>,
+    /// Only applied on Linux.
+    io_priority: Option<IoPriority>,
+    /// Record the entries copied into each directory in an append-only
+    /// journal beside it (`<destination>.fuc-journal`), which is deleted once
+    /// the copy succeeds. Each entry is flushed to disk before it's recorded
+    /// so that the copy can even be resumed after a crash. Only applied on
+    /// Linux.
+    #[builder(default = false)]
+    journal: bool,
+    /// Pick up an interrupted copy where its journal left off: unchanged
+    /// entries which were finished are skipped while partial or missing ones
+    /// are copied again. Finished directories are skipped whole as long as
+    /// their own size and modification time are unchanged, so edits to files
+    /// deeper down may go unnoticed. Implies
+    /// [`CopyOpBuilder::journal`] and allows the destination to exist. Other
+    /// platforms copy everything again.
+    #[builder(default = false)]
+    resume: bool,
+    /// Remove every file and directory the copy created if it fails, leaving
+    /// the ones which already existed alone (even if they were overwritten).
+    #[builder(default = false)]
+    transactional: bool,
+    /// Stop copying once this is set, failing with [`Error::Cancelled`]. It's
+    /// checked before each directory and each of the files passed in, so
+    /// files which are already being copied are finished first. Transactional
+    /// copies are rolled back like after any other failure.
+    cancel: Option<Arc<AtomicBool>>,
+    /// Filled in with what the copy observed once it finishes.
+    stats: Option<Arc<Stats>>,
+    /// Copy small files in batches through `io_uring` when the kernel supports
+    /// it. Only applied on Linux with the `io_uring` feature enabled.
+    #[builder(default = true)]
+    #[cfg_attr(not(all(target_os = "linux", feature = "io_uring")), allow(dead_code))]
+    io_uring: bool,
     #[builder(skip)]
     _marker1: PhantomData<&'a I1>,
     #[builder(skip)]
@@ -53,17 +259,324 @@ impl<
     /// # Errors
     ///
     /// Returns the underlying I/O errors that occurred.
-    pub fn run(self) -> Result<(), Error> {
-        let copy = compat::copy_impl(self.follow_symlinks, self.hard_link);
-        let result = schedule_copies(self, &copy);
-        copy.finish().and(result)
+    pub fn run(mut self) -> Result<(), Error> {
+        let link_dest = mem::take(&mut self.link_dest);
+        if !cfg!(target_os = "linux") && self.sync_mode == SyncMode::Filesystem {
+            self.sync_mode = SyncMode::Directories;
+        }
+        let sync_mode = self.sync_mode;
+        #[cfg(target_os = "linux")]
+        let _io_priority = crate::ops::IoPriorityGuard::new(self.io_priority)?;
+        let throttle = self
+            .bandwidth_limit
+            .map(|limit| Arc::new(RateLimiter::new(limit)));
+        let settings = Settings {
+            follow_symlinks: self.dereference == Dereference::Always,
+            copy_dangling_symlinks: self.copy_dangling_symlinks,
+            hard_link: self.hard_link,
+            fallback_to_copy: self.fallback_to_copy,
+            symbolic_link: self.symbolic_link,
+            one_file_system: self.one_file_system,
+            preserve_mode: self.preserve_mode,
+            preserve_mtime: self.preserve_mtime || !link_dest.is_empty(),
+            symlink_targets: self.symlink_targets,
+            cache_policy: self.cache_policy,
+            sync_mode,
+            preallocate: self.preallocate,
+            entry_order: self.entry_order,
+            journal: self.journal || self.resume,
+            resume: self.resume,
+            // Only the io_uring backend batches files.
+            #[cfg(all(target_os = "linux", feature = "io_uring"))]
+            io_uring: false,
+        };
+        let tuning = Tuning {
+            threads: self.threads,
+            dir_buffer_size: self.dir_buffer_size,
+            auto: self.auto_tune,
+            queue_memory_limit: self.queue_memory_limit,
+            io_priority: self.io_priority,
+        };
+
+        let cancel = self.cancel.clone();
+        let stats = self.stats.clone();
+
+        #[cfg(all(target_os = "linux", feature = "io_uring"))]
+        if self.io_uring && compat::io_uring::is_supported() {
+            let copy = compat::io_uring::copy_impl(
+                settings,
+                link_dest,
+                tuning,
+                throttle.clone(),
+                cancel,
+                stats,
+            );
+            return self.run_with(copy, throttle);
+        }
+        let copy = compat::copy_impl(settings, link_dest, tuning, throttle.clone(), cancel, stats);
+        self.run_with(copy, throttle)
+    }
+
+    fn run_with(
+        self,
+        copy: impl DirectoryOp<(Cow<'a, Path>, Cow<'b, Path>, Option<Arc<Transaction>>)>,
+        throttle: Option<Arc<RateLimiter>>,
+    ) -> Result<(), Error> {
+        let sync_mode = self.sync_mode;
+        let transaction = self.transactional.then(Arc::<Transaction>::default);
+        let mut deferred_modes = Vec::new();
+        let mut sync_dirs = Vec::new();
+        let result = schedule_copies(
+            self,
+            &copy,
+            throttle.as_deref(),
+            transaction.as_ref(),
+            &mut deferred_modes,
+            &mut sync_dirs,
+        );
+        if result.is_err() {
+            copy.fail();
+        }
+        let finished = copy.finish();
+        let result = result.and(finished);
+        let restored = restore_dir_modes(deferred_modes);
+        let result = result
+            .and(restored)
+            .and_then(|()| sync_destinations(sync_dirs, sync_mode));
+        match (result, transaction) {
+            (Err(error), Some(transaction)) => Err(transaction.roll_back_after(error)),
+            (result, _) => result,
+        }
+    }
+}
+
+/// Fails if the operation was cancelled.
+fn check_cancelled(cancel: Option<&AtomicBool>) -> Result<(), Error> {
+    if cancel.is_some_and(|cancel| cancel.load(Ordering::Relaxed)) {
+        return Err(Error::Cancelled);
+    }
+    Ok(())
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Settings {
+    follow_symlinks: bool,
+    copy_dangling_symlinks: bool,
+    hard_link: bool,
+    fallback_to_copy: bool,
+    symbolic_link: Option<SymlinkStyle>,
+    #[cfg_attr(not(unix), allow(dead_code))]
+    one_file_system: bool,
+    preserve_mode: PreserveMode,
+    preserve_mtime: bool,
+    symlink_targets: SymlinkTargets,
+    cache_policy: CachePolicy,
+    sync_mode: SyncMode,
+    preallocate: bool,
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    entry_order: EntryOrder,
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    journal: bool,
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    resume: bool,
+    #[cfg(all(target_os = "linux", feature = "io_uring"))]
+    io_uring: bool,
+}
+
+impl Settings {
+    /// Resolves the roots of a directory copy into absolute and lexically
+    /// normalized paths if symlinks are going to be pointed at them.
+    fn roots<'a, 'b>(
+        self,
+        from: Cow<'a, Path>,
+        to: Cow<'b, Path>,
+    ) -> io::Result<(Cow<'a, Path>, Cow<'b, Path>)> {
+        if self.symlink_targets == SymlinkTargets::Verbatim && self.symbolic_link.is_none() {
+            return Ok((from, to));
+        }
+        Ok((Cow::Owned(absolute(&from)?), Cow::Owned(absolute(&to)?)))
+    }
+}
+
+/// Maps symlink targets pointing inside a copied directory to the destination.
+#[derive(Debug)]
+struct Relocation {
+    from_root: PathBuf,
+    to_root: PathBuf,
+    relative: bool,
+}
+
+impl Relocation {
+    /// Expects the roots returned by [`Settings::roots`] and returns [`None`]
+    /// if targets are to be copied verbatim.
+    fn new(from: &Path, to: &Path, symlink_targets: SymlinkTargets) -> Option<Self> {
+        let relative = match symlink_targets {
+            SymlinkTargets::Verbatim => return None,
+            SymlinkTargets::Relocate => false,
+            SymlinkTargets::RelocateRelative => true,
+        };
+        Some(Self {
+            from_root: from.to_path_buf(),
+            to_root: to.to_path_buf(),
+            relative,
+        })
+    }
+
+    /// Computes the new target of a symlink found in `link_dir` (a directory
+    /// under the source root) or [`None`] if it should be copied verbatim.
+    fn rewrite(&self, link_dir: &Path, target: &Path) -> Option<PathBuf> {
+        let mut resolved = if target.is_absolute() {
+            PathBuf::new()
+        } else {
+            link_dir.to_path_buf()
+        };
+        let mut escaped = false;
+        for component in target.components() {
+            match component {
+                Component::Prefix(_) | Component::RootDir | Component::Normal(_) => {
+                    resolved.push(component);
+                }
+                Component::CurDir => {}
+                Component::ParentDir => {
+                    resolved.pop();
+                    escaped |= !resolved.starts_with(&self.from_root);
+                }
+            }
+        }
+        if target.is_relative() && !escaped {
+            // The link stays inside the tree and is therefore already correct.
+            return None;
+        }
+
+        let to_target = self
+            .to_root
+            .join(resolved.strip_prefix(&self.from_root).ok()?);
+        if self.relative || target.is_relative() {
+            let to_link_dir = self
+                .to_root
+                .join(link_dir.strip_prefix(&self.from_root).ok()?);
+            Some(relative_path(&to_link_dir, &to_target))
+        } else {
+            Some(to_target)
+        }
+    }
+}
+
+/// Returns the target of a symlink in `to_dir` pointing at `from`, both of
+/// which must be absolute and normalized.
+fn symlink_target<'a>(from: &'a Path, to_dir: &Path, style: SymlinkStyle) -> Cow<'a, Path> {
+    match style {
+        SymlinkStyle::Absolute => Cow::Borrowed(from),
+        SymlinkStyle::Relative => Cow::Owned(relative_path(to_dir, from)),
+    }
+}
+
+fn absolute(path: &Path) -> io::Result<PathBuf> {
+    std::path::absolute(path).map(|path| normalize(&path))
+}
+
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            _ => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+fn relative_path(from_dir: &Path, to: &Path) -> PathBuf {
+    let mut from_components = from_dir.components().peekable();
+    let mut to_components = to.components().peekable();
+    while from_components.peek().is_some() && from_components.peek() == to_components.peek() {
+        from_components.next();
+        to_components.next();
+    }
+
+    let relative = from_components
+        .map(|_| Component::ParentDir)
+        .chain(to_components)
+        .collect::<PathBuf>();
+    if relative.as_os_str().is_empty() {
+        PathBuf::from(".")
+    } else {
+        relative
+    }
+}
+
+/// Applies the real permissions of read-only root directories which were
+/// created with owner access so that they could be filled in.
+#[cfg_attr(feature = "tracing", tracing::instrument(level = "trace"))]
+fn restore_dir_modes(deferred_modes: Vec<(PathBuf, u32)>) -> Result<(), Error> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mut result = Ok(());
+        for (dir, mode) in deferred_modes {
+            let r = fs::set_permissions(&dir, fs::Permissions::from_mode(mode))
+                .map_io_err(|| format!("Failed to set directory permissions: {dir:?}"));
+            result = result.and(r);
+        }
+        result
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = deferred_modes;
+        Ok(())
+    }
+}
+
+/// Flushes the directories which received the top-level copies, or the file
+/// systems they live on.
+#[cfg_attr(feature = "tracing", tracing::instrument(level = "trace"))]
+#[cfg_attr(not(target_os = "linux"), allow(unused_variables))]
+#[cfg_attr(not(unix), allow(unused_mut, clippy::needless_pass_by_value))]
+fn sync_destinations(mut dirs: Vec<PathBuf>, sync_mode: SyncMode) -> Result<(), Error> {
+    #[cfg(unix)]
+    {
+        dirs.sort_unstable();
+        dirs.dedup();
+        for dir in dirs {
+            let file =
+                fs::File::open(&dir).map_io_err(|| format!("Failed to open directory: {dir:?}"))?;
+            #[cfg(target_os = "linux")]
+            if sync_mode == SyncMode::Filesystem {
+                rustix::fs::syncfs(&file)
+                    .map_io_err(|| format!("Failed to sync file system: {dir:?}"))?;
+                continue;
+            }
+            file.sync_all()
+                .map_io_err(|| format!("Failed to sync directory: {dir:?}"))?;
+        }
+        Ok(())
+    }
+    #[cfg(not(unix))]
+    {
+        // Directories can't be flushed on Windows.
+        Ok(())
     }
 }
 
+/// Flushes a copied file's data to disk.
+fn sync_file(path: &Path) -> io::Result<()> {
+    // Windows can only flush files which were opened for writing.
+    #[cfg(windows)]
+    let file = fs::File::options().write(true).open(path)?;
+    #[cfg(not(windows))]
+    let file = fs::File::open(path)?;
+    file.sync_data()
+}
+
 #[cfg_attr(
     feature = "tracing",
     tracing::instrument(level = "trace", skip(files, copy))
 )]
+#[cfg_attr(not(unix), allow(unused_variables, clippy::ptr_arg))]
 fn schedule_copies<
     'a,
     'b,
@@ -74,52 +587,135 @@ fn schedule_copies<
     CopyOp {
         files,
         force,
-        follow_symlinks,
+        dereference,
+        copy_dangling_symlinks,
         hard_link,
+        fallback_to_copy,
+        symbolic_link,
+        one_file_system: _,
+        preserve_mode,
+        preserve_mtime,
+        symlink_targets: _,
+        link_dest: _,
+        cache_policy,
+        sync_mode,
+        preallocate,
+        entry_order: _,
+        threads: _,
+        dir_buffer_size: _,
+        auto_tune: _,
+        queue_memory_limit: _,
+        bandwidth_limit: _,
+        io_priority: _,
+        journal: _,
+        resume,
+        transactional: _,
+        cancel,
+        stats: _,
+        io_uring: _,
         _marker1: _,
         _marker2: _,
     }: CopyOp<'a, 'b, I1, I2, F>,
-    copy: &impl DirectoryOp<(Cow<'a, Path>, Cow<'b, Path>)>,
+    copy: &impl DirectoryOp<(Cow<'a, Path>, Cow<'b, Path>, Option<Arc<Transaction>>)>,
+    throttle: Option<&RateLimiter>,
+    transaction: Option<&Arc<Transaction>>,
+    deferred_modes: &mut Vec<(PathBuf, u32)>,
+    sync_dirs: &mut Vec<PathBuf>,
 ) -> Result<(), Error> {
     for (from, to) in files {
+        check_cancelled(cancel.as_deref())?;
         let from = from.into();
         let to = to.into();
-        if !force {
-            match to.symlink_metadata() {
-                Ok(_) => {
-                    return Err(Error::AlreadyExists {
-                        file: to.into_owned(),
-                    });
-                }
-                Err(e) if e.kind() == io::ErrorKind::NotFound => {
-                    // Do nothing, this is good
-                }
-                r => {
-                    r.map_io_err(|| format!("Failed to read metadata for file: {to:?}"))?;
-                }
+        if matches!(sync_mode, SyncMode::Directories | SyncMode::Filesystem) {
+            let dir = match to.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => Path::new("."),
+            };
+            if sync_dirs.last().map(PathBuf::as_path) != Some(dir) {
+                sync_dirs.push(dir.to_path_buf());
             }
         }
+        if !force
+            && !resume
+            && path_exists(&to)
+                .map_io_err(|| format!("Failed to read metadata for file: {to:?}"))?
+        {
+            return Err(Error::AlreadyExists {
+                file: to.into_owned(),
+            });
+        }
 
-        let from_metadata = if follow_symlinks {
-            from.metadata()
-        } else {
+        let from_metadata = if dereference == Dereference::Never {
             from.symlink_metadata()
+        } else {
+            match from.metadata() {
+                Err(e) if copy_dangling_symlinks && e.kind() == io::ErrorKind::NotFound => {
+                    from.symlink_metadata()
+                }
+                r => r,
+            }
         }
         .map_io_err(|| format!("Failed to read metadata for file: {from:?}"))?;
+        if !from_metadata.is_dir()
+            && let Some(transaction) = transaction
+            && !path_exists(&to)
+                .map_io_err(|| format!("Failed to read metadata for file: {to:?}"))?
+        {
+            transaction.record(to.to_path_buf());
+        }
 
         if from_metadata.is_dir() {
             #[cfg_attr(not(unix), allow(unused_mut))]
             let mut builder = fs::DirBuilder::new();
             #[cfg(unix)]
-            {
+            if preserve_mode != PreserveMode::Ignore {
                 use std::os::unix::fs::{DirBuilderExt, MetadataExt};
-                builder.mode(from_metadata.mode());
+                builder.mode(from_metadata.mode() | 0o700);
+            }
+            let created = match builder.create(&to) {
+                Err(e) if (force || resume) && e.kind() == io::ErrorKind::AlreadyExists => false,
+                r => r
+                    .map(|()| true)
+                    .map_io_err(|| format!("Failed to create directory: {to:?}"))?,
+            };
+            // Only the entries created inside directories which already existed need
+            // to be recorded.
+            let transaction = match transaction {
+                Some(transaction) if created => {
+                    transaction.record(to.to_path_buf());
+                    None
+                }
+                transaction => transaction.cloned(),
+            };
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::MetadataExt;
+
+                let added = 0o700 & !from_metadata.mode();
+                // A resumed copy owns the directory left by the run it picks up.
+                let owned = created || resume;
+                if preserve_mode == PreserveMode::Exact {
+                    deferred_modes.push((to.to_path_buf(), from_metadata.mode() & 0o7777));
+                } else if owned && preserve_mode == PreserveMode::Umask && added != 0 {
+                    let to_mode = to
+                        .symlink_metadata()
+                        .map_io_err(|| format!("Failed to read metadata for file: {to:?}"))?
+                        .mode();
+                    deferred_modes.push((to.to_path_buf(), to_mode & !added & 0o7777));
+                }
             }
-            match builder.create(&to) {
-                Err(e) if force && e.kind() == io::ErrorKind::AlreadyExists => {}
-                r => r.map_io_err(|| format!("Failed to create directory: {to:?}"))?,
+            copy.run((from, to, transaction))?;
+        } else if let Some(style) = symbolic_link {
+            match fs::remove_file(&to) {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+                r => r.map_io_err(|| format!("Failed to remove existing file: {to:?}"))?,
             }
-            copy.run((from, to))?;
+            let from = absolute(&from).map_io_err(|| "Failed to resolve the current directory.")?;
+            let to_dir = absolute(&to).map_io_err(|| "Failed to resolve the current directory.")?;
+            let to_dir = to_dir.parent().unwrap_or(&to_dir);
+            let target = symlink_target(&from, to_dir, style);
+            symlink_file(&target, &to)
+                .map_io_err(|| format!("Failed to create symlink: {to:?} -> {target:?}"))?;
         } else if from_metadata.is_symlink() {
             let link =
                 fs::read_link(&from).map_io_err(|| format!("Failed to read symlink: {from:?}"))?;
@@ -127,10 +723,11 @@ fn schedule_copies<
                 Err(e) if e.kind() == io::ErrorKind::NotFound => (),
                 r => r.map_io_err(|| format!("Failed to remove existing file: {to:?}"))?,
             }
-            if hard_link {
-                fs::hard_link(&link, &to)
-                    .map_io_err(|| format!("Failed to create hard link: {to:?} -> {link:?}"))?;
-            } else {
+            // Link the symlink itself, just like the symlinks inside directories.
+            let linked = hard_link
+                && hard_link_or_fallback(&from, &to, fallback_to_copy)
+                    .map_io_err(|| format!("Failed to create hard link: {to:?} -> {from:?}"))?;
+            if !linked {
                 let run = || {
                     #[cfg(unix)]
                     {
@@ -150,101 +747,398 @@ fn schedule_copies<
                 Err(e) if e.kind() == io::ErrorKind::NotFound => (),
                 r => r.map_io_err(|| format!("Failed to remove existing file: {to:?}"))?,
             }
-            fs::hard_link(&from, &to)
-                .map_io_err(|| format!("Failed to create hard link: {to:?} -> {from:?}"))?;
+            // Hard links never follow symlinks on their own, so find the file we
+            // were asked to dereference.
+            let target = if from.is_symlink() {
+                Cow::Owned(
+                    fs::canonicalize(&from)
+                        .map_io_err(|| format!("Failed to resolve symlink: {from:?}"))?,
+                )
+            } else {
+                Cow::Borrowed(&*from)
+            };
+            let linked = hard_link_or_fallback(&target, &to, fallback_to_copy)
+                .map_io_err(|| format!("Failed to create hard link: {to:?} -> {target:?}"))?;
+            if !linked {
+                copy_file_with_mode(
+                    &from,
+                    &to,
+                    preserve_mode,
+                    cache_policy,
+                    preallocate,
+                    throttle,
+                )
+                .map_io_err(|| format!("Failed to copy file: {from:?}"))?;
+                if preserve_mtime {
+                    copy_mtime(&from, &to)
+                        .map_io_err(|| format!("Failed to set file modification time: {to:?}"))?;
+                }
+                if sync_mode.syncs_files() {
+                    sync_file(&to).map_io_err(|| format!("Failed to sync file: {to:?}"))?;
+                }
+            }
         } else {
-            fs::copy(&from, &to).map_io_err(|| format!("Failed to copy file: {from:?}"))?;
+            copy_file_with_mode(
+                &from,
+                &to,
+                preserve_mode,
+                cache_policy,
+                preallocate,
+                throttle,
+            )
+            .map_io_err(|| format!("Failed to copy file: {from:?}"))?;
+            if preserve_mtime {
+                copy_mtime(&from, &to)
+                    .map_io_err(|| format!("Failed to set file modification time: {to:?}"))?;
+            }
+            if sync_mode.syncs_files() {
+                sync_file(&to).map_io_err(|| format!("Failed to sync file: {to:?}"))?;
+            }
         }
     }
     Ok(())
 }
 
+/// Returns whether anything, even a dangling symlink, lives at the path.
+fn path_exists(path: &Path) -> io::Result<bool> {
+    match path.symlink_metadata() {
+        Ok(_) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Returns whether the file was linked or should be copied instead.
+fn hard_link_or_fallback(from: &Path, to: &Path, fallback_to_copy: bool) -> io::Result<bool> {
+    match fs::hard_link(from, to) {
+        Err(e)
+            if fallback_to_copy
+                && matches!(
+                    e.kind(),
+                    io::ErrorKind::CrossesDevices | io::ErrorKind::TooManyLinks
+                ) =>
+        {
+            Ok(false)
+        }
+        r => r.map(|()| true),
+    }
+}
+
+fn symlink_file(target: &Path, link: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::symlink(target, link)
+    }
+    #[cfg(windows)]
+    {
+        std::os::windows::fs::symlink_file(target, link)
+    }
+}
+
+fn copy_mtime(from: &Path, to: &Path) -> io::Result<()> {
+    fs::File::options()
+        .write(true)
+        .open(to)?
+        .set_modified(fs::metadata(from)?.modified()?)
+}
+
+fn copy_file_with_mode(
+    from: &Path,
+    to: &Path,
+    preserve_mode: PreserveMode,
+    cache_policy: CachePolicy,
+    preallocate: bool,
+    throttle: Option<&RateLimiter>,
+) -> io::Result<()> {
+    #[cfg(target_os = "linux")]
+    if cache_policy != CachePolicy::Normal || preallocate || throttle.is_some() {
+        return compat::copy_file_with_options(
+            from,
+            to,
+            preserve_mode,
+            cache_policy,
+            preallocate,
+            throttle,
+        );
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = (cache_policy, preallocate, throttle);
+
+    match preserve_mode {
+        PreserveMode::Umask => fs::copy(from, to).map(|_| ()),
+        PreserveMode::Exact => {
+            fs::copy(from, to)?;
+            // Writing to the file may have cleared its setuid and setgid bits.
+            fs::set_permissions(to, from.metadata()?.permissions())
+        }
+        PreserveMode::Ignore => {
+            io::copy(&mut fs::File::open(from)?, &mut fs::File::create(to)?).map(|_| ())
+        }
+    }
+}
+
 #[cfg(target_os = "linux")]
 mod compat {
     use std::{
+        alloc::{Layout, alloc_zeroed, dealloc},
         borrow::Cow,
-        cell::{Cell, LazyCell},
+        cell::{Cell, OnceCell, RefCell},
         env,
-        ffi::{CStr, CString},
+        ffi::{CStr, CString, OsStr},
         fmt::{Debug, Formatter},
-        fs::File,
+        fs::{File, OpenOptions},
         io,
+        io::{Read, Write},
+        mem,
         mem::MaybeUninit,
-        num::NonZeroUsize,
-        os::unix::io::{AsFd, OwnedFd},
-        path::Path,
+        num::NonZeroU64,
+        ops::{Deref, DerefMut},
+        os::unix::{
+            ffi::OsStrExt,
+            fs::{FileExt, MetadataExt, OpenOptionsExt, PermissionsExt},
+            io::{AsFd, OwnedFd},
+        },
+        path::{MAIN_SEPARATOR, Path, PathBuf},
+        ptr::NonNull,
+        slice,
+        sync::{
+            Arc,
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+        },
         thread,
         thread::JoinHandle,
     };
 
-    use crossbeam_channel::{Receiver, Sender};
     use rustix::{
         fs::{
-            AtFlags, CWD, FileType, Mode, OFlags, RawDir, StatxFlags, copy_file_range, linkat,
-            mkdirat, openat, readlinkat, statx, symlinkat,
+            Advice, AtFlags, CWD, FallocateFlags, FileType, Mode, OFlags, SeekFrom, Statx,
+            StatxFlags, Timespec, Timestamps, UTIME_OMIT, chmodat, copy_file_range, fadvise,
+            fallocate, fchmod, fcntl_getfl, fcntl_setfl, fdatasync, fsync, ftruncate, futimens,
+            linkat, mkdirat, openat, readlinkat, seek, statx, symlinkat, unlinkat,
         },
         io::Errno,
         thread::{UnshareFlags, unshare_unsafe},
     };
 
+    use super::{
+        CachePolicy, PreserveMode, Relocation, Settings, SymlinkStyle, SyncMode, absolute,
+        check_cancelled, symlink_target,
+    };
     use crate::{
         Error,
-        ops::{IoErr, compat::DirectoryOp, concat_cstrs, join_cstr_paths, path_buf_to_cstring},
+        ops::{
+            Arcable, DeviceId, DirEntries, EntryOrder, IoErr, IoPriority, Stats, Tuning,
+            compat::DirectoryOp,
+            concat_cstrs, is_mount_point, join_cstr_paths,
+            journal::{Journal, Kind, Stamp, journal_path},
+            path_buf_to_cstring,
+            scheduler::{LocalQueue, MAX_DESCENT, Queued, Scheduler},
+            set_io_priority,
+            throttle::RateLimiter,
+            transaction::Transaction,
+        },
     };
 
-    struct Impl<LF: FnOnce() -> (Sender<TreeNode>, JoinHandle<Result<(), Error>>)> {
-        scheduling: LazyCell<(Sender<TreeNode>, JoinHandle<Result<(), Error>>), LF>,
+    const DEFAULT_DIR_BUFFER_SIZE: usize = 32768;
+
+    struct Impl {
+        scheduling: OnceCell<JoinHandle<Result<(), Error>>>,
+        scheduler: Arc<Scheduler<Task>>,
+        settings: Settings,
+        link_dest: Vec<PathBuf>,
+        tuning: Tuning,
+        throttle: Option<Arc<RateLimiter>>,
+        cancel: Option<Arc<AtomicBool>>,
+        stats: Option<Arc<Stats>>,
+        journals: RefCell<Vec<Arc<Journal>>>,
     }
 
     pub fn copy_impl<'a, 'b>(
-        follow_symlinks: bool,
-        hard_link: bool,
-    ) -> impl DirectoryOp<(Cow<'a, Path>, Cow<'b, Path>)> {
-        let scheduling = LazyCell::new(move || {
-            let (tx, rx) = crossbeam_channel::unbounded();
-            (
-                tx,
-                if hard_link {
-                    thread::spawn(move || root_worker_thread::<true>(rx, follow_symlinks))
-                } else {
-                    thread::spawn(move || root_worker_thread::<false>(rx, follow_symlinks))
-                },
-            )
-        });
+        settings: Settings,
+        link_dest: Vec<PathBuf>,
+        tuning: Tuning,
+        throttle: Option<Arc<RateLimiter>>,
+        cancel: Option<Arc<AtomicBool>>,
+        stats: Option<Arc<Stats>>,
+    ) -> impl DirectoryOp<(Cow<'a, Path>, Cow<'b, Path>, Option<Arc<Transaction>>)> {
+        Impl {
+            scheduling: OnceCell::new(),
+            scheduler: Arc::new(Scheduler::new(tuning.queue_memory_limit)),
+            settings,
+            link_dest,
+            tuning,
+            throttle,
+            cancel,
+            stats,
+            journals: RefCell::default(),
+        }
+    }
 
-        Impl { scheduling }
+    impl Impl {
+        /// Starts the workers, sized for the first directory being copied.
+        fn start(&self, from: &CStr, to: &CStr) -> JoinHandle<Result<(), Error>> {
+            let Self {
+                scheduling: _,
+                ref scheduler,
+                settings,
+                link_dest: _,
+                tuning,
+                ref throttle,
+                ref cancel,
+                stats: _,
+                journals: _,
+            } = *self;
+            let (threads, buffer_size) = tuning.resolve(&[from, to], DEFAULT_DIR_BUFFER_SIZE);
+            let io_priority = tuning.io_priority;
+            let scheduler = scheduler.clone();
+            let throttle = throttle.clone();
+            let cancel = cancel.clone();
+            // The root worker is one of the threads.
+            let spare_threads = SpareThreads::new(threads - 1);
+
+            if settings.hard_link {
+                thread::spawn(move || {
+                    root_worker_thread::<true>(
+                        &scheduler,
+                        settings,
+                        threads,
+                        buffer_size,
+                        io_priority,
+                        throttle.as_deref(),
+                        cancel.as_deref(),
+                        &spare_threads,
+                    )
+                })
+            } else {
+                thread::spawn(move || {
+                    root_worker_thread::<false>(
+                        &scheduler,
+                        settings,
+                        threads,
+                        buffer_size,
+                        io_priority,
+                        throttle.as_deref(),
+                        cancel.as_deref(),
+                        &spare_threads,
+                    )
+                })
+            }
+        }
     }
 
-    impl<LF: FnOnce() -> (Sender<TreeNode>, JoinHandle<Result<(), Error>>)>
-        DirectoryOp<(Cow<'_, Path>, Cow<'_, Path>)> for Impl<LF>
-    {
+    impl DirectoryOp<(Cow<'_, Path>, Cow<'_, Path>, Option<Arc<Transaction>>)> for Impl {
         #[cfg_attr(feature = "tracing", tracing::instrument(level = "trace", skip(self)))]
-        fn run(&self, (from, to): (Cow<Path>, Cow<Path>)) -> Result<(), Error> {
+        fn run(
+            &self,
+            (from, to, transaction): (Cow<Path>, Cow<Path>, Option<Arc<Transaction>>),
+        ) -> Result<(), Error> {
+            let (from, to) = self
+                .settings
+                .roots(from, to)
+                .map_io_err(|| "Failed to resolve the current directory.")?;
+            let relocation = Relocation::new(&from, &to, self.settings.symlink_targets);
+            let journal_path = if self.settings.journal {
+                let to = absolute(&to).map_io_err(|| "Failed to resolve the current directory.")?;
+                Some(journal_path(&to).ok_or(Error::BadPath)?)
+            } else {
+                None
+            };
             let to = path_buf_to_cstring(to.into_owned())?;
             let root_to_inode = {
                 let to_metadata = statx(CWD, &to, AtFlags::SYMLINK_NOFOLLOW, StatxFlags::INO)
                     .map_io_err(|| format!("Failed to stat directory: {to:?}"))?;
                 to_metadata.stx_ino
             };
+            let from = path_buf_to_cstring(from.into_owned())?;
+            let root_dev = if self.settings.one_file_system {
+                let from_metadata = statx(CWD, &from, AtFlags::empty(), StatxFlags::empty())
+                    .map_io_err(|| format!("Failed to stat directory: {from:?}"))?;
+                Some(DeviceId::from(&from_metadata))
+            } else {
+                None
+            };
+            let entry_order = self.settings.entry_order.resolve(&from);
+            let journal = if let Some(path) = journal_path {
+                // Directories merged into the same destination share its journal.
+                let existing = self
+                    .journals
+                    .borrow()
+                    .iter()
+                    .find(|journal| journal.path() == path)
+                    .cloned();
+                let journal = if let Some(journal) = existing {
+                    journal
+                } else {
+                    let journal = Journal::open(&path, &to, self.settings.resume)
+                        .map_io_err(|| format!("Failed to open journal: {path:?}"))?;
+                    let journal = Arc::new(journal);
+                    self.journals.borrow_mut().push(journal.clone());
+                    journal
+                };
+                let stamp = Stamp::of(CWD, &from, AtFlags::empty())
+                    .map_io_err(|| format!("Failed to stat directory: {from:?}"))?;
+                if journal.completed(Kind::Dir, &to, None, stamp) {
+                    return Ok(());
+                }
+                Some((journal, stamp))
+            } else {
+                None
+            };
 
-            let (tasks, _) = &*self.scheduling;
-            tasks
-                .send(TreeNode {
-                    from: path_buf_to_cstring(from.into_owned())?,
+            self.scheduling.get_or_init(|| self.start(&from, &to));
+            self.scheduler.inject(Task::Dir(TreeNode {
+                name: CString::default(),
+                roots: Arc::new(Roots {
+                    from,
                     to,
-                    root_to_inode,
-                    messages: tasks.clone(),
-                })
-                .map_err(|_| Error::Internal)
+                    link_dest: self
+                        .link_dest
+                        .iter()
+                        .map(|dir| path_buf_to_cstring(dir.clone()))
+                        .collect::<Result<_, _>>()?,
+                }),
+                root_to_inode,
+                root_dev,
+                relocation: relocation.map(Arc::new),
+                deferred_mode: None,
+                entry_order,
+                journal,
+                transaction,
+                done: false,
+                parent: None,
+            }));
+            Ok(())
+        }
+
+        fn fail(&self) {
+            // Keep the journals around so that the copy can be resumed.
+            self.journals.borrow_mut().clear();
         }
 
         #[cfg_attr(feature = "tracing", tracing::instrument(level = "trace", skip(self)))]
         fn finish(self) -> Result<(), Error> {
-            let Self { scheduling } = self;
+            let Self {
+                scheduling,
+                scheduler,
+                settings: _,
+                link_dest: _,
+                tuning: _,
+                throttle: _,
+                cancel: _,
+                stats,
+                journals,
+            } = self;
 
-            if let Ok((tasks, thread)) = LazyCell::into_inner(scheduling) {
-                drop(tasks);
-                thread.join().map_err(|_| Error::Join)??;
+            scheduler.close();
+            let result = scheduling.into_inner().map_or(Ok(Ok(())), JoinHandle::join);
+            if let Some(stats) = stats {
+                stats.record_peak_queued_bytes(scheduler.peak_queued_bytes());
+            }
+            result.map_err(|_| Error::Join)??;
+            for journal in journals.into_inner() {
+                journal
+                    .remove()
+                    .map_io_err(|| format!("Failed to remove journal: {:?}", journal.path()))?;
             }
             Ok(())
         }
@@ -258,49 +1152,82 @@ mod compat {
         Ok(())
     }
 
-    #[cfg_attr(feature = "tracing", tracing::instrument(level = "trace", skip(tasks)))]
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip(scheduler))
+    )]
+    #[allow(clippy::too_many_arguments)]
     fn root_worker_thread<const HARD_LINK: bool>(
-        tasks: Receiver<TreeNode>,
-        follow_symlinks: bool,
+        scheduler: &Scheduler<Task>,
+        settings: Settings,
+        threads: usize,
+        buffer_size: usize,
+        io_priority: Option<IoPriority>,
+        throttle: Option<&RateLimiter>,
+        cancel: Option<&AtomicBool>,
+        spare_threads: &SpareThreads,
     ) -> Result<(), Error> {
         unshare_files()?;
-
-        let mut available_parallelism =
-            thread::available_parallelism().map_or(1, NonZeroUsize::get) - 1;
+        set_io_priority(io_priority)?;
 
         thread::scope(|scope| {
-            let mut threads = Vec::with_capacity(available_parallelism);
+            let mut threads = Vec::with_capacity(threads - 1);
 
             {
-                #[allow(clippy::large_stack_arrays)]
-                let mut buf = [MaybeUninit::<u8>::uninit(); 32768];
+                let queue = scheduler.local_queue();
+                let mut buf = dir_buf(buffer_size);
+                let mut descent_bufs: [Option<Box<[MaybeUninit<u8>]>>; MAX_DESCENT] =
+                    Default::default();
                 let symlink_buf_cache = Cell::new(Vec::new());
-                for node in &tasks {
+                while let Some((task, _finished)) = queue.next() {
+                    check_cancelled(cancel)?;
                     let mut maybe_spawn = || {
-                        if available_parallelism > 0 && !tasks.is_empty() {
+                        if queue.has_work() && spare_threads.take(1) == 1 {
                             #[cfg(feature = "tracing")]
                             tracing::event!(
                                 tracing::Level::TRACE,
-                                available_parallelism,
+                                workers = threads.len() + 2,
                                 "Spawning new thread."
                             );
 
-                            available_parallelism -= 1;
-                            threads.push(scope.spawn({
-                                let tasks = tasks.clone();
-                                move || worker_thread::<HARD_LINK>(tasks, follow_symlinks)
+                            threads.push(scope.spawn(move || {
+                                worker_thread::<HARD_LINK>(
+                                    scheduler,
+                                    settings,
+                                    buffer_size,
+                                    io_priority,
+                                    throttle,
+                                    cancel,
+                                    spare_threads,
+                                )
                             }));
                         }
                     };
                     maybe_spawn();
 
-                    copy_dir::<HARD_LINK>(
-                        node,
-                        follow_symlinks,
-                        &mut buf,
-                        &symlink_buf_cache,
-                        maybe_spawn,
-                    )?;
+                    match task {
+                        Task::Dir(node) => copy_dir::<HARD_LINK>(
+                            node,
+                            settings,
+                            &mut buf,
+                            &mut descent_bufs,
+                            &symlink_buf_cache,
+                            &queue,
+                            throttle,
+                            cancel,
+                            spare_threads,
+                            &mut maybe_spawn,
+                        )?,
+                        Task::Files(batch) => {
+                            copy_files::<HARD_LINK>(
+                                batch,
+                                settings,
+                                &symlink_buf_cache,
+                                throttle,
+                                spare_threads,
+                            )?;
+                        }
+                    }
                 }
             }
 
@@ -311,22 +1238,61 @@ mod compat {
         })
     }
 
-    #[cfg_attr(feature = "tracing", tracing::instrument(level = "trace", skip(tasks)))]
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip(scheduler))
+    )]
     fn worker_thread<const HARD_LINK: bool>(
-        tasks: Receiver<TreeNode>,
-        follow_symlinks: bool,
+        scheduler: &Scheduler<Task>,
+        settings: Settings,
+        buffer_size: usize,
+        io_priority: Option<IoPriority>,
+        throttle: Option<&RateLimiter>,
+        cancel: Option<&AtomicBool>,
+        spare_threads: &SpareThreads,
     ) -> Result<(), Error> {
         unshare_files()?;
+        set_io_priority(io_priority)?;
 
-        #[allow(clippy::large_stack_arrays)]
-        let mut buf = [MaybeUninit::<u8>::uninit(); 32768];
+        let queue = scheduler.local_queue();
+        let mut buf = dir_buf(buffer_size);
+        let mut descent_bufs: [Option<Box<[MaybeUninit<u8>]>>; MAX_DESCENT] = Default::default();
         let symlink_buf_cache = Cell::new(Vec::new());
-        for node in tasks {
-            copy_dir::<HARD_LINK>(node, follow_symlinks, &mut buf, &symlink_buf_cache, || {})?;
+        while let Some((task, _finished)) = queue.next() {
+            check_cancelled(cancel)?;
+            match task {
+                Task::Dir(node) => {
+                    copy_dir::<HARD_LINK>(
+                        node,
+                        settings,
+                        &mut buf,
+                        &mut descent_bufs,
+                        &symlink_buf_cache,
+                        &queue,
+                        throttle,
+                        cancel,
+                        spare_threads,
+                        &mut || {},
+                    )?;
+                }
+                Task::Files(batch) => {
+                    copy_files::<HARD_LINK>(
+                        batch,
+                        settings,
+                        &symlink_buf_cache,
+                        throttle,
+                        spare_threads,
+                    )?;
+                }
+            }
         }
         Ok(())
     }
 
+    fn dir_buf(len: usize) -> Box<[MaybeUninit<u8>]> {
+        vec![MaybeUninit::uninit(); len].into_boxed_slice()
+    }
+
     #[cold]
     #[cfg_attr(feature = "tracing", tracing::instrument(level = "trace", skip(dir)))]
     pub fn get_file_type(
@@ -334,62 +1300,118 @@ mod compat {
         file_name: &CStr,
         path: &CString,
         follow_symlinks: bool,
+        copy_dangling_symlinks: bool,
     ) -> Result<FileType, Error> {
         let flags = if follow_symlinks {
             AtFlags::empty()
         } else {
             AtFlags::SYMLINK_NOFOLLOW
         };
-        statx(dir, file_name, flags, StatxFlags::TYPE)
-            .map_io_err(|| {
-                format!(
-                    "Failed to stat file: {:?}",
-                    join_cstr_paths(path, file_name)
-                )
-            })
-            .map(|metadata| FileType::from_raw_mode(metadata.stx_mode.into()))
+        match statx(&dir, file_name, flags, StatxFlags::TYPE) {
+            Err(Errno::NOENT) if follow_symlinks && copy_dangling_symlinks => {
+                statx(dir, file_name, AtFlags::SYMLINK_NOFOLLOW, StatxFlags::TYPE)
+            }
+            r => r,
+        }
+        .map_io_err(|| {
+            format!(
+                "Failed to stat file: {:?}",
+                join_cstr_paths(path, file_name)
+            )
+        })
+        .map(|metadata| FileType::from_raw_mode(metadata.stx_mode.into()))
     }
 
+    #[allow(clippy::too_many_arguments)]
     #[cfg_attr(
         feature = "tracing",
-        tracing::instrument(level = "info", skip(messages, buf, symlink_buf_cache, maybe_spawn))
+        tracing::instrument(
+            level = "info",
+            skip(buf, descent_bufs, symlink_buf_cache, queue, maybe_spawn)
+        )
     )]
     fn copy_dir<const HARD_LINK: bool>(
-        TreeNode {
-            from,
-            to,
-            root_to_inode,
-            messages,
-        }: TreeNode,
-        follow_symlinks: bool,
+        node: TreeNode,
+        settings: Settings,
         buf: &mut [MaybeUninit<u8>],
+        descent_bufs: &mut [Option<Box<[MaybeUninit<u8>]>>],
         symlink_buf_cache: &Cell<Vec<u8>>,
-        mut maybe_spawn: impl FnMut(),
+        queue: &LocalQueue<Task>,
+        throttle: Option<&RateLimiter>,
+        cancel: Option<&AtomicBool>,
+        spare_threads: &SpareThreads,
+        maybe_spawn: &mut dyn FnMut(),
     ) -> Result<(), Error> {
-        let from_dir = openat(
-            CWD,
-            &from,
-            OFlags::RDONLY
-                | OFlags::DIRECTORY
-                | if follow_symlinks {
-                    OFlags::empty()
-                } else {
-                    OFlags::NOFOLLOW
-                },
-            Mode::empty(),
-        )
-        .map_io_err(|| format!("Failed to open directory: {from:?}"))?;
-        let to_dir = openat(
-            CWD,
-            &to,
-            OFlags::RDONLY | OFlags::DIRECTORY | OFlags::PATH,
-            Mode::empty(),
+        check_cancelled(cancel)?;
+        let node = copy_dir_contents::<HARD_LINK>(
+            node,
+            settings,
+            buf,
+            descent_bufs,
+            symlink_buf_cache,
+            queue,
+            throttle,
+            cancel,
+            spare_threads,
+            maybe_spawn,
+        )?;
+        finish_dir_chain(node, settings.sync_mode)
+    }
+
+    /// Directories with more files than this have the rest of their files
+    /// copied in batches which other workers can pick up.
+    const FILE_BATCH_THRESHOLD: usize = 1024;
+    const FILE_BATCH_SIZE: usize = 512;
+
+    #[allow(clippy::too_many_arguments)]
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            level = "trace",
+            skip(buf, descent_bufs, symlink_buf_cache, queue, maybe_spawn)
         )
-        .map_io_err(|| format!("Failed to open directory: {to:?}"))?;
+    )]
+    fn copy_dir_contents<const HARD_LINK: bool>(
+        node: TreeNode,
+        settings: Settings,
+        buf: &mut [MaybeUninit<u8>],
+        descent_bufs: &mut [Option<Box<[MaybeUninit<u8>]>>],
+        symlink_buf_cache: &Cell<Vec<u8>>,
+        queue: &LocalQueue<Task>,
+        throttle: Option<&RateLimiter>,
+        cancel: Option<&AtomicBool>,
+        spare_threads: &SpareThreads,
+        maybe_spawn: &mut dyn FnMut(),
+    ) -> Result<Option<TreeNode>, Error> {
+        let Settings {
+            follow_symlinks,
+            copy_dangling_symlinks,
+            preserve_mode,
+            ..
+        } = settings;
+        let dirs = DirFds::open(&node, follow_symlinks)?;
+
+        let mut node = Arcable::Raw(node);
+        let mut entries = EntryCopier::new(throttle, spare_threads);
+        let mut files = 0;
+        let mut batch = Vec::new();
+        let DirFds {
+            from_path: ref from,
+            to_path: ref to,
+            ..
+        } = dirs;
+        let buf_len = buf.len();
+        let mut dir_entries = DirEntries::new(&dirs.from, buf, node.as_ref().entry_order)
+            .map_io_err(|| format!("Failed to read directory: {from:?}"))?;
+        while let Some(file) = dir_entries.next() {
+            let TreeNode {
+                root_to_inode,
+                root_dev,
+                ref journal,
+                ref transaction,
+                ..
+            } = *node.as_ref();
 
-        let mut failed_cross_device = false;
-        let mut raw_dir = RawDir::new(&from_dir, buf);
-        while let Some(file) = raw_dir.next() {
             let file = file.map_io_err(|| format!("Failed to read directory: {from:?}"))?;
             if file.ino() == root_to_inode {
                 // Block recursive descent from parent into child (e.g. cp parent parent/child).
@@ -405,107 +1427,776 @@ mod compat {
             let mut file_type = file.file_type();
             if file_type == FileType::Unknown || (follow_symlinks && file_type == FileType::Symlink)
             {
-                file_type = get_file_type(&from_dir, file.file_name(), &from, follow_symlinks)?;
+                file_type = get_file_type(
+                    &dirs.from,
+                    file.file_name(),
+                    from,
+                    follow_symlinks,
+                    copy_dangling_symlinks,
+                )?;
             }
             let file_type = file_type;
             if file_type == FileType::Directory {
-                let from = concat_cstrs(&from, file.file_name());
-                let to = concat_cstrs(&to, file.file_name());
+                let journal = match journal {
+                    Some((journal, _)) => {
+                        let stamp = stamp_entry(
+                            &dirs.from,
+                            file.file_name(),
+                            from,
+                            follow_symlinks,
+                            file_type,
+                        )?;
+                        if journal.completed_in(Kind::Dir, &dirs.to, to, file.file_name(), stamp) {
+                            continue;
+                        }
+                        Some((journal.clone(), stamp))
+                    }
+                    None => None,
+                };
+                // Directories we create are removed along with everything in them.
+                let transaction = match transaction {
+                    Some(transaction)
+                        if record_if_new(transaction, &dirs.to, to, file.file_name())? =>
+                    {
+                        Some(transaction.clone())
+                    }
+                    _ => None,
+                };
+                let from = concat_cstrs(from, file.file_name());
+                let to = concat_cstrs(to, file.file_name());
 
-                copy_one_dir(&from, &to)?;
-                maybe_spawn();
-                messages
-                    .send(TreeNode {
-                        from,
-                        to,
-                        root_to_inode,
-                        messages: messages.clone(),
-                    })
-                    .map_err(|_| Error::Internal)?;
-            } else if HARD_LINK {
-                let name = file.file_name();
-                let flags = if follow_symlinks {
-                    AtFlags::SYMLINK_FOLLOW
-                } else {
-                    AtFlags::empty()
+                let deferred_mode = copy_one_dir(
+                    &from,
+                    &to,
+                    preserve_mode,
+                    journal
+                        .as_ref()
+                        .is_some_and(|(journal, _)| journal.is_resuming()),
+                )?;
+                if let Some(root_dev) = root_dev
+                    && is_mount_point(
+                        &dirs.from,
+                        file.file_name(),
+                        if follow_symlinks {
+                            AtFlags::empty()
+                        } else {
+                            AtFlags::SYMLINK_NOFOLLOW
+                        },
+                        root_dev,
+                    )
+                    .map_io_err(|| format!("Failed to stat directory: {from:?}"))?
+                {
+                    // Like `cp -x`, mount points are recreated as empty directories.
+                    if let Some(mode) = deferred_mode {
+                        restore_dir_mode(&to, mode)?;
+                    }
+                    continue;
+                }
+                let parent;
+                (node, parent) = Arcable::share(node);
+                let child = TreeNode {
+                    name: file.file_name().to_owned(),
+                    roots: parent.roots.clone(),
+                    root_to_inode,
+                    root_dev,
+                    relocation: parent.relocation.clone(),
+                    deferred_mode,
+                    entry_order: parent.entry_order,
+                    journal,
+                    transaction,
+                    done: false,
+                    parent: Some(parent),
                 };
-                match linkat(&from_dir, name, &to_dir, name, flags) {
-                    Err(Errno::EXIST) => {
-                        let id = |dir: &OwnedFd, dir_name| {
-                            let metadata = statx(
-                                dir,
-                                name,
-                                if follow_symlinks {
-                                    AtFlags::empty()
-                                } else {
-                                    AtFlags::SYMLINK_NOFOLLOW
-                                },
-                                StatxFlags::INO,
-                            )
-                            .map_io_err(|| {
+                maybe_spawn();
+                if let Err(Task::Dir(child)) = queue.try_push(Task::Dir(child)) {
+                    // The queue is full, so copy the directory now instead of holding on
+                    // to it. Only the directories on the way down stay open.
+                    if let Some((buf, descent_bufs)) = descent_bufs.split_first_mut() {
+                        copy_dir::<HARD_LINK>(
+                            child,
+                            settings,
+                            buf.get_or_insert_with(|| dir_buf(buf_len)),
+                            descent_bufs,
+                            symlink_buf_cache,
+                            queue,
+                            throttle,
+                            cancel,
+                            spare_threads,
+                            maybe_spawn,
+                        )?;
+                    } else {
+                        queue.inject(Task::Dir(child));
+                    }
+                }
+            } else if files < FILE_BATCH_THRESHOLD {
+                files += 1;
+                entries.copy::<HARD_LINK>(
+                    &dirs,
+                    node.as_ref(),
+                    file.file_name(),
+                    file_type,
+                    settings,
+                    symlink_buf_cache,
+                )?;
+            } else {
+                batch.push((file.file_name().to_owned(), file_type));
+                if batch.len() < FILE_BATCH_SIZE {
+                    continue;
+                }
+                maybe_spawn();
+
+                let parent;
+                (node, parent) = Arcable::share(node);
+                if let Err(Task::Files(FileBatch { dir: _, files })) =
+                    queue.try_push(Task::Files(FileBatch {
+                        dir: parent,
+                        files: mem::replace(&mut batch, Vec::with_capacity(FILE_BATCH_SIZE)),
+                    }))
+                {
+                    // The queue is full, so copy the files ourselves.
+                    for (name, file_type) in files {
+                        entries.copy::<HARD_LINK>(
+                            &dirs,
+                            node.as_ref(),
+                            &name,
+                            file_type,
+                            settings,
+                            symlink_buf_cache,
+                        )?;
+                    }
+                }
+            }
+        }
+
+        for (name, file_type) in batch {
+            entries.copy::<HARD_LINK>(
+                &dirs,
+                node.as_ref(),
+                &name,
+                file_type,
+                settings,
+                symlink_buf_cache,
+            )?;
+        }
+        #[cfg(feature = "io_uring")]
+        entries.flush::<HARD_LINK>(&dirs, node.as_ref(), settings, symlink_buf_cache)?;
+        Ok(Arcable::into_inner(node))
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "info", skip(symlink_buf_cache))
+    )]
+    fn copy_files<const HARD_LINK: bool>(
+        FileBatch { dir, files }: FileBatch,
+        settings: Settings,
+        symlink_buf_cache: &Cell<Vec<u8>>,
+        throttle: Option<&RateLimiter>,
+        spare_threads: &SpareThreads,
+    ) -> Result<(), Error> {
+        {
+            let dirs = DirFds::open(&dir, settings.follow_symlinks)?;
+            let mut entries = EntryCopier::new(throttle, spare_threads);
+            for (name, file_type) in files {
+                entries.copy::<HARD_LINK>(
+                    &dirs,
+                    &dir,
+                    &name,
+                    file_type,
+                    settings,
+                    symlink_buf_cache,
+                )?;
+            }
+            #[cfg(feature = "io_uring")]
+            entries.flush::<HARD_LINK>(&dirs, &dir, settings, symlink_buf_cache)?;
+        }
+        finish_dir_chain(Arc::into_inner(dir), settings.sync_mode)
+    }
+
+    struct DirFds {
+        from: OwnedFd,
+        to: OwnedFd,
+        link_dest: Vec<OwnedFd>,
+        from_path: CString,
+        to_path: CString,
+    }
+
+    impl DirFds {
+        fn open(node: &TreeNode, follow_symlinks: bool) -> Result<Self, Error> {
+            let from_path = node.path_under(&node.roots.from);
+            let to_path = node.path_under(&node.roots.to);
+            let from = openat(
+                CWD,
+                &from_path,
+                OFlags::RDONLY
+                    | OFlags::DIRECTORY
+                    // The root was already resolved according to the dereference policy.
+                    | if follow_symlinks || node.parent.is_none() {
+                        OFlags::empty()
+                    } else {
+                        OFlags::NOFOLLOW
+                    },
+                Mode::empty(),
+            )
+            .map_io_err(|| format!("Failed to open directory: {from_path:?}"))?;
+            let to = openat(
+                CWD,
+                &to_path,
+                OFlags::RDONLY | OFlags::DIRECTORY | OFlags::PATH,
+                Mode::empty(),
+            )
+            .map_io_err(|| format!("Failed to open directory: {to_path:?}"))?;
+            let link_dest = node
+                .roots
+                .link_dest
+                .iter()
+                .map(|dir| node.path_under(dir))
+                .filter_map(|dir| {
+                    match openat(
+                        CWD,
+                        &dir,
+                        OFlags::RDONLY | OFlags::DIRECTORY | OFlags::PATH,
+                        Mode::empty(),
+                    ) {
+                        // The directory is new since the previous snapshot.
+                        Err(Errno::NOENT | Errno::NOTDIR) => None,
+                        r => Some(r.map_io_err(|| format!("Failed to open directory: {dir:?}"))),
+                    }
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(Self {
+                from,
+                to,
+                link_dest,
+                from_path,
+                to_path,
+            })
+        }
+    }
+
+    /// Copies the non-directory entries of a directory, remembering which
+    /// fast paths already failed for earlier entries.
+    struct EntryCopier<'a> {
+        throttle: Option<&'a RateLimiter>,
+        spare_threads: &'a SpareThreads,
+        failed_cross_device: bool,
+        failed_cross_device_link: bool,
+        #[cfg(feature = "io_uring")]
+        small_files: Vec<CString>,
+    }
+
+    impl<'a> EntryCopier<'a> {
+        const fn new(throttle: Option<&'a RateLimiter>, spare_threads: &'a SpareThreads) -> Self {
+            Self {
+                throttle,
+                spare_threads,
+                failed_cross_device: false,
+                failed_cross_device_link: false,
+                #[cfg(feature = "io_uring")]
+                small_files: Vec::new(),
+            }
+        }
+
+        fn copy<const HARD_LINK: bool>(
+            &mut self,
+            dirs: &DirFds,
+            node: &TreeNode,
+            name: &CStr,
+            file_type: FileType,
+            settings: Settings,
+            symlink_buf_cache: &Cell<Vec<u8>>,
+        ) -> Result<(), Error> {
+            let journal = match node.journal {
+                Some((ref journal, _)) => {
+                    let stamp = stamp_entry(
+                        &dirs.from,
+                        name,
+                        &dirs.from_path,
+                        settings.follow_symlinks,
+                        file_type,
+                    )?;
+                    if journal.completed_in(Kind::File, &dirs.to, &dirs.to_path, name, stamp) {
+                        return Ok(());
+                    }
+                    if journal.is_resuming() {
+                        // Clear out whatever the interrupted run left behind.
+                        match unlinkat(&dirs.to, name, AtFlags::empty()) {
+                            Err(Errno::NOENT | Errno::ISDIR) => (),
+                            r => r.map_io_err(|| {
                                 format!(
-                                    "Failed to stat file: {:?}",
-                                    join_cstr_paths(dir_name, name)
+                                    "Failed to remove existing file: {:?}",
+                                    join_cstr_paths(&dirs.to_path, name)
                                 )
-                            })?;
-                            Ok::<_, Error>((
-                                metadata.stx_ino,
-                                metadata.stx_dev_minor,
-                                metadata.stx_dev_major,
-                            ))
-                        };
-                        let from_id = id(&from_dir, &from)?;
-                        let to_id = id(&to_dir, &to)?;
-
-                        // We add this check to handle NFSv3 nonsense. If you drop the response
-                        // packet that says the hardlink suceeded, then you'll get back an EXIST
-                        // even though the hardlink suceeded.
-                        if from_id == to_id {
-                            Ok(())
-                        } else {
-                            Err(Errno::EXIST)
+                            })?,
                         }
                     }
-                    r => r,
+                    Some((journal, stamp))
                 }
-                .map_io_err(|| {
-                    format!(
-                        "Failed to create hard link: {:?} -> {:?}",
-                        join_cstr_paths(&to, name),
-                        join_cstr_paths(&from, name),
-                    )
-                })?;
-            } else {
-                copy_one_file(
-                    &from_dir,
-                    &to_dir,
-                    file.file_name(),
-                    file_type,
-                    &from,
-                    &to,
+                None => None,
+            };
+            if let Some(ref transaction) = node.transaction {
+                record_if_new(transaction, &dirs.to, &dirs.to_path, name)?;
+            }
+
+            // Batched copies can't be throttled as they go.
+            #[cfg(feature = "io_uring")]
+            if self.throttle.is_none() && io_uring::accepts(file_type, settings) {
+                self.small_files.push(name.to_owned());
+                if self.small_files.len() < io_uring::BATCH_SIZE {
+                    return Ok(());
+                }
+                return self.flush::<HARD_LINK>(dirs, node, settings, symlink_buf_cache);
+            }
+
+            copy_dir_entry::<HARD_LINK>(
+                dirs,
+                node,
+                name,
+                file_type,
+                settings,
+                symlink_buf_cache,
+                self.throttle,
+                self.spare_threads,
+                &mut self.failed_cross_device,
+                &mut self.failed_cross_device_link,
+            )?;
+            if let Some((journal, stamp)) = journal {
+                journal
+                    .record(Kind::File, &dirs.to_path, Some(name), stamp)
+                    .map_io_err(|| format!("Failed to write to journal: {:?}", journal.path()))?;
+            }
+            Ok(())
+        }
+
+        /// Copies the files which were held back to be submitted together,
+        /// falling back to the regular path for any that couldn't be.
+        #[cfg(feature = "io_uring")]
+        fn flush<const HARD_LINK: bool>(
+            &mut self,
+            dirs: &DirFds,
+            node: &TreeNode,
+            settings: Settings,
+            symlink_buf_cache: &Cell<Vec<u8>>,
+        ) -> Result<(), Error> {
+            for name in io_uring::copy_small_files(dirs, &mut self.small_files, settings) {
+                copy_dir_entry::<HARD_LINK>(
+                    dirs,
+                    node,
+                    &name,
+                    FileType::RegularFile,
+                    settings,
                     symlink_buf_cache,
-                    &mut failed_cross_device,
+                    self.throttle,
+                    self.spare_threads,
+                    &mut self.failed_cross_device,
+                    &mut self.failed_cross_device_link,
                 )?;
             }
+            Ok(())
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip(dirs, node, symlink_buf_cache))
+    )]
+    fn copy_dir_entry<const HARD_LINK: bool>(
+        dirs: &DirFds,
+        node: &TreeNode,
+        name: &CStr,
+        file_type: FileType,
+        Settings {
+            follow_symlinks,
+            fallback_to_copy,
+            symbolic_link,
+            preserve_mode,
+            preserve_mtime,
+            cache_policy,
+            sync_mode,
+            preallocate,
+            journal,
+            ..
+        }: Settings,
+        symlink_buf_cache: &Cell<Vec<u8>>,
+        throttle: Option<&RateLimiter>,
+        spare_threads: &SpareThreads,
+        failed_cross_device: &mut bool,
+        failed_cross_device_link: &mut bool,
+    ) -> Result<(), Error> {
+        let DirFds {
+            from_path: ref from,
+            to_path: ref to,
+            ..
+        } = *dirs;
+        if let Some(style) = symbolic_link {
+            return symlink_one_file(&dirs.to, name, from, to, style);
+        }
+
+        let linked = (file_type == FileType::RegularFile
+            && !dirs.link_dest.is_empty()
+            && link_previous_file(
+                &dirs.from,
+                &dirs.to,
+                &dirs.link_dest,
+                name,
+                from,
+                to,
+                follow_symlinks,
+            )?)
+            || (HARD_LINK
+                && !*failed_cross_device_link
+                && link_one_file(
+                    &dirs.from,
+                    &dirs.to,
+                    name,
+                    file_type,
+                    from,
+                    to,
+                    follow_symlinks,
+                    fallback_to_copy,
+                    failed_cross_device_link,
+                )?);
+        if linked {
+            return Ok(());
+        }
+        // A journal record vouches for the file's contents, so they must reach the
+        // disk before the record does.
+        let sync_mode = if journal && !sync_mode.syncs_files() {
+            SyncMode::Files
+        } else {
+            sync_mode
+        };
+        copy_one_file(
+            &dirs.from,
+            &dirs.to,
+            name,
+            file_type,
+            from,
+            to,
+            symlink_buf_cache,
+            preserve_mode,
+            preserve_mtime,
+            cache_policy,
+            sync_mode,
+            preallocate,
+            node.relocation.as_deref(),
+            throttle,
+            spare_threads,
+            failed_cross_device,
+        )
+    }
+
+    /// Records the entry as created unless it already exists, returning whether
+    /// it does.
+    fn record_if_new(
+        transaction: &Transaction,
+        to_dir: impl AsFd,
+        to: &CString,
+        name: &CStr,
+    ) -> Result<bool, Error> {
+        match statx(to_dir, name, AtFlags::SYMLINK_NOFOLLOW, StatxFlags::empty()) {
+            Err(Errno::NOENT) => {
+                transaction.record(join_cstr_paths(to, name));
+                Ok(false)
+            }
+            r => r
+                .map(|_| true)
+                .map_io_err(|| format!("Failed to stat file: {:?}", join_cstr_paths(to, name))),
         }
-        Ok(())
     }
 
+    /// Stamps a source entry the way it's going to be copied.
+    fn stamp_entry(
+        from_dir: impl AsFd,
+        name: &CStr,
+        from: &CString,
+        follow_symlinks: bool,
+        file_type: FileType,
+    ) -> Result<Stamp, Error> {
+        // Symlinks which were meant to be followed are dangling by now.
+        Stamp::of(
+            from_dir,
+            name,
+            if follow_symlinks && file_type != FileType::Symlink {
+                AtFlags::empty()
+            } else {
+                AtFlags::SYMLINK_NOFOLLOW
+            },
+        )
+        .map_io_err(|| format!("Failed to stat file: {:?}", join_cstr_paths(from, name)))
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "trace"))]
+    fn finish_dir_chain(mut node: Option<TreeNode>, sync_mode: SyncMode) -> Result<(), Error> {
+        let mut result = Ok(());
+        while let Some(mut node_) = node {
+            // We don't use ? here and also don't break out of the loop so that we continue
+            // to drain the linked list without overflowing the drop stack
+            let to = || node_.path_under(&node_.roots.to);
+            if result.is_ok()
+                && let Some(mode) = node_.deferred_mode
+            {
+                result = restore_dir_mode(&to(), mode);
+            }
+            // The record vouches for the whole subtree, whose entries must therefore
+            // survive a crash.
+            if result.is_ok() && (sync_mode == SyncMode::Directories || node_.journal.is_some()) {
+                result = sync_dir(&to());
+            }
+            if result.is_ok()
+                && let Some((ref journal, stamp)) = node_.journal
+            {
+                result = journal
+                    .record(Kind::Dir, &to(), None, stamp)
+                    .map_io_err(|| format!("Failed to write to journal: {:?}", journal.path()));
+            }
+            node_.done = result.is_ok();
+            node = node_.parent.take().and_then(Arc::into_inner);
+        }
+        result
+    }
+
+    /// Creates the directory with owner access so that it can be filled in even
+    /// if the source is read-only, returning the mode to apply once its
+    /// subtree has been copied. An existing directory is only treated as
+    /// created by us when resuming, in which case the interrupted run made it.
     #[cfg_attr(feature = "tracing", tracing::instrument(level = "trace"))]
-    pub fn copy_one_dir(from_path: &CString, to_path: &CString) -> Result<(), Error> {
+    pub fn copy_one_dir(
+        from_path: &CString,
+        to_path: &CString,
+        preserve_mode: PreserveMode,
+        resuming: bool,
+    ) -> Result<Option<Mode>, Error> {
+        if preserve_mode == PreserveMode::Ignore {
+            return match mkdirat(CWD, to_path, Mode::from_raw_mode(0o777)) {
+                Err(Errno::EXIST) => Ok(None),
+                r => r
+                    .map(|()| None)
+                    .map_io_err(|| format!("Failed to create directory: {to_path:?}")),
+            };
+        }
+
         let from_mode = {
             let from_metadata = statx(CWD, from_path, AtFlags::EMPTY_PATH, StatxFlags::MODE)
                 .map_io_err(|| format!("Failed to stat directory: {from_path:?}"))?;
             Mode::from_raw_mode(from_metadata.stx_mode.into())
         };
-        match mkdirat(CWD, to_path, from_mode) {
-            Err(Errno::EXIST) => {}
-            r => r.map_io_err(|| format!("Failed to create directory: {to_path:?}"))?,
+        let created = match mkdirat(CWD, to_path, from_mode | Mode::RWXU) {
+            Err(Errno::EXIST) => resuming,
+            r => r
+                .map(|()| true)
+                .map_io_err(|| format!("Failed to create directory: {to_path:?}"))?,
+        };
+        if preserve_mode == PreserveMode::Exact {
+            // mkdir ignores the special bits and applies the umask.
+            return Ok(Some(from_mode));
+        } else if !created {
+            return Ok(None);
         }
 
-        Ok(())
+        let added = Mode::RWXU - from_mode;
+        if added.is_empty() {
+            return Ok(None);
+        }
+        // Read back the mode instead of recomputing it so the umask and inherited
+        // setgid bit are accounted for.
+        let to_mode = {
+            let to_metadata = statx(CWD, to_path, AtFlags::SYMLINK_NOFOLLOW, StatxFlags::MODE)
+                .map_io_err(|| format!("Failed to stat directory: {to_path:?}"))?;
+            Mode::from_raw_mode(to_metadata.stx_mode.into())
+        };
+        Ok(Some(to_mode - added))
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "trace"))]
+    fn sync_dir(path: &CString) -> Result<(), Error> {
+        let dir = openat(
+            CWD,
+            path,
+            OFlags::RDONLY | OFlags::DIRECTORY | OFlags::CLOEXEC,
+            Mode::empty(),
+        )
+        .map_io_err(|| format!("Failed to open directory: {path:?}"))?;
+        fsync(dir).map_io_err(|| format!("Failed to sync directory: {path:?}"))
+    }
+
+    #[cold]
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "trace"))]
+    fn restore_dir_mode(path: &CString, mode: Mode) -> Result<(), Error> {
+        chmodat(CWD, path, mode, AtFlags::empty())
+            .map_io_err(|| format!("Failed to set directory permissions: {path:?}"))
+    }
+
+    /// Hard links the file to an unchanged version of it from one of the
+    /// previous snapshots, returning whether one was found.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip(from_dir, to_dir, previous_dirs))
+    )]
+    fn link_previous_file(
+        from_dir: &OwnedFd,
+        to_dir: &OwnedFd,
+        previous_dirs: &[OwnedFd],
+        name: &CStr,
+        from: &CString,
+        to: &CString,
+        follow_symlinks: bool,
+    ) -> Result<bool, Error> {
+        const FLAGS: StatxFlags = StatxFlags::TYPE
+            .union(StatxFlags::MODE)
+            .union(StatxFlags::SIZE)
+            .union(StatxFlags::MTIME);
+        let id = |metadata: Statx| {
+            (
+                metadata.stx_mode,
+                metadata.stx_size,
+                metadata.stx_mtime.tv_sec,
+                metadata.stx_mtime.tv_nsec,
+            )
+        };
+
+        let from_id = statx(
+            from_dir,
+            name,
+            if follow_symlinks {
+                AtFlags::empty()
+            } else {
+                AtFlags::SYMLINK_NOFOLLOW
+            },
+            FLAGS,
+        )
+        .map(id)
+        .map_io_err(|| format!("Failed to stat file: {:?}", join_cstr_paths(from, name)))?;
+        for dir in previous_dirs {
+            let previous_id = match statx(dir, name, AtFlags::SYMLINK_NOFOLLOW, FLAGS) {
+                Err(Errno::NOENT) => continue,
+                r => r.map(id).map_io_err(|| {
+                    format!(
+                        "Failed to stat previous snapshot of file: {:?}",
+                        join_cstr_paths(from, name)
+                    )
+                })?,
+            };
+            if previous_id != from_id {
+                continue;
+            }
+
+            return match linkat(dir, name, to_dir, name, AtFlags::empty()) {
+                Err(Errno::XDEV | Errno::MLINK) => Ok(false),
+                r => r.map(|()| true),
+            }
+            .map_io_err(|| {
+                format!(
+                    "Failed to create hard link to previous snapshot: {:?}",
+                    join_cstr_paths(to, name)
+                )
+            });
+        }
+        Ok(false)
+    }
+
+    /// Returns whether the file was linked or should be copied instead.
+    #[allow(clippy::too_many_arguments)]
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip(from_dir, to_dir))
+    )]
+    fn link_one_file(
+        from_dir: &OwnedFd,
+        to_dir: &OwnedFd,
+        name: &CStr,
+        file_type: FileType,
+        from: &CString,
+        to: &CString,
+        follow_symlinks: bool,
+        fallback_to_copy: bool,
+        failed_cross_device_link: &mut bool,
+    ) -> Result<bool, Error> {
+        // Dangling symlinks are linked as-is.
+        let follow_symlinks = follow_symlinks && file_type != FileType::Symlink;
+        let flags = if follow_symlinks {
+            AtFlags::SYMLINK_FOLLOW
+        } else {
+            AtFlags::empty()
+        };
+        match linkat(from_dir, name, to_dir, name, flags) {
+            Err(Errno::EXIST) => {
+                let id = |dir: &OwnedFd, dir_name| {
+                    let metadata = statx(
+                        dir,
+                        name,
+                        if follow_symlinks {
+                            AtFlags::empty()
+                        } else {
+                            AtFlags::SYMLINK_NOFOLLOW
+                        },
+                        StatxFlags::INO,
+                    )
+                    .map_io_err(|| {
+                        format!("Failed to stat file: {:?}", join_cstr_paths(dir_name, name))
+                    })?;
+                    Ok::<_, Error>((
+                        metadata.stx_ino,
+                        metadata.stx_dev_minor,
+                        metadata.stx_dev_major,
+                    ))
+                };
+                let from_id = id(from_dir, from)?;
+                let to_id = id(to_dir, to)?;
+
+                // We add this check to handle NFSv3 nonsense. If you drop the response
+                // packet that says the hardlink suceeded, then you'll get back an EXIST
+                // even though the hardlink suceeded.
+                if from_id == to_id {
+                    Ok(true)
+                } else {
+                    Err(Errno::EXIST)
+                }
+            }
+            Err(Errno::XDEV) if fallback_to_copy => {
+                // Everything else in this directory lives on the same device.
+                *failed_cross_device_link = true;
+                Ok(false)
+            }
+            Err(Errno::MLINK) if fallback_to_copy => Ok(false),
+            r => r.map(|()| true),
+        }
+        .map_io_err(|| {
+            format!(
+                "Failed to create hard link: {:?} -> {:?}",
+                join_cstr_paths(to, name),
+                join_cstr_paths(from, name),
+            )
+        })
     }
 
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip(to_dir))
+    )]
+    fn symlink_one_file(
+        to_dir: impl AsFd,
+        file_name: &CStr,
+        from_path: &CString,
+        to_path: &CString,
+        style: SymlinkStyle,
+    ) -> Result<(), Error> {
+        let from = join_cstr_paths(from_path, file_name);
+        let target = symlink_target(
+            &from,
+            Path::new(OsStr::from_bytes(to_path.as_bytes())),
+            style,
+        );
+        symlinkat(&*target, to_dir, file_name).map_io_err(|| {
+            format!(
+                "Failed to create symlink: {:?} -> {target:?}",
+                join_cstr_paths(to_path, file_name),
+            )
+        })
+    }
+
+    #[allow(clippy::too_many_arguments)]
     #[cfg_attr(
         feature = "tracing",
         tracing::instrument(level = "debug", skip(from_dir, to_dir, symlink_buf_cache))
@@ -518,68 +2209,614 @@ mod compat {
         from_path: &CString,
         to_path: &CString,
         symlink_buf_cache: &Cell<Vec<u8>>,
+        preserve_mode: PreserveMode,
+        preserve_mtime: bool,
+        cache_policy: CachePolicy,
+        sync_mode: SyncMode,
+        preallocate: bool,
+        relocation: Option<&Relocation>,
+        throttle: Option<&RateLimiter>,
+        spare_threads: &SpareThreads,
         failed_cross_device: &mut bool,
     ) -> Result<(), Error> {
         if file_type == FileType::Symlink {
-            copy_symlink(
+            return copy_symlink(
                 from_dir,
                 to_dir,
                 file_name,
                 from_path,
                 to_path,
                 symlink_buf_cache,
-            )
+                relocation,
+            );
+        }
+
+        let (from, to, from_size, from_mode) = prep_regular_file(
+            from_dir,
+            to_dir,
+            file_name,
+            from_path,
+            to_path,
+            preserve_mode,
+            preallocate,
+        )?;
+        if file_type == FileType::RegularFile
+            && from_size < SMALL_FILE_THRESHOLD as u64
+            && cache_policy == CachePolicy::Normal
+        {
+            if let Some(throttle) = throttle {
+                throttle.acquire(from_size);
+            }
+            copy_small_file(&from, &to, file_name, from_path)?;
+        } else if file_type == FileType::RegularFile
+            && (!*failed_cross_device || cache_policy != CachePolicy::Normal || throttle.is_some())
+        {
+            copy_regular_file(
+                &from,
+                &to,
+                file_name,
+                from_path,
+                from_size,
+                cache_policy,
+                throttle,
+                spare_threads,
+                failed_cross_device,
+            )?;
         } else {
-            let (from, to, from_size) =
-                prep_regular_file(from_dir, to_dir, file_name, from_path, to_path)?;
-            if file_type == FileType::RegularFile && !*failed_cross_device {
-                copy_regular_file(
+            copy_any_file(&from, &to, file_name, from_path)?;
+        }
+
+        if preserve_mode == PreserveMode::Exact {
+            // The kernel clears the setuid and setgid bits on write, so they can
+            // only be applied once the contents are in place.
+            fchmod(&to, from_mode).map_io_err(|| {
+                format!(
+                    "Failed to set file permissions: {:?}",
+                    join_cstr_paths(to_path, file_name)
+                )
+            })?;
+        }
+        if preserve_mtime {
+            copy_mtime(&from, &to).map_io_err(|| {
+                format!(
+                    "Failed to set file modification time: {:?}",
+                    join_cstr_paths(to_path, file_name)
+                )
+            })?;
+        }
+        if sync_mode.syncs_files() {
+            fdatasync(&to).map_io_err(|| {
+                format!(
+                    "Failed to sync file: {:?}",
+                    join_cstr_paths(to_path, file_name)
+                )
+            })?;
+        }
+        Ok(())
+    }
+
+    fn copy_mtime(from: &File, to: &File) -> rustix::io::Result<()> {
+        let mtime = statx(from, c"", AtFlags::EMPTY_PATH, StatxFlags::MTIME)?.stx_mtime;
+        futimens(to, &Timestamps {
+            last_access: Timespec {
+                tv_sec: 0,
+                tv_nsec: UTIME_OMIT,
+            },
+            last_modification: Timespec {
+                tv_sec: mtime.tv_sec,
+                tv_nsec: mtime.tv_nsec.into(),
+            },
+        })
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip(from, to))
+    )]
+    fn copy_regular_file(
+        from: &File,
+        to: &File,
+        file_name: &CStr,
+        from_path: &CString,
+        from_size: u64,
+        cache_policy: CachePolicy,
+        throttle: Option<&RateLimiter>,
+        spare_threads: &SpareThreads,
+        failed_cross_device: &mut bool,
+    ) -> Result<(), Error> {
+        let context = || {
+            format!(
+                "Failed to copy file: {:?}",
+                join_cstr_paths(from_path, file_name)
+            )
+        };
+        if cache_policy == CachePolicy::Direct
+            && copy_direct(from, to, from_size, throttle).map_io_err(context)?
+        {
+            return Ok(());
+        }
+        let drop_behind = cache_policy != CachePolicy::Normal;
+
+        if from_size >= PARALLEL_COPY_THRESHOLD
+            && !*failed_cross_device
+            && !is_sparse(from, from_size)
+        {
+            let helpers = spare_threads.take(
+                usize::try_from(from_size / PARALLEL_COPY_MIN_CHUNK).unwrap_or(usize::MAX) - 1,
+            );
+            if helpers > 0 {
+                let result = copy_regular_file_parallel(
                     from,
                     to,
                     file_name,
                     from_path,
                     from_size,
+                    u64::try_from(helpers + 1).unwrap(),
+                    drop_behind,
+                    throttle,
                     failed_cross_device,
-                )
-            } else {
-                copy_any_file(from, to, file_name, from_path)
+                );
+                spare_threads.give_back(helpers);
+                return result;
+            }
+        }
+        if drop_behind || throttle.is_some() {
+            *failed_cross_device |= copy_range(
+                from,
+                to,
+                0,
+                from_size,
+                drop_behind,
+                throttle,
+                *failed_cross_device,
+            )
+            .map_io_err(context)?;
+            return Ok(());
+        }
+
+        let mut total_copied = 0;
+        loop {
+            let byte_copied =
+                match copy_file_range(from, None, to, None, usize::MAX / 2 - total_copied) {
+                    Err(Errno::XDEV) if total_copied == 0 => {
+                        *failed_cross_device = true;
+                        return copy_any_file(from, to, file_name, from_path);
+                    }
+                    r => r.map_io_err(context)?,
+                };
+            total_copied += byte_copied;
+
+            if u64::try_from(total_copied).unwrap() == from_size || byte_copied == 0 {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Files at least this large are split into ranges which are copied
+    /// concurrently.
+    const PARALLEL_COPY_THRESHOLD: u64 = 128 << 20;
+    const PARALLEL_COPY_MIN_CHUNK: u64 = 32 << 20;
+
+    /// The threads which haven't been started yet. Workers are spawned from
+    /// them as work shows up and large files borrow them to be split across,
+    /// so that together they never exceed the configured thread count.
+    #[derive(Debug)]
+    struct SpareThreads(AtomicUsize);
+
+    impl SpareThreads {
+        const fn new(count: usize) -> Self {
+            Self(AtomicUsize::new(count))
+        }
+
+        /// Claims up to `wanted` threads, returning how many were available.
+        fn take(&self, wanted: usize) -> usize {
+            match self
+                .0
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |spare| {
+                    Some(spare - spare.min(wanted))
+                }) {
+                Ok(spare) | Err(spare) => spare.min(wanted),
+            }
+        }
+
+        fn give_back(&self, count: usize) {
+            self.0.fetch_add(count, Ordering::Relaxed);
+        }
+    }
+
+    /// Splitting up a sparse file would fill in its holes, so those are
+    /// copied in one go instead.
+    fn is_sparse(file: &File, size: u64) -> bool {
+        statx(file, c"", AtFlags::EMPTY_PATH, StatxFlags::BLOCKS).map_or(true, |metadata| {
+            metadata.stx_blocks.saturating_mul(512) < size
+        })
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip(from, to))
+    )]
+    fn copy_regular_file_parallel(
+        from: &File,
+        to: &File,
+        file_name: &CStr,
+        from_path: &CString,
+        from_size: u64,
+        chunks: u64,
+        drop_behind: bool,
+        throttle: Option<&RateLimiter>,
+        failed_cross_device: &mut bool,
+    ) -> Result<(), Error> {
+        let context = || {
+            format!(
+                "Failed to copy file: {:?}",
+                join_cstr_paths(from_path, file_name)
+            )
+        };
+
+        // Allocating all the blocks up front keeps the concurrent writers from
+        // fragmenting the file and makes running out of space fail fast. The
+        // source isn't sparse, so this doesn't allocate anything it wouldn't.
+        match fallocate(to, FallocateFlags::empty(), 0, from_size) {
+            Err(Errno::OPNOTSUPP) => ftruncate(to, from_size),
+            r => r,
+        }
+        .map_io_err(context)?;
+
+        let chunk_size = from_size.div_ceil(chunks).next_multiple_of(4096);
+        let cross_device = thread::scope(|scope| {
+            let threads = (1..chunks)
+                .map(|i| i * chunk_size)
+                .take_while(|&start| start < from_size)
+                .map(|start| {
+                    scope.spawn(move || {
+                        copy_range(
+                            from,
+                            to,
+                            start,
+                            chunk_size.min(from_size - start),
+                            drop_behind,
+                            throttle,
+                            false,
+                        )
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            let mut cross_device =
+                copy_range(from, to, 0, chunk_size, drop_behind, throttle, false)
+                    .map_io_err(context)?;
+            for thread in threads {
+                cross_device |= thread
+                    .join()
+                    .map_err(|_| Error::Join)?
+                    .map_io_err(context)?;
+            }
+            Ok::<_, Error>(cross_device)
+        })?;
+        *failed_cross_device |= cross_device;
+        Ok(())
+    }
+
+    /// Returns `true` if the range had to be copied through userspace because
+    /// the files live on different devices.
+    fn copy_range(
+        from: &File,
+        to: &File,
+        start: u64,
+        len: u64,
+        drop_behind: bool,
+        throttle: Option<&RateLimiter>,
+        cross_device: bool,
+    ) -> io::Result<bool> {
+        let end = start + len;
+        let mut off_in = start;
+        let mut off_out = start;
+        let mut cache = DropBehind::new(start);
+        let chunk = if throttle.is_some() {
+            THROTTLED_CHUNK
+        } else if drop_behind {
+            DROP_BEHIND_CHUNK
+        } else {
+            u64::MAX
+        };
+        while !cross_device && off_in < end {
+            let len = (end - off_in).min(chunk);
+            if let Some(throttle) = throttle {
+                throttle.acquire(len);
+            }
+            let len = usize::try_from(len).unwrap_or(usize::MAX / 2);
+            match copy_file_range(from, Some(&mut off_in), to, Some(&mut off_out), len) {
+                Ok(0) => return Ok(false),
+                Ok(_) if drop_behind => cache.drop_until(from, to, off_in)?,
+                Ok(_) => {}
+                Err(Errno::XDEV) => break,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        if off_in >= end {
+            return Ok(false);
+        }
+
+        let mut buf = vec![0; 1 << 20];
+        while off_in < end {
+            let len = usize::try_from(end - off_in).map_or(buf.len(), |len| len.min(buf.len()));
+            if let Some(throttle) = throttle {
+                throttle.acquire(len as u64);
+            }
+            let read = from.read_at(&mut buf[..len], off_in)?;
+            if read == 0 {
+                break;
+            }
+            to.write_all_at(&buf[..read], off_in)?;
+            off_in += u64::try_from(read).unwrap();
+            if drop_behind {
+                cache.drop_until(from, to, off_in)?;
+            }
+        }
+        Ok(true)
+    }
+
+    const DROP_BEHIND_CHUNK: u64 = 8 << 20;
+    /// Throttled copies pay for their bandwidth in small steps so that the
+    /// limit holds over short periods too.
+    const THROTTLED_CHUNK: u64 = 1 << 20;
+
+    /// Evicts copied ranges from the page cache. Dirty destination pages can
+    /// only be dropped once they've been written back, which the first advice
+    /// merely starts, so each destination range is advised again along with
+    /// the next one.
+    struct DropBehind {
+        previous: u64,
+        current: u64,
+    }
+
+    impl DropBehind {
+        const fn new(start: u64) -> Self {
+            Self {
+                previous: start,
+                current: start,
+            }
+        }
+
+        fn drop_until(&mut self, from: &File, to: &File, end: u64) -> io::Result<()> {
+            let Some(len) = NonZeroU64::new(end - self.current) else {
+                return Ok(());
+            };
+            fadvise(from, self.current, Some(len), Advice::DontNeed)?;
+            fadvise(
+                to,
+                self.previous,
+                NonZeroU64::new(end - self.previous),
+                Advice::DontNeed,
+            )?;
+            self.previous = self.current;
+            self.current = end;
+            Ok(())
+        }
+    }
+
+    const DIRECT_IO_ALIGNMENT: usize = 4096;
+    const DIRECT_IO_BUF_SIZE: usize = 1 << 20;
+
+    /// Copies the file through an aligned buffer with `O_DIRECT`, returning
+    /// `false` without copying anything if the file system doesn't support it.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip(from, to))
+    )]
+    fn copy_direct(
+        from: &File,
+        to: &File,
+        from_size: u64,
+        throttle: Option<&RateLimiter>,
+    ) -> io::Result<bool> {
+        let from_flags = fcntl_getfl(from)?;
+        let to_flags = fcntl_getfl(to)?;
+        if fcntl_setfl(from, from_flags | OFlags::DIRECT).is_err() {
+            return Ok(false);
+        }
+        if fcntl_setfl(to, to_flags | OFlags::DIRECT).is_err() {
+            fcntl_setfl(from, from_flags)?;
+            return Ok(false);
+        }
+
+        let result = (|| {
+            let mut buf = AlignedBuf::new(DIRECT_IO_BUF_SIZE);
+            let mut offset = 0;
+            let mut end_of_file = false;
+            while !end_of_file && offset < from_size {
+                // Reads may come up short well before the end of the file, so the buffer
+                // is filled up before anything is written.
+                let mut filled = 0;
+                while filled < buf.len() && offset + (filled as u64) < from_size {
+                    if let Some(throttle) = throttle {
+                        let unread = from_size - offset - filled as u64;
+                        throttle.acquire(unread.min((buf.len() - filled) as u64));
+                    }
+                    // Reads must start on a block boundary, so a partially read block is
+                    // read again.
+                    let start = filled - filled % DIRECT_IO_ALIGNMENT;
+                    let read = match from.read_at(&mut buf[start..], offset + start as u64) {
+                        Err(e)
+                            if offset == 0
+                                && filled == 0
+                                && e.raw_os_error() == Some(Errno::INVAL.raw_os_error()) =>
+                        {
+                            return Ok(false);
+                        }
+                        r => r?,
+                    };
+                    if start + read <= filled {
+                        end_of_file = true;
+                        break;
+                    }
+                    filled = start + read;
+                }
+                if filled == 0 {
+                    break;
+                }
+
+                // Writes must cover whole blocks, so the final block is padded with
+                // zeros which are truncated away afterwards.
+                let padded = filled.next_multiple_of(DIRECT_IO_ALIGNMENT);
+                buf[filled..padded].fill(0);
+                to.write_all_at(&buf[..padded], offset)?;
+                offset += filled as u64;
+                if padded != filled {
+                    break;
+                }
+            }
+            if to.metadata()?.len() != offset {
+                to.set_len(offset)?;
+            }
+            Ok(true)
+        })();
+
+        fcntl_setfl(from, from_flags)?;
+        fcntl_setfl(to, to_flags)?;
+        result
+    }
+
+    struct AlignedBuf {
+        ptr: NonNull<u8>,
+        len: usize,
+    }
+
+    impl AlignedBuf {
+        fn new(len: usize) -> Self {
+            let layout = Self::layout(len);
+            let ptr = unsafe { alloc_zeroed(layout) };
+            Self {
+                ptr: NonNull::new(ptr).unwrap_or_else(|| std::alloc::handle_alloc_error(layout)),
+                len,
             }
         }
+
+        fn layout(len: usize) -> Layout {
+            Layout::from_size_align(len, DIRECT_IO_ALIGNMENT).unwrap()
+        }
+    }
+
+    impl Deref for AlignedBuf {
+        type Target = [u8];
+
+        fn deref(&self) -> &[u8] {
+            unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+        }
+    }
+
+    impl DerefMut for AlignedBuf {
+        fn deref_mut(&mut self) -> &mut [u8] {
+            unsafe { slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+        }
+    }
+
+    impl Drop for AlignedBuf {
+        fn drop(&mut self) {
+            unsafe { dealloc(self.ptr.as_ptr(), Self::layout(self.len)) };
+        }
+    }
+
+    /// Copies a file named directly by the caller with the options that
+    /// `fs::copy` has no equivalent for.
+    pub fn copy_file_with_options(
+        from: &Path,
+        to: &Path,
+        preserve_mode: PreserveMode,
+        cache_policy: CachePolicy,
+        preallocate: bool,
+        throttle: Option<&RateLimiter>,
+    ) -> io::Result<()> {
+        let from = File::open(from)?;
+        let metadata = from.metadata()?;
+        let to = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(if preserve_mode == PreserveMode::Ignore {
+                0o666
+            } else {
+                metadata.mode()
+            })
+            .open(to)?;
+        if !metadata.is_file() {
+            io::copy(&mut &from, &mut &to)?;
+            return Ok(());
+        }
+
+        if preserve_mode == PreserveMode::Umask && to.metadata()?.is_file() {
+            // Match fs::copy which is used when no options are set.
+            to.set_permissions(metadata.permissions())?;
+        }
+        if preallocate {
+            preallocate_file(&from, &to, metadata.len(), metadata.blocks())?;
+        }
+        if !(cache_policy == CachePolicy::Direct
+            && copy_direct(&from, &to, metadata.len(), throttle)?)
+        {
+            copy_range(
+                &from,
+                &to,
+                0,
+                metadata.len(),
+                cache_policy != CachePolicy::Normal,
+                throttle,
+                false,
+            )?;
+        }
+        if preserve_mode == PreserveMode::Exact {
+            to.set_permissions(PermissionsExt::from_mode(metadata.mode() & 0o7777))?;
+        }
+        Ok(())
+    }
+
+    /// Files smaller than this are copied with a single read and write
+    /// through a buffer since `copy_file_range` has a high fixed cost.
+    const SMALL_FILE_THRESHOLD: usize = 16 << 10;
+
+    thread_local! {
+        static SMALL_FILE_BUF: RefCell<Box<[u8]>> =
+            RefCell::new(vec![0; SMALL_FILE_THRESHOLD].into_boxed_slice());
     }
 
     #[cfg_attr(
         feature = "tracing",
         tracing::instrument(level = "trace", skip(from, to))
     )]
-    fn copy_regular_file(
-        from: OwnedFd,
-        to: OwnedFd,
+    fn copy_small_file(
+        mut from: &File,
+        mut to: &File,
         file_name: &CStr,
         from_path: &CString,
-        from_size: u64,
-        failed_cross_device: &mut bool,
     ) -> Result<(), Error> {
-        let mut total_copied = 0;
-        loop {
-            let byte_copied =
-                match copy_file_range(&from, None, &to, None, usize::MAX / 2 - total_copied) {
-                    Err(Errno::XDEV) if total_copied == 0 => {
-                        *failed_cross_device = true;
-                        return copy_any_file(from, to, file_name, from_path);
+        let filled = SMALL_FILE_BUF
+            .with_borrow_mut(|buf| -> io::Result<_> {
+                let mut len = 0;
+                while len < buf.len() {
+                    match from.read(&mut buf[len..]) {
+                        Ok(0) => break,
+                        Ok(read) => len += read,
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                        Err(e) => return Err(e),
                     }
-                    r => r.map_io_err(|| {
-                        format!(
-                            "Failed to copy file: {:?}",
-                            join_cstr_paths(from_path, file_name)
-                        )
-                    })?,
-                };
-            total_copied += byte_copied;
-
-            if u64::try_from(total_copied).unwrap() == from_size || byte_copied == 0 {
-                return Ok(());
-            }
+                }
+                to.write_all(&buf[..len])?;
+                Ok(len == buf.len())
+            })
+            .map_io_err(|| {
+                format!(
+                    "Failed to copy file: {:?}",
+                    join_cstr_paths(from_path, file_name)
+                )
+            })?;
+        if filled {
+            // The file grew after it was stat-ed, so copy whatever is left.
+            copy_any_file(from, to, file_name, from_path)?;
         }
+        Ok(())
     }
 
     #[cold]
@@ -588,12 +2825,12 @@ mod compat {
         tracing::instrument(level = "trace", skip(from, to))
     )]
     fn copy_any_file(
-        from: OwnedFd,
-        to: OwnedFd,
+        mut from: &File,
+        mut to: &File,
         file_name: &CStr,
         from_path: &CString,
     ) -> Result<(), Error> {
-        io::copy(&mut File::from(from), &mut File::from(to))
+        io::copy(&mut from, &mut to)
             .map_io_err(|| {
                 format!(
                     "Failed to copy file: {:?}",
@@ -613,7 +2850,9 @@ mod compat {
         file_name: &CStr,
         from_path: &CString,
         to_path: &CString,
-    ) -> Result<(OwnedFd, OwnedFd, u64), Error> {
+        preserve_mode: PreserveMode,
+        preallocate: bool,
+    ) -> Result<(File, File, u64, Mode), Error> {
         let from =
             openat(&from_dir, file_name, OFlags::RDONLY, Mode::empty()).map_io_err(|| {
                 format!(
@@ -623,13 +2862,20 @@ mod compat {
             })?;
 
         let from_size;
+        let from_blocks;
+        let from_mode;
         let to = {
-            let from_mode = {
+            from_mode = {
+                // Stat the open file rather than looking up its path again.
                 let from_metadata = statx(
-                    from_dir,
-                    file_name,
-                    AtFlags::empty(),
-                    StatxFlags::MODE | StatxFlags::SIZE,
+                    &from,
+                    c"",
+                    AtFlags::EMPTY_PATH,
+                    if preallocate {
+                        StatxFlags::MODE | StatxFlags::SIZE | StatxFlags::BLOCKS
+                    } else {
+                        StatxFlags::MODE | StatxFlags::SIZE
+                    },
                 )
                 .map_io_err(|| {
                     format!(
@@ -638,13 +2884,18 @@ mod compat {
                     )
                 })?;
                 from_size = from_metadata.stx_size;
+                from_blocks = from_metadata.stx_blocks;
                 Mode::from_raw_mode(from_metadata.stx_mode.into())
             };
             openat(
                 &to_dir,
                 file_name,
                 OFlags::CREATE | OFlags::TRUNC | OFlags::WRONLY,
-                from_mode,
+                if preserve_mode == PreserveMode::Ignore {
+                    Mode::from_raw_mode(0o666)
+                } else {
+                    from_mode
+                },
             )
             .map_io_err(|| {
                 format!(
@@ -653,8 +2904,61 @@ mod compat {
                 )
             })?
         };
+        if preallocate {
+            preallocate_file(&from, &to, from_size, from_blocks).map_io_err(|| {
+                format!(
+                    "Failed to preallocate file: {:?}",
+                    join_cstr_paths(to_path, file_name)
+                )
+            })?;
+        }
+
+        Ok((File::from(from), File::from(to), from_size, from_mode))
+    }
+
+    /// Allocates the whole file up front so that running out of space is
+    /// reported before anything is written. Filling in the holes of a sparse
+    /// file would make it take up its full size, so only its data is reserved
+    /// for it, without changing its size.
+    fn preallocate_file(
+        from: impl AsFd,
+        to: impl AsFd,
+        from_size: u64,
+        from_blocks: u64,
+    ) -> io::Result<()> {
+        if from_size == 0 {
+            return Ok(());
+        }
+        let result = if from_blocks.saturating_mul(512) < from_size {
+            reserve_data(&from, &to, from_size)
+        } else {
+            fallocate(&to, FallocateFlags::empty(), 0, from_size)
+        };
+        match result {
+            Err(Errno::OPNOTSUPP) => Ok(()),
+            r => r.map_err(io::Error::from),
+        }
+    }
 
-        Ok((from, to, from_size))
+    fn reserve_data(from: impl AsFd, to: impl AsFd, from_size: u64) -> rustix::io::Result<()> {
+        let result = (|| {
+            let mut offset = 0;
+            while offset < from_size {
+                let start = match seek(&from, SeekFrom::Data(offset)) {
+                    Err(Errno::NXIO) => break,
+                    r => r?,
+                };
+                let end = seek(&from, SeekFrom::Hole(start))?.min(from_size);
+                if start >= end {
+                    break;
+                }
+                fallocate(&to, FallocateFlags::KEEP_SIZE, start, end - start)?;
+                offset = end;
+            }
+            Ok(())
+        })();
+        seek(&from, SeekFrom::Start(0))?;
+        result
     }
 
     #[cold]
@@ -669,6 +2973,7 @@ mod compat {
         from_path: &CString,
         to_path: &CString,
         symlink_buf_cache: &Cell<Vec<u8>>,
+        relocation: Option<&Relocation>,
     ) -> Result<(), Error> {
         let from_symlink =
             readlinkat(from_dir, file_name, symlink_buf_cache.take()).map_io_err(|| {
@@ -678,9 +2983,15 @@ mod compat {
                 )
             })?;
 
-        symlinkat(&from_symlink, &to_dir, file_name).map_io_err(|| {
+        let target = Path::new(OsStr::from_bytes(from_symlink.as_bytes()));
+        let target = relocation
+            .and_then(|relocation| {
+                relocation.rewrite(Path::new(OsStr::from_bytes(from_path.as_bytes())), target)
+            })
+            .map_or(Cow::Borrowed(target), Cow::Owned);
+        symlinkat(&*target, &to_dir, file_name).map_io_err(|| {
             format!(
-                "Failed to create symlink: {:?} -> {from_symlink:?}",
+                "Failed to create symlink: {:?} -> {target:?}",
                 join_cstr_paths(to_path, file_name),
             )
         })?;
@@ -689,53 +3000,566 @@ mod compat {
         Ok(())
     }
 
-    struct TreeNode {
+    enum Task {
+        Dir(TreeNode),
+        Files(FileBatch),
+    }
+
+    impl Queued for Task {
+        fn queued_size(&self) -> usize {
+            match self {
+                Self::Dir(node) => node.queued_size(),
+                Self::Files(FileBatch { dir: _, files }) => queued_batch_size(files),
+            }
+        }
+    }
+
+    /// Files from a large directory which are copied separately from the rest
+    /// of its entries.
+    #[derive(Debug)]
+    struct FileBatch {
+        dir: Arc<TreeNode>,
+        files: Vec<(CString, FileType)>,
+    }
+
+    fn queued_batch_size(files: &[(CString, FileType)]) -> usize {
+        mem::size_of::<Task>()
+            + files
+                .iter()
+                .map(|(name, _)| {
+                    mem::size_of::<(CString, FileType)>() + name.as_bytes_with_nul().len()
+                })
+                .sum::<usize>()
+    }
+
+    /// The directories a tree is copied between, which its nodes' names are
+    /// relative to.
+    #[derive(Debug)]
+    struct Roots {
         from: CString,
         to: CString,
+        link_dest: Vec<CString>,
+    }
+
+    struct TreeNode {
+        /// The directory's name in its parent, which is empty for the root.
+        name: CString,
+        roots: Arc<Roots>,
         root_to_inode: u64,
-        messages: Sender<Self>,
+        root_dev: Option<DeviceId>,
+        relocation: Option<Arc<Relocation>>,
+        deferred_mode: Option<Mode>,
+        entry_order: EntryOrder,
+        /// The journal the tree is recorded in and the source directory's
+        /// stamp.
+        journal: Option<(Arc<Journal>, Stamp)>,
+        /// Where to record the entries created in the destination directory,
+        /// which is only needed if the directory itself already existed.
+        transaction: Option<Arc<Transaction>>,
+        /// Whether the whole subtree was copied.
+        done: bool,
+        parent: Option<Arc<Self>>,
+    }
+
+    impl TreeNode {
+        fn queued_size(&self) -> usize {
+            mem::size_of::<Task>() + self.name.as_bytes_with_nul().len()
+        }
+
+        /// Returns this directory's counterpart under `root`, which is one of
+        /// the tree's roots.
+        fn path_under(&self, root: &CString) -> CString {
+            let mut names = Vec::new();
+            let mut node = self;
+            while let Some(ref parent) = node.parent {
+                names.push(node.name.as_bytes());
+                node = parent;
+            }
+
+            let mut path = root.as_bytes().to_vec();
+            for name in names.into_iter().rev() {
+                path.push(u8::try_from(MAIN_SEPARATOR).unwrap());
+                path.extend_from_slice(name);
+            }
+            unsafe { CString::from_vec_unchecked(path) }
+        }
     }
 
     impl Debug for TreeNode {
         fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
             f.debug_struct("TreeNode")
-                .field("from", &self.from)
-                .field("to", &self.to)
+                .field("name", &self.name)
+                .field("roots", &self.roots)
                 .field("root_to_inode", &self.root_to_inode)
+                .field("root_dev", &self.root_dev)
+                .field("relocation", &self.relocation)
+                .field("deferred_mode", &self.deferred_mode)
+                .field("entry_order", &self.entry_order)
+                .field("journal", &self.journal)
+                .field("transaction", &self.transaction)
+                .field("done", &self.done)
                 .finish_non_exhaustive()
         }
     }
+
+    impl Drop for TreeNode {
+        fn drop(&mut self) {
+            if !self.done
+                && let Some((ref journal, _)) = self.journal
+            {
+                // Whatever went wrong, the directories above this one are
+                // incomplete.
+                journal.poison();
+            }
+        }
+    }
+
+    /// Copies batches of small files with a handful of `io_uring` submissions
+    /// instead of several blocking syscalls per file.
+    #[cfg(feature = "io_uring")]
+    pub mod io_uring {
+        use std::{
+            borrow::Cow,
+            cell::RefCell,
+            ffi::{CString, c_void},
+            mem,
+            mem::MaybeUninit,
+            os::fd::AsRawFd,
+            path::{Path, PathBuf},
+            sync::{Arc, OnceLock, atomic::AtomicBool},
+        };
+
+        use rustix::{
+            fs::{AtFlags, FileType, OFlags, Statx, StatxFlags},
+            io_uring::{
+                IoringFeatureFlags, IoringOp, IoringSqeFlags, addr_or_splice_off_in_union,
+                io_uring_ptr, io_uring_sqe, io_uring_user_data, len_union, off_or_addr2_union,
+                op_flags_union, splice_fd_in_or_file_index_or_addr_len_union,
+            },
+        };
+
+        use super::{CachePolicy, DirFds, PreserveMode, Settings};
+        use crate::ops::{
+            Stats, Tuning,
+            compat::DirectoryOp,
+            throttle::RateLimiter,
+            transaction::Transaction,
+            uring::{Ring, is_available},
+        };
+
+        const RING_ENTRIES: u32 = 256;
+        /// Files are copied in groups of this many, each needing at most five
+        /// submission queue entries per round.
+        pub const BATCH_SIZE: usize = 32;
+        /// Every file in a batch gets a slot for its source and one for its
+        /// destination.
+        const FILE_SLOTS: u32 = 2 * BATCH_SIZE as u32;
+        /// Larger files are left to `copy_file_range`.
+        const MAX_FILE_SIZE: usize = 64 * 1024;
+
+        const OPS: [IoringOp; 5] = [
+            IoringOp::Openat,
+            IoringOp::Statx,
+            IoringOp::Read,
+            IoringOp::Write,
+            IoringOp::Close,
+        ];
+        /// Linked operations need to use the files opened earlier in their
+        /// chain.
+        const FEATURES: IoringFeatureFlags = IoringFeatureFlags::LINKED_FILE;
+
+        struct Copier {
+            ring: Ring,
+            buf: Box<[u8]>,
+            metadata: Box<[MaybeUninit<Statx>; BATCH_SIZE]>,
+        }
+
+        thread_local! {
+            static COPIER: RefCell<Option<Copier>> = RefCell::new(
+                Ring::new(RING_ENTRIES, &OPS, FEATURES)
+                    .and_then(|ring| ring.register_file_slots(FILE_SLOTS).map(|()| ring))
+                    .ok()
+                    .map(|ring| Copier {
+                        ring,
+                        buf: vec![0; BATCH_SIZE * MAX_FILE_SIZE].into_boxed_slice(),
+                        metadata: Box::new([MaybeUninit::uninit(); BATCH_SIZE]),
+                    }),
+            );
+        }
+
+        pub fn is_supported() -> bool {
+            static SUPPORTED: OnceLock<bool> = OnceLock::new();
+            *SUPPORTED.get_or_init(|| is_available(&OPS, FEATURES))
+        }
+
+        /// Copies like the regular implementation, except that small files are
+        /// held back and submitted together.
+        pub fn copy_impl<'a, 'b>(
+            settings: Settings,
+            link_dest: Vec<PathBuf>,
+            tuning: Tuning,
+            throttle: Option<Arc<RateLimiter>>,
+            cancel: Option<Arc<AtomicBool>>,
+            stats: Option<Arc<Stats>>,
+        ) -> impl DirectoryOp<(Cow<'a, Path>, Cow<'b, Path>, Option<Arc<Transaction>>)> {
+            super::copy_impl(
+                Settings {
+                    io_uring: true,
+                    ..settings
+                },
+                link_dest,
+                tuning,
+                throttle,
+                cancel,
+                stats,
+            )
+        }
+
+        pub fn accepts(file_type: FileType, settings: Settings) -> bool {
+            settings.io_uring
+                && file_type == FileType::RegularFile
+                && !settings.hard_link
+                && settings.symbolic_link.is_none()
+                && settings.preserve_mode != PreserveMode::Exact
+                && !settings.preserve_mtime
+                && settings.cache_policy == CachePolicy::Normal
+                && !settings.sync_mode.syncs_files()
+                && !settings.preallocate
+                && !settings.journal
+        }
+
+        /// Copies the named files, returning the ones which must go through the
+        /// regular path instead.
+        pub(super) fn copy_small_files(
+            dirs: &DirFds,
+            names: &mut Vec<CString>,
+            settings: Settings,
+        ) -> Vec<CString> {
+            if names.is_empty() {
+                return Vec::new();
+            }
+            COPIER.with_borrow_mut(|copier| {
+                let Some(copier_) = copier else {
+                    return mem::take(names);
+                };
+                if let Ok(fallback) = copy_batch(copier_, dirs, names, settings) {
+                    names.clear();
+                    fallback
+                } else {
+                    // Nothing is in flight anymore, but the ring can't be trusted after
+                    // failing, so everything is left to the regular path from now on.
+                    *copier = None;
+                    mem::take(names)
+                }
+            })
+        }
+
+        // Files are opened straight into the ring's slots so that the operations
+        // linked after the open can use them. The statx has to finish before the
+        // rest of the chain can be queued since it decides how much to read and
+        // write, so each batch takes two rounds: open -> statx, then
+        // read -> open -> write -> close -> close.
+        const OPEN_FROM: u64 = 0;
+        const STATX: u64 = 1;
+        const READ: u64 = 2;
+        const OPEN_TO: u64 = 3;
+        const WRITE: u64 = 4;
+        const CLOSE_TO: u64 = 5;
+        const CLOSE_FROM: u64 = 6;
+
+        fn copy_batch(
+            Copier {
+                ring,
+                buf,
+                metadata,
+            }: &mut Copier,
+            dirs: &DirFds,
+            names: &[CString],
+            Settings {
+                follow_symlinks,
+                preserve_mode,
+                ..
+            }: Settings,
+        ) -> rustix::io::Result<Vec<CString>> {
+            // The files were only accepted because of what the blocking path found,
+            // so they must be looked up the same way.
+            let (at_flags, open_flags) = if follow_symlinks {
+                (AtFlags::empty(), OFlags::empty())
+            } else {
+                (AtFlags::SYMLINK_NOFOLLOW, OFlags::NOFOLLOW)
+            };
+            let mut opened = vec![false; names.len()];
+            let mut statted = vec![false; names.len()];
+            let mut copied = vec![false; names.len()];
+            let mut closed = vec![false; names.len()];
+
+            for (i, name) in names.iter().enumerate() {
+                unsafe {
+                    ring.push(io_uring_sqe {
+                        flags: IoringSqeFlags::IO_LINK,
+                        ..openat(
+                            dirs.from.as_raw_fd(),
+                            name,
+                            OFlags::RDONLY | open_flags,
+                            0,
+                            from_slot(i),
+                            user_data(i, OPEN_FROM),
+                        )
+                    });
+                    ring.push(io_uring_sqe {
+                        opcode: IoringOp::Statx,
+                        fd: dirs.from.as_raw_fd(),
+                        addr_or_splice_off_in: addr(name.as_ptr().cast_mut().cast()),
+                        len: len_union {
+                            len: (StatxFlags::TYPE | StatxFlags::MODE | StatxFlags::SIZE).bits(),
+                        },
+                        op_flags: op_flags_union {
+                            statx_flags: at_flags,
+                        },
+                        off_or_addr2: off_or_addr2_union {
+                            addr2: io_uring_ptr::new(metadata[i].as_mut_ptr().cast()),
+                        },
+                        user_data: user_data(i, STATX),
+                        ..Default::default()
+                    });
+                }
+            }
+            ring.submit_and_wait(|data, res| {
+                let (i, op) = parse_user_data(data);
+                match op {
+                    OPEN_FROM => opened[i] = res == 0,
+                    STATX => statted[i] = res == 0,
+                    _ => {}
+                }
+            })?;
+
+            for (i, (name, chunk)) in names
+                .iter()
+                .zip(buf.chunks_exact_mut(MAX_FILE_SIZE))
+                .enumerate()
+            {
+                if !statted[i] {
+                    continue;
+                }
+                let metadata = unsafe { metadata[i].assume_init_ref() };
+                if FileType::from_raw_mode(metadata.stx_mode.into()) != FileType::RegularFile
+                    || metadata.stx_size > MAX_FILE_SIZE as u64
+                {
+                    continue;
+                }
+                let mode = if preserve_mode == PreserveMode::Ignore {
+                    0o666
+                } else {
+                    u32::from(metadata.stx_mode) & 0o7777
+                };
+                #[allow(clippy::cast_possible_truncation)]
+                let len = metadata.stx_size as u32;
+                let mut chain = Vec::with_capacity(5);
+                if len > 0 {
+                    // A short read breaks the chain, so a truncated file is never
+                    // written.
+                    chain.push(io_uring_sqe {
+                        opcode: IoringOp::Read,
+                        fd: from_slot(i).cast_signed() - 1,
+                        addr_or_splice_off_in: addr(chunk.as_mut_ptr().cast()),
+                        len: len_union { len },
+                        user_data: user_data(i, READ),
+                        ..Default::default()
+                    });
+                }
+                chain.push(openat(
+                    dirs.to.as_raw_fd(),
+                    name,
+                    OFlags::CREATE | OFlags::TRUNC | OFlags::WRONLY,
+                    mode,
+                    to_slot(i),
+                    user_data(i, OPEN_TO),
+                ));
+                if len > 0 {
+                    chain.push(io_uring_sqe {
+                        opcode: IoringOp::Write,
+                        fd: to_slot(i).cast_signed() - 1,
+                        addr_or_splice_off_in: addr(chunk.as_mut_ptr().cast()),
+                        len: len_union { len },
+                        user_data: user_data(i, WRITE),
+                        ..Default::default()
+                    });
+                }
+                chain.push(close(to_slot(i), user_data(i, CLOSE_TO)));
+                chain.push(close(from_slot(i), user_data(i, CLOSE_FROM)));
+
+                let last = chain.len() - 1;
+                for (j, mut sqe) in chain.into_iter().enumerate() {
+                    if matches!(sqe.opcode, IoringOp::Read | IoringOp::Write) {
+                        sqe.flags |= IoringSqeFlags::FIXED_FILE;
+                    }
+                    if j < last {
+                        sqe.flags |= IoringSqeFlags::IO_LINK;
+                    }
+                    unsafe {
+                        ring.push(sqe);
+                    }
+                }
+                copied[i] = len == 0;
+            }
+            ring.submit_and_wait(|data, res| {
+                let (i, op) = parse_user_data(data);
+                match op {
+                    OPEN_TO if res != 0 => copied[i] = false,
+                    WRITE => {
+                        copied[i] = res >= 0
+                            && res.cast_unsigned()
+                                == unsafe {
+                                    #[allow(clippy::cast_possible_truncation)]
+                                    {
+                                        metadata[i].assume_init_ref().stx_size as u32
+                                    }
+                                }
+                    }
+                    CLOSE_FROM => closed[i] = res == 0,
+                    _ => {}
+                }
+            })?;
+
+            // Broken chains and files which turned out not to be small leave their
+            // slots open.
+            if opened
+                .iter()
+                .zip(&closed)
+                .any(|(&opened, &closed)| opened && !closed)
+            {
+                ring.clear_file_slots(FILE_SLOTS)?;
+            }
+
+            Ok(names
+                .iter()
+                .zip(copied)
+                .filter(|&(_, copied)| !copied)
+                .map(|(name, _)| name.clone())
+                .collect())
+        }
+
+        /// Opens a file into the given slot, counting from one.
+        fn openat(
+            dir: i32,
+            name: &CString,
+            flags: OFlags,
+            mode: u32,
+            slot: u32,
+            user_data: io_uring_user_data,
+        ) -> io_uring_sqe {
+            io_uring_sqe {
+                opcode: IoringOp::Openat,
+                fd: dir,
+                addr_or_splice_off_in: addr(name.as_ptr().cast_mut().cast()),
+                len: len_union { len: mode },
+                op_flags: op_flags_union { open_flags: flags },
+                splice_fd_in_or_file_index_or_addr_len:
+                    splice_fd_in_or_file_index_or_addr_len_union { file_index: slot },
+                user_data,
+                ..Default::default()
+            }
+        }
+
+        /// Closes the file in the given slot, counting from one.
+        fn close(slot: u32, user_data: io_uring_user_data) -> io_uring_sqe {
+            io_uring_sqe {
+                opcode: IoringOp::Close,
+                splice_fd_in_or_file_index_or_addr_len:
+                    splice_fd_in_or_file_index_or_addr_len_union { file_index: slot },
+                user_data,
+                ..Default::default()
+            }
+        }
+
+        #[allow(clippy::cast_possible_truncation)]
+        const fn from_slot(index: usize) -> u32 {
+            index as u32 + 1
+        }
+
+        #[allow(clippy::cast_possible_truncation)]
+        const fn to_slot(index: usize) -> u32 {
+            (BATCH_SIZE + index) as u32 + 1
+        }
+
+        const fn addr(ptr: *mut c_void) -> addr_or_splice_off_in_union {
+            addr_or_splice_off_in_union {
+                addr: io_uring_ptr::new(ptr),
+            }
+        }
+
+        const fn user_data(index: usize, op: u64) -> io_uring_user_data {
+            io_uring_user_data::from_u64((index as u64) << 3 | op)
+        }
+
+        #[allow(clippy::cast_possible_truncation)]
+        const fn parse_user_data(data: u64) -> (usize, u64) {
+            ((data >> 3) as usize, data & 0b111)
+        }
+    }
 }
 
 #[cfg(not(target_os = "linux"))]
 mod compat {
-    use std::{borrow::Cow, fmt::Debug, fs, io, path::Path};
+    use std::{
+        borrow::Cow,
+        fmt::Debug,
+        fs, io,
+        path::{Path, PathBuf},
+        sync::{
+            Arc,
+            atomic::{AtomicBool, Ordering},
+        },
+    };
 
     use rayon::prelude::*;
 
+    #[cfg(unix)]
+    use super::{PreserveMode, SyncMode};
+    use super::{
+        Relocation, Settings, check_cancelled, copy_file_with_mode, copy_mtime,
+        hard_link_or_fallback, path_exists, symlink_file, symlink_target, sync_file,
+    };
     use crate::{
         Error,
-        ops::{IoErr, compat::DirectoryOp},
+        ops::{
+            IoErr, Stats, Tuning, compat::DirectoryOp, throttle::RateLimiter,
+            transaction::Transaction,
+        },
     };
 
     struct Impl {
-        follow_symlinks: bool,
-        hard_link: bool,
+        settings: Settings,
+        link_dest: Vec<PathBuf>,
+        cancel: Option<Arc<AtomicBool>>,
     }
 
     pub fn copy_impl<'a, 'b>(
-        follow_symlinks: bool,
-        hard_link: bool,
-    ) -> impl DirectoryOp<(Cow<'a, Path>, Cow<'b, Path>)> {
+        settings: Settings,
+        link_dest: Vec<PathBuf>,
+        _tuning: Tuning,
+        _throttle: Option<Arc<RateLimiter>>,
+        cancel: Option<Arc<AtomicBool>>,
+        _stats: Option<Arc<Stats>>,
+    ) -> impl DirectoryOp<(Cow<'a, Path>, Cow<'b, Path>, Option<Arc<Transaction>>)> {
         Impl {
-            follow_symlinks,
-            hard_link,
+            settings,
+            link_dest,
+            cancel,
         }
     }
 
-    impl DirectoryOp<(Cow<'_, Path>, Cow<'_, Path>)> for Impl {
+    impl DirectoryOp<(Cow<'_, Path>, Cow<'_, Path>, Option<Arc<Transaction>>)> for Impl {
         #[cfg_attr(feature = "tracing", tracing::instrument(level = "trace", skip(self)))]
-        fn run(&self, (from, to): (Cow<Path>, Cow<Path>)) -> Result<(), Error> {
+        fn run(
+            &self,
+            (from, to, transaction): (Cow<Path>, Cow<Path>, Option<Arc<Transaction>>),
+        ) -> Result<(), Error> {
+            let (from, to) = self
+                .settings
+                .roots(from, to)
+                .map_io_err(|| "Failed to resolve the current directory.")?;
+            let relocation = Relocation::new(&from, &to, self.settings.symlink_targets);
             #[cfg(unix)]
             let root_to_inode = {
                 use std::os::unix::fs::MetadataExt;
@@ -743,17 +3567,37 @@ mod compat {
                     .map_io_err(|| format!("Failed to get inode: {to:?}"))?
                     .ino()
             };
+            #[cfg(unix)]
+            let root_dev = if self.settings.one_file_system {
+                use std::os::unix::fs::MetadataExt;
+                Some(
+                    fs::metadata(&*from)
+                        .map_io_err(|| format!("Failed to get device: {from:?}"))?
+                        .dev(),
+                )
+            } else {
+                None
+            };
             // TODO get rid of this crap once https://github.com/tokio-rs/tracing/issues/3320 is fixed
             #[cfg(not(unix))]
             let root_to_inode = 0;
-            copy_dir(
+            #[cfg(not(unix))]
+            let root_dev = None;
+            let result = copy_dir(
                 &from,
-                to,
-                self.follow_symlinks,
-                self.hard_link,
+                &to,
+                self.settings,
+                relocation.as_ref(),
+                &self.link_dest,
                 root_to_inode,
-            )
-            .map_io_err(|| format!("Failed to copy directory: {from:?}"))
+                root_dev,
+                transaction.as_deref(),
+                self.cancel.as_deref(),
+            );
+            if result.is_err() {
+                check_cancelled(self.cancel.as_deref())?;
+            }
+            result.map_io_err(|| format!("Failed to copy directory: {from:?}"))
         }
 
         #[cfg_attr(feature = "tracing", tracing::instrument(level = "trace", skip(self)))]
@@ -762,35 +3606,64 @@ mod compat {
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     #[cfg_attr(feature = "tracing", tracing::instrument(level = "info"))]
     fn copy_dir<P: AsRef<Path> + Debug, Q: AsRef<Path> + Debug>(
         from: P,
         to: Q,
-        follow_symlinks: bool,
-        hard_link: bool,
+        settings: Settings,
+        relocation: Option<&Relocation>,
+        link_dest: &[PathBuf],
         root_to_inode: u64,
+        root_dev: Option<u64>,
+        transaction: Option<&Transaction>,
+        cancel: Option<&AtomicBool>,
     ) -> Result<(), io::Error> {
+        if cancel.is_some_and(|cancel| cancel.load(Ordering::Relaxed)) {
+            return Err(io::ErrorKind::Interrupted.into());
+        }
+        let Settings {
+            follow_symlinks,
+            copy_dangling_symlinks,
+            hard_link,
+            fallback_to_copy,
+            symbolic_link,
+            one_file_system: _,
+            preserve_mode,
+            preserve_mtime,
+            symlink_targets: _,
+            cache_policy,
+            sync_mode,
+            preallocate,
+            entry_order: _,
+            journal: _,
+            resume: _,
+        } = settings;
         let from = from.as_ref();
         let to = to.as_ref();
-        match {
+        #[cfg(unix)]
+        let from_mode = {
+            use std::os::unix::fs::MetadataExt;
+            fs::metadata(from)?.mode()
+        };
+        let created = match {
             #[cfg_attr(not(unix), allow(unused_mut))]
             let mut builder = fs::DirBuilder::new();
             #[cfg(unix)]
-            {
-                use std::os::unix::fs::{DirBuilderExt, MetadataExt};
-
-                let from_metadata = from.symlink_metadata()?;
-                builder.mode(from_metadata.mode());
+            if preserve_mode != PreserveMode::Ignore {
+                use std::os::unix::fs::DirBuilderExt;
+                builder.mode(from_mode | 0o700);
             }
             builder.create(to)
         } {
-            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
-            r => r?,
-        }
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => false,
+            r => r.map(|()| true)?,
+        };
         #[cfg(not(unix))]
-        let _ = root_to_inode;
+        let _ = (root_to_inode, root_dev, created);
 
-        from.read_dir()?
+        let result = from
+            .read_dir()?
             .par_bridge()
             .try_for_each(|dir_entry| -> io::Result<()> {
                 let dir_entry = dir_entry?;
@@ -803,43 +3676,163 @@ mod compat {
                     }
                 }
 
+                let to_dir = to;
                 let to = to.join(dir_entry.file_name());
+                // Directories we create are removed along with everything in them.
+                let transaction = match transaction {
+                    Some(transaction) if !path_exists(&to)? => {
+                        transaction.record(to.clone());
+                        None
+                    }
+                    transaction => transaction,
+                };
                 let file_type = dir_entry.file_type()?;
-                let file_type = if follow_symlinks && file_type.is_symlink() {
-                    fs::metadata(dir_entry.path())?.file_type()
+                let follow_symlink = follow_symlinks && file_type.is_symlink();
+                let file_type = if follow_symlink {
+                    match fs::metadata(dir_entry.path()) {
+                        Err(e) if copy_dangling_symlinks && e.kind() == io::ErrorKind::NotFound => {
+                            file_type
+                        }
+                        r => r?.file_type(),
+                    }
                 } else {
                     file_type
                 };
 
                 if file_type.is_dir() {
+                    #[cfg(unix)]
+                    if let Some(root_dev) = root_dev {
+                        use std::os::unix::fs::MetadataExt;
+
+                        let metadata = if follow_symlinks {
+                            fs::metadata(dir_entry.path())?
+                        } else {
+                            dir_entry.metadata()?
+                        };
+                        if metadata.dev() != root_dev {
+                            // Like `cp -x`, mount points are recreated as empty directories.
+                            return match fs::create_dir(to) {
+                                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(()),
+                                r => r,
+                            };
+                        }
+                    }
                     copy_dir(
                         dir_entry.path(),
                         to,
-                        follow_symlinks,
-                        hard_link,
+                        settings,
+                        relocation,
+                        &link_dest
+                            .iter()
+                            .map(|dir| dir.join(dir_entry.file_name()))
+                            .collect::<Vec<_>>(),
                         root_to_inode,
+                        root_dev,
+                        transaction,
+                        cancel,
                     )?;
+                } else if let Some(style) = symbolic_link {
+                    symlink_file(&symlink_target(&dir_entry.path(), to_dir, style), &to)?;
                 } else if file_type.is_symlink() {
-                    let from = fs::read_link(dir_entry.path())?;
-                    if hard_link {
-                        fs::hard_link(dir_entry.path(), to)?;
-                    } else {
+                    let target = fs::read_link(dir_entry.path())?;
+                    let target = relocation
+                        .and_then(|relocation| relocation.rewrite(from, &target))
+                        .unwrap_or(target);
+                    let linked = hard_link
+                        && hard_link_or_fallback(&dir_entry.path(), &to, fallback_to_copy)?;
+                    if !linked {
                         #[cfg(unix)]
-                        std::os::unix::fs::symlink(from, to)?;
+                        std::os::unix::fs::symlink(target, to)?;
                         #[cfg(windows)]
-                        if fs::metadata(&from)?.file_type().is_dir() {
-                            std::os::windows::fs::symlink_dir(from, to)?;
+                        if fs::metadata(&target)?.file_type().is_dir() {
+                            std::os::windows::fs::symlink_dir(target, to)?;
                         } else {
-                            std::os::windows::fs::symlink_file(from, to)?;
+                            std::os::windows::fs::symlink_file(target, to)?;
                         }
                     }
-                } else if hard_link {
-                    fs::hard_link(dir_entry.path(), to)?;
                 } else {
-                    fs::copy(dir_entry.path(), to)?;
+                    let linked = link_previous_file(&dir_entry.path(), link_dest, &to)?
+                        || (hard_link && {
+                            let from = if follow_symlink {
+                                fs::canonicalize(dir_entry.path())?
+                            } else {
+                                dir_entry.path()
+                            };
+                            hard_link_or_fallback(&from, &to, fallback_to_copy)?
+                        });
+                    if !linked {
+                        copy_file_with_mode(
+                            &dir_entry.path(),
+                            &to,
+                            preserve_mode,
+                            cache_policy,
+                            preallocate,
+                            None,
+                        )?;
+                        if preserve_mtime {
+                            copy_mtime(&dir_entry.path(), &to)?;
+                        }
+                        if sync_mode.syncs_files() {
+                            sync_file(&to)?;
+                        }
+                    }
                 }
 
                 Ok(())
-            })
+            });
+
+        // Apply the real permissions now that the directory has been filled in.
+        #[cfg(unix)]
+        if preserve_mode == PreserveMode::Exact {
+            use std::os::unix::fs::PermissionsExt;
+
+            fs::set_permissions(to, fs::Permissions::from_mode(from_mode & 0o7777))?;
+        } else if preserve_mode == PreserveMode::Umask && created && 0o700 & !from_mode != 0 {
+            use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+            let to_mode = to.symlink_metadata()?.mode();
+            fs::set_permissions(
+                to,
+                fs::Permissions::from_mode(to_mode & !(0o700 & !from_mode) & 0o7777),
+            )?;
+        }
+        #[cfg(unix)]
+        if result.is_ok() && sync_mode == SyncMode::Directories {
+            fs::File::open(to)?.sync_all()?;
+        }
+        #[cfg(not(unix))]
+        let _ = sync_mode;
+        result
+    }
+
+    /// Hard links the file to an unchanged version of it from one of the
+    /// previous snapshots, returning whether one was found.
+    fn link_previous_file(from: &Path, previous_dirs: &[PathBuf], to: &Path) -> io::Result<bool> {
+        if previous_dirs.is_empty() {
+            return Ok(false);
+        }
+        let from_metadata = fs::metadata(from)?;
+        if !from_metadata.is_file() {
+            return Ok(false);
+        }
+        let Some(name) = from.file_name() else {
+            return Ok(false);
+        };
+
+        for dir in previous_dirs {
+            let previous = dir.join(name);
+            let metadata = match fs::symlink_metadata(&previous) {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                r => r?,
+            };
+            if metadata.is_file()
+                && metadata.len() == from_metadata.len()
+                && metadata.modified()? == from_metadata.modified()?
+                && metadata.permissions() == from_metadata.permissions()
+            {
+                return hard_link_or_fallback(&previous, to, true);
+            }
+        }
+        Ok(false)
     }
 }
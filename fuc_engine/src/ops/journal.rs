@@ -0,0 +1,252 @@
+use std::{
+    collections::HashMap,
+    ffi::CStr,
+    fs,
+    fs::File,
+    io,
+    io::Write,
+    os::fd::AsFd,
+    path::{Path, PathBuf},
+    str,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+use rustix::fs::{AtFlags, CWD, Mode, OFlags, StatxFlags, openat, statx};
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Kind {
+    File,
+    /// A directory whose whole subtree was copied.
+    Dir,
+}
+
+impl Kind {
+    const fn tag(self) -> u8 {
+        match self {
+            Self::File => b'f',
+            Self::Dir => b'd',
+        }
+    }
+}
+
+/// What a journal remembers about a source entry so that it can tell whether
+/// the entry changed since it was copied.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Stamp {
+    size: u64,
+    mtime_sec: i64,
+    mtime_nsec: u32,
+}
+
+impl Stamp {
+    pub fn of(dir: impl AsFd, name: &CStr, flags: AtFlags) -> rustix::io::Result<Self> {
+        let metadata = statx(dir, name, flags, StatxFlags::SIZE | StatxFlags::MTIME)?;
+        Ok(Self {
+            size: metadata.stx_size,
+            mtime_sec: metadata.stx_mtime.tv_sec,
+            mtime_nsec: metadata.stx_mtime.tv_nsec,
+        })
+    }
+}
+
+/// How many records are appended between flushes of the journal.
+const SYNC_INTERVAL: usize = 1024;
+
+/// The stamps of the entries in a journal, keyed by their kind and relative
+/// path.
+type Records = HashMap<(u8, Box<[u8]>), Stamp>;
+
+/// An append-only record of the entries a copy has finished, kept beside the
+/// destination so that an interrupted copy can pick up where it left off.
+///
+/// Each record is `<kind> <size> <mtime seconds> <mtime nanoseconds> <path>`
+/// terminated by a NUL, with the path relative to the destination root.
+/// Records are appended with a single write to a file opened with
+/// `O_APPEND`, which the kernel doesn't interleave with other appends, so
+/// workers can write to the journal without coordinating. A record torn by a
+/// crash lacks its terminator and is ignored.
+///
+/// Entries are flushed to disk before they're recorded, so a record which
+/// survives a crash never vouches for lost data. The journal itself is only
+/// flushed every so often and once the copy stops, since losing its latest
+/// records merely means copying those entries again.
+#[derive(Debug)]
+pub struct Journal {
+    path: PathBuf,
+    file: File,
+    /// The length of the destination root's path, which is stripped from the
+    /// paths being recorded.
+    root_len: usize,
+    /// The entries recorded by the run being resumed.
+    previous: Option<Records>,
+    /// Set once part of the tree failed to copy, after which no directory can
+    /// be vouched for anymore.
+    poisoned: AtomicBool,
+    records: AtomicUsize,
+    removed: AtomicBool,
+}
+
+impl Journal {
+    /// Opens the journal for the tree copied to `root`, either continuing the
+    /// one left by a previous run or starting over.
+    pub fn open(path: &Path, root: &CStr, resume: bool) -> io::Result<Self> {
+        let previous = if resume {
+            match fs::read(path) {
+                Ok(journal) => Some(parse(&journal)),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Some(HashMap::new()),
+                Err(e) => return Err(e),
+            }
+        } else {
+            None
+        };
+        let file = openat(
+            CWD,
+            path,
+            OFlags::WRONLY
+                | OFlags::CREATE
+                | OFlags::APPEND
+                | OFlags::CLOEXEC
+                | if resume {
+                    OFlags::empty()
+                } else {
+                    OFlags::TRUNC
+                },
+            Mode::from_raw_mode(0o666),
+        )?;
+        Ok(Self {
+            path: path.to_owned(),
+            file: File::from(file),
+            root_len: root.count_bytes(),
+            previous,
+            poisoned: AtomicBool::new(false),
+            records: AtomicUsize::new(0),
+            removed: AtomicBool::new(false),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns whether a previous run is being resumed, in which case the
+    /// destination may hold leftovers of entries it didn't finish.
+    pub const fn is_resuming(&self) -> bool {
+        self.previous.is_some()
+    }
+
+    /// Returns whether the previous run finished copying the entry (or the
+    /// directory `dir` itself if there's no name) and it hasn't changed since.
+    pub fn completed(&self, kind: Kind, dir: &CStr, name: Option<&CStr>, stamp: Stamp) -> bool {
+        self.previous.as_ref().is_some_and(|previous| {
+            previous.get(&(kind.tag(), self.relative_path(dir, name).into())) == Some(&stamp)
+        })
+    }
+
+    /// Like [`Journal::completed`], but also checks that the copy of the entry
+    /// in `to_dir` is still there.
+    pub fn completed_in(
+        &self,
+        kind: Kind,
+        to_dir: impl AsFd,
+        dir: &CStr,
+        name: &CStr,
+        stamp: Stamp,
+    ) -> bool {
+        self.completed(kind, dir, Some(name), stamp)
+            && statx(to_dir, name, AtFlags::SYMLINK_NOFOLLOW, StatxFlags::empty()).is_ok()
+    }
+
+    pub fn record(
+        &self,
+        kind: Kind,
+        dir: &CStr,
+        name: Option<&CStr>,
+        Stamp {
+            size,
+            mtime_sec,
+            mtime_nsec,
+        }: Stamp,
+    ) -> io::Result<()> {
+        if kind == Kind::Dir && self.poisoned.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        let mut record = format!(
+            "{} {size} {mtime_sec} {mtime_nsec} ",
+            char::from(kind.tag())
+        )
+        .into_bytes();
+        record.extend_from_slice(&self.relative_path(dir, name));
+        record.push(0);
+        (&self.file).write_all(&record)?;
+        if self.records.fetch_add(1, Ordering::Relaxed) % SYNC_INTERVAL == SYNC_INTERVAL - 1 {
+            self.file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    pub fn poison(&self) {
+        self.poisoned.store(true, Ordering::Relaxed);
+    }
+
+    /// Deletes the journal once it's no longer needed.
+    pub fn remove(&self) -> io::Result<()> {
+        self.removed.store(true, Ordering::Relaxed);
+        match fs::remove_file(&self.path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            r => r,
+        }
+    }
+
+    fn relative_path(&self, dir: &CStr, name: Option<&CStr>) -> Vec<u8> {
+        let dir = dir.to_bytes().get(self.root_len + 1..).unwrap_or_default();
+        let name = name.map_or_else(Default::default, CStr::to_bytes);
+        let mut path = Vec::with_capacity(dir.len() + 1 + name.len());
+        path.extend_from_slice(dir);
+        if !dir.is_empty() && !name.is_empty() {
+            path.push(b'/');
+        }
+        path.extend_from_slice(name);
+        path
+    }
+}
+
+impl Drop for Journal {
+    fn drop(&mut self) {
+        // Keep as much progress as possible for the next run. Failing to is
+        // harmless since the entries would just be copied again.
+        if !*self.removed.get_mut() {
+            let _ = self.file.sync_data();
+        }
+    }
+}
+
+fn parse(journal: &[u8]) -> Records {
+    let mut records = journal.split(|&b| b == 0);
+    // Whatever follows the last terminator is either empty or torn.
+    records.next_back();
+    records
+        .filter_map(|record| {
+            let mut fields = record.splitn(5, |&b| b == b' ');
+            let kind = match fields.next()? {
+                b"f" => Kind::File,
+                b"d" => Kind::Dir,
+                _ => return None,
+            };
+            let mut number = || str::from_utf8(fields.next()?).ok();
+            let stamp = Stamp {
+                size: number()?.parse().ok()?,
+                mtime_sec: number()?.parse().ok()?,
+                mtime_nsec: number()?.parse().ok()?,
+            };
+            Some(((kind.tag(), fields.next()?.into()), stamp))
+        })
+        .collect()
+}
+
+/// Returns where the journal for a tree copied to `root` lives: next to it,
+/// named after it.
+pub fn journal_path(root: &Path) -> Option<PathBuf> {
+    let mut name = root.file_name()?.to_os_string();
+    name.push(".fuc-journal");
+    Some(root.with_file_name(name))
+}
@@ -1,14 +1,102 @@
-use std::{borrow::Cow, io};
+use std::{
+    borrow::Cow,
+    io,
+    num::NonZeroUsize,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
-pub use copy::{CopyOp, CopyOpBuilder, copy_file};
+pub use copy::{
+    CachePolicy, CopyOp, CopyOpBuilder, Dereference, PreserveMode, SymlinkStyle, SymlinkTargets,
+    SyncMode, copy_file,
+};
 #[cfg(target_os = "linux")]
-use linux::{concat_cstrs, join_cstr_paths, path_buf_to_cstring};
+use linux::{
+    Arcable, DeviceId, DirEntries, IoPriorityGuard, concat_cstrs, is_mount_point, join_cstr_paths,
+    path_buf_to_cstring, set_io_priority,
+};
 pub use remove::{RemoveOp, RemoveOpBuilder, remove_file};
 
 use crate::Error;
 
 mod copy;
+#[cfg(target_os = "linux")]
+mod journal;
 mod remove;
+#[cfg(target_os = "linux")]
+mod scheduler;
+mod throttle;
+mod transaction;
+#[cfg(all(target_os = "linux", feature = "io_uring"))]
+mod uring;
+
+/// The order in which the entries of each directory are processed.
+///
+/// Only applied on Linux.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub enum EntryOrder {
+    /// Process entries in the order the file system lists them.
+    #[default]
+    Unordered,
+    /// Sort entries by inode number, which roughly follows where they live on
+    /// disk.
+    Inode,
+    /// Sort files by the location of their first block on disk. Removals only
+    /// need the inodes and use [`EntryOrder::Inode`] instead.
+    Physical,
+    /// Use [`EntryOrder::Inode`] for trees on rotational disks and
+    /// [`EntryOrder::Unordered`] everywhere else.
+    Auto,
+}
+
+/// The I/O scheduling class and level given to the threads running an
+/// operation, as with `ionice`. Levels go from 0 (served first) to 7 and
+/// larger ones are treated as 7.
+///
+/// Only applied on Linux.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum IoPriority {
+    /// Served ahead of everyone else. Requires `CAP_SYS_ADMIN`.
+    RealTime(u8),
+    /// The class everything runs in by default.
+    BestEffort(u8),
+    /// Only served when no one else needs the disk.
+    Idle,
+}
+
+/// What an operation observed while it ran, which is filled in once it
+/// finishes. Mostly useful for checking that its limits held.
+///
+/// Only collected on Linux.
+#[derive(Debug, Default)]
+pub struct Stats {
+    peak_queued_bytes: AtomicUsize,
+}
+
+impl Stats {
+    /// The most memory that was held at once by directories waiting to be
+    /// processed, as counted against the queue memory limit.
+    pub fn peak_queued_bytes(&self) -> usize {
+        self.peak_queued_bytes.load(Ordering::Relaxed)
+    }
+
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    fn record_peak_queued_bytes(&self, bytes: usize) {
+        self.peak_queued_bytes.fetch_max(bytes, Ordering::Relaxed);
+    }
+}
+
+/// How many threads an operation runs on, how much of each directory it
+/// reads at once, how much memory its queue of pending work may hold, and
+/// the I/O priority of its threads.
+#[derive(Debug, Copy, Clone)]
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+struct Tuning {
+    threads: Option<NonZeroUsize>,
+    dir_buffer_size: Option<NonZeroUsize>,
+    auto: bool,
+    queue_memory_limit: Option<NonZeroUsize>,
+    io_priority: Option<IoPriority>,
+}
 
 trait IoErr<Out> {
     fn map_io_err<I: Into<Cow<'static, str>>>(self, f: impl FnOnce() -> I) -> Out;
@@ -31,12 +119,30 @@ mod linux {
     use std::{
         borrow::Cow,
         ffi::{CStr, CString, OsStr, OsString},
-        io,
-        os::unix::ffi::{OsStrExt, OsStringExt},
+        fs, io,
+        mem::MaybeUninit,
+        num::NonZeroUsize,
+        os::{
+            fd::AsFd,
+            unix::ffi::{OsStrExt, OsStringExt},
+        },
         path::{MAIN_SEPARATOR, Path, PathBuf},
+        sync::Arc,
+        thread,
     };
 
-    use crate::{Error, ops::IoErr};
+    use rustix::{
+        fs::{
+            AtFlags, CWD, FileType, Mode, NFS_SUPER_MAGIC, OFlags, RawDir, RawDirEntry, Statx,
+            StatxAttributes, StatxFlags, openat, statfs, statx,
+        },
+        ioctl,
+    };
+
+    use crate::{
+        Error,
+        ops::{EntryOrder, IoErr, IoPriority, Tuning},
+    };
 
     impl<T> IoErr<Result<T, Error>> for Result<T, rustix::io::Errno> {
         fn map_io_err<I: Into<Cow<'static, str>>>(
@@ -69,6 +175,392 @@ mod linux {
         Path::new(OsStr::from_bytes(path.as_bytes()))
             .join(Path::new(OsStr::from_bytes(name.to_bytes())))
     }
+
+    /// A tree node which only pays for reference counting once it has children
+    /// that need to point back at it.
+    pub enum Arcable<T> {
+        Raw(T),
+        Arced(Arc<T>),
+    }
+
+    impl<T> Arcable<T> {
+        pub fn into_inner(this: Self) -> Option<T> {
+            match this {
+                Self::Raw(t) => Some(t),
+                Self::Arced(arc) => Arc::into_inner(arc),
+            }
+        }
+
+        pub fn share(this: Self) -> (Self, Arc<T>) {
+            let arc = match this {
+                Self::Raw(t) => Arc::new(t),
+                Self::Arced(arc) => arc,
+            };
+            (Self::Arced(arc.clone()), arc)
+        }
+    }
+
+    impl<T> AsRef<T> for Arcable<T> {
+        fn as_ref(&self) -> &T {
+            match self {
+                Self::Raw(node) => node,
+                Self::Arced(arc) => arc,
+            }
+        }
+    }
+
+    #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+    pub struct DeviceId {
+        major: u32,
+        minor: u32,
+    }
+
+    impl From<&Statx> for DeviceId {
+        fn from(metadata: &Statx) -> Self {
+            Self {
+                major: metadata.stx_dev_major,
+                minor: metadata.stx_dev_minor,
+            }
+        }
+    }
+
+    /// Returns whether the named file lives on a different device than the
+    /// root or is the root of a mount (which catches bind mounts of the same
+    /// file system).
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "trace", skip(dir)))]
+    pub fn is_mount_point(
+        dir: impl AsFd,
+        file_name: &CStr,
+        flags: AtFlags,
+        root: DeviceId,
+    ) -> rustix::io::Result<bool> {
+        let metadata = statx(dir, file_name, flags, StatxFlags::empty())?;
+        Ok(DeviceId::from(&metadata) != root
+            || (metadata
+                .stx_attributes_mask
+                .contains(StatxAttributes::MOUNT_ROOT)
+                && metadata
+                    .stx_attributes
+                    .contains(StatxAttributes::MOUNT_ROOT)))
+    }
+
+    impl EntryOrder {
+        /// Decides on an order for the tree rooted at `path` if this one is
+        /// [`EntryOrder::Auto`].
+        #[cfg_attr(feature = "tracing", tracing::instrument(level = "trace"))]
+        pub(crate) fn resolve(self, path: &CStr) -> Self {
+            if self != Self::Auto {
+                return self;
+            }
+            match statx(CWD, path, AtFlags::empty(), StatxFlags::empty()) {
+                Ok(metadata) if is_rotational(DeviceId::from(&metadata)) => Self::Inode,
+                _ => Self::Unordered,
+            }
+        }
+    }
+
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    const NETWORK_FS_MAGICS: [u32; 6] = [
+        NFS_SUPER_MAGIC as u32,
+        0x6573_5546, // FUSE
+        0x517b,      // SMB
+        0xfe53_4d42, // SMB2
+        0xff53_4d42, // CIFS
+        0x00c3_6400, // Ceph
+    ];
+    const MEMORY_FS_MAGICS: [u32; 2] = [
+        0x0102_1994, // tmpfs
+        0x8584_58f6, // ramfs
+    ];
+
+    /// getdents needs room for at least one entry with a maximum length name.
+    const MIN_DIR_BUFFER_SIZE: usize = 4096;
+
+    impl Tuning {
+        /// Returns the number of threads and the directory buffer size to use
+        /// for trees on the file systems of `paths`.
+        #[cfg_attr(feature = "tracing", tracing::instrument(level = "trace"))]
+        pub fn resolve(self, paths: &[&CStr], default_buffer_size: usize) -> (usize, usize) {
+            let cpus = thread::available_parallelism().map_or(1, NonZeroUsize::get);
+            let (threads, buffer_size) = if self.auto {
+                let fs_types = paths
+                    .iter()
+                    .filter_map(|path| statfs(*path).ok())
+                    .map(|stats| {
+                        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+                        let fs_type = stats.f_type as u32;
+                        fs_type
+                    })
+                    .collect::<Vec<_>>();
+                if fs_types
+                    .iter()
+                    .any(|fs_type| NETWORK_FS_MAGICS.contains(fs_type))
+                {
+                    // Most of the time is spent waiting on the network, so keep plenty of
+                    // requests in flight and make each of them count.
+                    ((cpus * 4).max(16), default_buffer_size.max(1 << 20))
+                } else if paths.iter().any(|path| {
+                    statx(CWD, *path, AtFlags::empty(), StatxFlags::empty())
+                        .is_ok_and(|metadata| is_rotational(DeviceId::from(&metadata)))
+                }) {
+                    // Extra threads only make the disk seek between their requests.
+                    (cpus.min(2), default_buffer_size)
+                } else if !fs_types.is_empty()
+                    && fs_types
+                        .iter()
+                        .all(|fs_type| MEMORY_FS_MAGICS.contains(fs_type))
+                {
+                    (cpus, default_buffer_size.min(64 << 10))
+                } else {
+                    (cpus, default_buffer_size)
+                }
+            } else {
+                (cpus, default_buffer_size)
+            };
+            (
+                self.threads.map_or(threads, NonZeroUsize::get),
+                self.dir_buffer_size
+                    .map_or(buffer_size, NonZeroUsize::get)
+                    .max(MIN_DIR_BUFFER_SIZE),
+            )
+        }
+    }
+
+    /// Returns whether the device is a spinning disk, which is only known for
+    /// block devices and their partitions.
+    fn is_rotational(DeviceId { major, minor }: DeviceId) -> bool {
+        let device = format!("/sys/dev/block/{major}:{minor}");
+        [
+            format!("{device}/queue/rotational"),
+            format!("{device}/../queue/rotational"),
+        ]
+        .into_iter()
+        .find_map(|path| fs::read(path).ok())
+        .is_some_and(|rotational| rotational.trim_ascii() == b"1")
+    }
+
+    const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+    const IOPRIO_CLASS_SHIFT: u32 = 13;
+
+    impl IoPriority {
+        fn to_raw(self) -> libc::c_int {
+            let (class, level) = match self {
+                Self::RealTime(level) => (1, level),
+                Self::BestEffort(level) => (2, level),
+                Self::Idle => (3, 0),
+            };
+            // The bits above the level hold hints which we don't want to set by accident.
+            (class << IOPRIO_CLASS_SHIFT) | libc::c_int::from(level.min(7))
+        }
+    }
+
+    fn ioprio_set(raw: libc::c_int) -> io::Result<()> {
+        // Passing 0 as the process ID targets the calling thread.
+        if unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, raw) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn ioprio_get() -> io::Result<libc::c_int> {
+        let raw = unsafe { libc::syscall(libc::SYS_ioprio_get, IOPRIO_WHO_PROCESS, 0) };
+        if raw < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(libc::c_int::try_from(raw).unwrap_or_default())
+    }
+
+    /// Sets the I/O priority of the calling thread if one was requested.
+    pub fn set_io_priority(priority: Option<IoPriority>) -> Result<(), Error> {
+        let Some(priority) = priority else {
+            return Ok(());
+        };
+        ioprio_set(priority.to_raw())
+            .map_io_err(|| format!("Failed to set I/O priority: {priority:?}"))
+    }
+
+    /// Gives the calling thread an I/O priority for as long as the guard is
+    /// alive, since the thread belongs to the caller once the operation is
+    /// over.
+    pub struct IoPriorityGuard(Option<libc::c_int>);
+
+    impl IoPriorityGuard {
+        pub fn new(priority: Option<IoPriority>) -> Result<Self, Error> {
+            let Some(priority) = priority else {
+                return Ok(Self(None));
+            };
+            let previous = ioprio_get().map_io_err(|| "Failed to get I/O priority.")?;
+            set_io_priority(Some(priority))?;
+            Ok(Self(Some(previous)))
+        }
+    }
+
+    impl Drop for IoPriorityGuard {
+        fn drop(&mut self) {
+            if let Some(previous) = self.0 {
+                let _ = ioprio_set(previous);
+            }
+        }
+    }
+
+    /// A directory's entries, either in the order they're listed in or sorted
+    /// so that they're visited in roughly the order they live on disk.
+    pub enum DirEntries<'buf, Fd: AsFd> {
+        Raw(RawDir<'buf, Fd>),
+        Sorted {
+            entries: Vec<SortedEntry>,
+            next: usize,
+        },
+    }
+
+    pub struct SortedEntry {
+        name: CString,
+        file_type: FileType,
+        ino: u64,
+    }
+
+    pub enum DirEntry<'a> {
+        Raw(RawDirEntry<'a>),
+        Sorted(&'a SortedEntry),
+    }
+
+    impl DirEntry<'_> {
+        pub fn file_name(&self) -> &CStr {
+            match self {
+                Self::Raw(entry) => entry.file_name(),
+                Self::Sorted(entry) => &entry.name,
+            }
+        }
+
+        pub fn file_type(&self) -> FileType {
+            match self {
+                Self::Raw(entry) => entry.file_type(),
+                Self::Sorted(entry) => entry.file_type,
+            }
+        }
+
+        pub fn ino(&self) -> u64 {
+            match self {
+                Self::Raw(entry) => entry.ino(),
+                Self::Sorted(entry) => entry.ino,
+            }
+        }
+    }
+
+    impl<'buf, Fd: AsFd> DirEntries<'buf, Fd> {
+        /// Starts reading the directory, which means reading all of it up front
+        /// unless its entries are unordered.
+        pub fn new(
+            dir: Fd,
+            buf: &'buf mut [MaybeUninit<u8>],
+            order: EntryOrder,
+        ) -> rustix::io::Result<Self> {
+            if matches!(order, EntryOrder::Unordered | EntryOrder::Auto) {
+                return Ok(Self::Raw(RawDir::new(dir, buf)));
+            }
+
+            let mut entries = Vec::new();
+            let mut raw_dir = RawDir::new(&dir, buf);
+            while let Some(entry) = raw_dir.next() {
+                let entry = entry?;
+                entries.push(SortedEntry {
+                    name: entry.file_name().to_owned(),
+                    file_type: entry.file_type(),
+                    ino: entry.ino(),
+                });
+            }
+            entries.sort_unstable_by_key(|entry| entry.ino);
+            if order == EntryOrder::Physical {
+                // Looking up the extents in inode order keeps the inode reads sequential.
+                let offsets = entries
+                    .iter()
+                    .map(|entry| {
+                        if entry.file_type == FileType::RegularFile {
+                            first_physical_offset(&dir, &entry.name)
+                        } else {
+                            0
+                        }
+                    })
+                    .collect::<Vec<_>>();
+                let mut keyed = offsets.into_iter().zip(entries).collect::<Vec<_>>();
+                keyed.sort_by_key(|&(offset, _)| offset);
+                entries = keyed.into_iter().map(|(_, entry)| entry).collect();
+            }
+            Ok(Self::Sorted { entries, next: 0 })
+        }
+
+        #[allow(clippy::should_implement_trait)]
+        pub fn next(&mut self) -> Option<rustix::io::Result<DirEntry<'_>>> {
+            match self {
+                Self::Raw(raw_dir) => raw_dir.next().map(|entry| entry.map(DirEntry::Raw)),
+                Self::Sorted { entries, next } => {
+                    let entry = entries.get(*next)?;
+                    *next += 1;
+                    Some(Ok(DirEntry::Sorted(entry)))
+                }
+            }
+        }
+    }
+
+    #[repr(C)]
+    struct FiemapHeader {
+        start: u64,
+        length: u64,
+        flags: u32,
+        mapped_extents: u32,
+        extent_count: u32,
+        reserved: u32,
+    }
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct FiemapExtent {
+        logical: u64,
+        physical: u64,
+        length: u64,
+        reserved64: [u64; 2],
+        flags: u32,
+        reserved: [u32; 3],
+    }
+
+    #[repr(C)]
+    struct Fiemap {
+        header: FiemapHeader,
+        extents: [FiemapExtent; 1],
+    }
+
+    const FS_IOC_FIEMAP: ioctl::Opcode = ioctl::opcode::read_write::<FiemapHeader>(b'f', 11);
+
+    /// Returns where the file's data starts on disk, or zero if that can't be
+    /// determined (e.g. for empty files or file systems without `FIEMAP`).
+    fn first_physical_offset(dir: impl AsFd, name: &CStr) -> u64 {
+        let Ok(file) = openat(
+            dir,
+            name,
+            OFlags::RDONLY | OFlags::NOFOLLOW | OFlags::NONBLOCK | OFlags::CLOEXEC,
+            Mode::empty(),
+        ) else {
+            return 0;
+        };
+        let mut fiemap = Fiemap {
+            header: FiemapHeader {
+                start: 0,
+                length: u64::MAX,
+                flags: 0,
+                mapped_extents: 0,
+                extent_count: 1,
+                reserved: 0,
+            },
+            extents: [FiemapExtent::default()],
+        };
+        let result =
+            unsafe { ioctl::ioctl(&file, ioctl::Updater::<FS_IOC_FIEMAP, _>::new(&mut fiemap)) };
+        if result.is_ok() && fiemap.header.mapped_extents > 0 {
+            fiemap.extents[0].physical
+        } else {
+            0
+        }
+    }
 }
 
 mod compat {
@@ -77,6 +569,10 @@ mod compat {
     pub trait DirectoryOp<T> {
         fn run(&self, dir: T) -> Result<(), Error>;
 
+        /// Tells the operation that something outside of it failed, so its
+        /// work can't be considered complete.
+        fn fail(&self) {}
+
         fn finish(self) -> Result<(), Error>;
     }
 }
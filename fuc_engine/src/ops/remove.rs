@@ -4,14 +4,18 @@ use std::{
     fmt::Debug,
     fs, io,
     marker::PhantomData,
+    num::{NonZeroU64, NonZeroUsize},
     path::{MAIN_SEPARATOR_STR, Path},
+    sync::Arc,
 };
 
 use bon::Builder;
 
 use crate::{
     Error,
-    ops::{IoErr, compat::DirectoryOp},
+    ops::{
+        EntryOrder, IoErr, IoPriority, Stats, Tuning, compat::DirectoryOp, throttle::RateLimiter,
+    },
 };
 
 /// Removes a file or directory at this path, after removing all its contents.
@@ -36,6 +40,45 @@ pub struct RemoveOp<'a, I: Into<Cow<'a, Path>> + 'a, F: IntoIterator<Item = I>>
     force: bool,
     #[builder(default = true)]
     preserve_root: bool,
+    #[builder(default = false)]
+    one_file_system: bool,
+    #[builder(default = false)]
+    force_permissions: bool,
+    /// Along with `force_permissions`, also clear the immutable and
+    /// append-only attributes of entries which can't be removed otherwise.
+    /// Requires root or `CAP_LINUX_IMMUTABLE`. Only applied on Linux.
+    #[builder(default = false)]
+    clear_immutable: bool,
+    #[builder(default)]
+    entry_order: EntryOrder,
+    /// The number of threads to delete with, defaulting to the number of
+    /// CPUs. Only applied on Linux.
+    threads: Option<NonZeroUsize>,
+    /// How much of a directory to read at once, defaulting to 16 MiB. Only
+    /// applied on Linux.
+    dir_buffer_size: Option<NonZeroUsize>,
+    /// Pick the thread count and buffer size from the file system and disk
+    /// type of the first directory deleted. Explicit values take precedence.
+    #[builder(default = false)]
+    auto_tune: bool,
+    /// Roughly how many bytes of directories waiting to be processed may be
+    /// queued. Past this point, workers delete the directories they find
+    /// right away instead of queueing them, down to a fixed depth with a
+    /// directory buffer per level. Deeper directories and the directories
+    /// passed in are always queued. Unlimited by default. Only applied on
+    /// Linux.
+    queue_memory_limit: Option<NonZeroUsize>,
+    /// The most files and directories to unlink per second, shared by all
+    /// threads. Only applied on Linux.
+    rate_limit: Option<NonZeroU64>,
+    /// Only applied on Linux.
+    io_priority: Option<IoPriority>,
+    /// Unlink files in batches through `io_uring` when the kernel supports
+    /// it. Only applied on Linux with the `io_uring` feature enabled.
+    #[builder(default = true)]
+    io_uring: bool,
+    /// Filled in with what the removal observed once it finishes.
+    stats: Option<Arc<Stats>>,
     #[builder(skip)]
     _marker: PhantomData<&'a I>,
 }
@@ -47,12 +90,53 @@ impl<'a, I: Into<Cow<'a, Path>>, F: IntoIterator<Item = I>> RemoveOp<'a, I, F> {
     ///
     /// Returns the underlying I/O errors that occurred.
     pub fn run(self) -> Result<(), Error> {
-        let remove = compat::remove_impl();
-        let result = schedule_deletions(self, &remove);
+        #[cfg(target_os = "linux")]
+        let _io_priority = crate::ops::IoPriorityGuard::new(self.io_priority)?;
+        let throttle = self
+            .rate_limit
+            .map(|limit| Arc::new(RateLimiter::new(limit)));
+        let force_permissions = match (self.force_permissions, self.clear_immutable) {
+            (false, _) => ForcePermissions::No,
+            (true, false) => ForcePermissions::Mode,
+            (true, true) => ForcePermissions::ModeAndAttributes,
+        };
+        let remove = compat::remove_impl(
+            self.one_file_system,
+            force_permissions,
+            self.io_uring,
+            self.entry_order,
+            Tuning {
+                threads: self.threads,
+                dir_buffer_size: self.dir_buffer_size,
+                auto: self.auto_tune,
+                queue_memory_limit: self.queue_memory_limit,
+                io_priority: self.io_priority,
+            },
+            throttle.clone(),
+            self.stats.clone(),
+        );
+        let result = schedule_deletions(self, &remove, throttle.as_deref());
         remove.finish().and(result)
     }
 }
 
+/// How far to go to remove entries we aren't allowed to modify.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum ForcePermissions {
+    No,
+    /// Give the owner access to directories which can't be read or modified.
+    Mode,
+    /// Also clear the immutable and append-only attributes.
+    ModeAndAttributes,
+}
+
+impl ForcePermissions {
+    #[cfg_attr(target_os = "windows", allow(dead_code))]
+    const fn enabled(self) -> bool {
+        !matches!(self, Self::No)
+    }
+}
+
 #[cfg_attr(
     feature = "tracing",
     tracing::instrument(level = "trace", skip(files, remove))
@@ -62,9 +146,22 @@ fn schedule_deletions<'a, I: Into<Cow<'a, Path>>, F: IntoIterator<Item = I>>(
         files,
         force,
         preserve_root,
+        one_file_system: _,
+        force_permissions: _,
+        clear_immutable: _,
+        entry_order: _,
+        threads: _,
+        dir_buffer_size: _,
+        auto_tune: _,
+        queue_memory_limit: _,
+        rate_limit: _,
+        io_priority: _,
+        io_uring: _,
+        stats: _,
         _marker: _,
     }: RemoveOp<'a, I, F>,
     remove: &impl DirectoryOp<Cow<'a, Path>>,
+    throttle: Option<&RateLimiter>,
 ) -> Result<(), Error> {
     for file in files {
         let file = file.into();
@@ -105,31 +202,76 @@ fn schedule_deletions<'a, I: Into<Cow<'a, Path>>, F: IntoIterator<Item = I>>(
                 },
             )?;
         } else {
-            fs::remove_file(stripped_path)
-                .map_io_err(|| format!("Failed to delete file: {stripped_path:?}"))?;
+            if let Some(throttle) = throttle {
+                throttle.acquire(1);
+            }
+            match fs::remove_file(stripped_path) {
+                Err(e)
+                    if e.kind() == io::ErrorKind::ResourceBusy && is_mount_root(stripped_path) =>
+                {
+                    return Err(Error::MountPoint {
+                        file: stripped_path.to_path_buf(),
+                    });
+                }
+                r => r.map_io_err(|| format!("Failed to delete file: {stripped_path:?}"))?,
+            }
         }
     }
     Ok(())
 }
 
+/// Returns whether something is mounted on the path, as opposed to it being
+/// busy for some other reason.
+#[cfg(unix)]
+fn is_mount_root(path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    #[cfg(target_os = "linux")]
+    {
+        use rustix::fs::{AtFlags, CWD, StatxAttributes, StatxFlags, statx};
+
+        if let Ok(metadata) = statx(CWD, path, AtFlags::SYMLINK_NOFOLLOW, StatxFlags::empty())
+            && metadata
+                .stx_attributes_mask
+                .contains(StatxAttributes::MOUNT_ROOT)
+        {
+            return metadata
+                .stx_attributes
+                .contains(StatxAttributes::MOUNT_ROOT);
+        }
+    }
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    matches!(
+        (path.symlink_metadata(), parent.metadata()),
+        (Ok(metadata), Ok(parent)) if metadata.dev() != parent.dev()
+    )
+}
+
+#[cfg(not(unix))]
+const fn is_mount_root(_: &Path) -> bool {
+    false
+}
+
 #[cfg(target_os = "linux")]
 mod compat {
     use std::{
         borrow::Cow,
-        cell::LazyCell,
+        cell::OnceCell,
         env,
         env::{current_dir, set_current_dir},
         ffi::{CStr, CString, OsStr},
         fmt::{Debug, Formatter},
-        fs,
+        fs, mem,
         mem::MaybeUninit,
-        num::NonZeroUsize,
         ops::{Deref, DerefMut},
         os::{
             fd::{AsFd, OwnedFd},
             unix::ffi::OsStrExt,
         },
-        path::{Path, PathBuf},
+        path::{MAIN_SEPARATOR, Path, PathBuf},
         ptr,
         ptr::NonNull,
         slice,
@@ -138,58 +280,164 @@ mod compat {
         thread::JoinHandle,
     };
 
-    use crossbeam_channel::{Receiver, Sender};
     use rustix::{
-        fs::{AtFlags, CWD, FileType, Mode, OFlags, RawDir, openat, unlinkat},
+        fs::{
+            AtFlags, CWD, FileType, IFlags, Mode, OFlags, StatxFlags, chmodat, fchmod, fstat,
+            ioctl_getflags, ioctl_setflags, openat, statat, statx, unlinkat,
+        },
         io::Errno,
         mm::{MapFlags, ProtFlags, mmap_anonymous, munmap},
-        thread::{UnshareFlags, unshare_unsafe},
+        thread::{CapabilitySet, UnshareFlags, capabilities, unshare_unsafe},
     };
 
+    use super::{ForcePermissions, is_mount_root};
     use crate::{
         Error,
-        ops::{IoErr, compat::DirectoryOp, concat_cstrs, join_cstr_paths, path_buf_to_cstring},
+        ops::{
+            Arcable, DeviceId, DirEntries, EntryOrder, IoErr, IoPriority, Stats, Tuning,
+            compat::DirectoryOp,
+            is_mount_point, join_cstr_paths, path_buf_to_cstring,
+            scheduler::{LocalQueue, MAX_DESCENT, Queued, Scheduler},
+            set_io_priority,
+            throttle::RateLimiter,
+        },
     };
 
-    struct Impl<LF: FnOnce() -> (Sender<TreeNode>, JoinHandle<Result<(), Error>>)> {
-        scheduling: LazyCell<(Sender<TreeNode>, JoinHandle<Result<(), Error>>), LF>,
+    const DEFAULT_DIR_BUFFER_SIZE: usize = 16 * (1 << 20);
+
+    struct Impl {
+        scheduling: OnceCell<JoinHandle<Result<(), Error>>>,
+        scheduler: Arc<Scheduler<Task>>,
+        one_file_system: bool,
+        force_permissions: ForcePermissions,
+        io_uring: bool,
+        entry_order: EntryOrder,
+        tuning: Tuning,
+        throttle: Option<Arc<RateLimiter>>,
+        stats: Option<Arc<Stats>>,
     }
 
-    pub fn remove_impl<'a>() -> impl DirectoryOp<Cow<'a, Path>> {
-        let scheduling = LazyCell::new(|| {
-            let (tx, rx) = crossbeam_channel::unbounded();
-            (tx, thread::spawn(|| root_worker_thread(rx)))
-        });
+    pub fn remove_impl<'a>(
+        one_file_system: bool,
+        force_permissions: ForcePermissions,
+        io_uring: bool,
+        entry_order: EntryOrder,
+        tuning: Tuning,
+        throttle: Option<Arc<RateLimiter>>,
+        stats: Option<Arc<Stats>>,
+    ) -> impl DirectoryOp<Cow<'a, Path>> {
+        Impl {
+            scheduling: OnceCell::new(),
+            scheduler: Arc::new(Scheduler::new(tuning.queue_memory_limit)),
+            one_file_system,
+            force_permissions: if force_permissions == ForcePermissions::ModeAndAttributes
+                && !can_change_attributes()
+            {
+                ForcePermissions::Mode
+            } else {
+                force_permissions
+            },
+            io_uring,
+            tuning,
+            throttle,
+            stats,
+            // Unlinking only touches inodes, so there's nothing to gain from reading
+            // the file extents.
+            entry_order: if entry_order == EntryOrder::Physical {
+                EntryOrder::Inode
+            } else {
+                entry_order
+            },
+        }
+    }
 
-        Impl { scheduling }
+    impl Impl {
+        /// Starts the workers, sized for the first directory being deleted.
+        fn start(&self, path: &CStr) -> JoinHandle<Result<(), Error>> {
+            let Self {
+                scheduling: _,
+                ref scheduler,
+                one_file_system: _,
+                force_permissions,
+                io_uring,
+                entry_order: _,
+                tuning,
+                ref throttle,
+                stats: _,
+            } = *self;
+            let (threads, buffer_size) = tuning.resolve(&[path], DEFAULT_DIR_BUFFER_SIZE);
+            let scheduler = scheduler.clone();
+            let throttle = throttle.clone();
+
+            thread::spawn(move || {
+                root_worker_thread(
+                    &scheduler,
+                    force_permissions,
+                    io_uring,
+                    threads,
+                    buffer_size,
+                    tuning.io_priority,
+                    throttle.as_deref(),
+                )
+            })
+        }
     }
 
-    impl<LF: FnOnce() -> (Sender<TreeNode>, JoinHandle<Result<(), Error>>)>
-        DirectoryOp<Cow<'_, Path>> for Impl<LF>
-    {
+    impl DirectoryOp<Cow<'_, Path>> for Impl {
         #[cfg_attr(feature = "tracing", tracing::instrument(level = "trace", skip(self)))]
         fn run(&self, dir: Cow<Path>) -> Result<(), Error> {
-            let Self { ref scheduling } = *self;
-
-            let (tasks, _) = &**scheduling;
-            tasks
-                .send(TreeNode {
-                    path: path_buf_to_cstring(dir.into_owned())?,
-                    parent: None,
-                    messages: tasks.clone(),
-                })
-                .map_err(|_| Error::Internal)
+            let Self {
+                ref scheduling,
+                ref scheduler,
+                one_file_system,
+                force_permissions: _,
+                io_uring: _,
+                entry_order,
+                tuning: _,
+                throttle: _,
+                stats: _,
+            } = *self;
+
+            let path = path_buf_to_cstring(dir.into_owned())?;
+            let root_dev = if one_file_system {
+                let metadata = statx(CWD, &path, AtFlags::SYMLINK_NOFOLLOW, StatxFlags::empty())
+                    .map_io_err(|| format!("Failed to stat directory: {path:?}"))?;
+                Some(DeviceId::from(&metadata))
+            } else {
+                None
+            };
+            let entry_order = entry_order.resolve(&path);
+
+            scheduling.get_or_init(|| self.start(&path));
+            scheduler.inject(Task::Dir(TreeNode {
+                name: path,
+                parent: None,
+                root_dev,
+                entry_order,
+            }));
+            Ok(())
         }
 
         #[cfg_attr(feature = "tracing", tracing::instrument(level = "trace", skip(self)))]
         fn finish(self) -> Result<(), Error> {
-            let Self { scheduling } = self;
-
-            if let Ok((tasks, thread)) = LazyCell::into_inner(scheduling) {
-                drop(tasks);
-                thread.join().map_err(|_| Error::Join)??;
+            let Self {
+                scheduling,
+                scheduler,
+                one_file_system: _,
+                force_permissions: _,
+                io_uring: _,
+                entry_order: _,
+                tuning: _,
+                throttle: _,
+                stats,
+            } = self;
+
+            scheduler.close();
+            let result = scheduling.into_inner().map_or(Ok(Ok(())), JoinHandle::join);
+            if let Some(stats) = stats {
+                stats.record_peak_queued_bytes(scheduler.peak_queued_bytes());
             }
-            Ok(())
+            result.map_err(|_| Error::Join)?
         }
     }
 
@@ -200,8 +448,7 @@ mod compat {
     }
 
     impl DirBuf {
-        pub fn new() -> rustix::io::Result<Self> {
-            let len = 16 * (1 << 20);
+        pub fn new(len: usize) -> rustix::io::Result<Self> {
             Ok(Self {
                 ptr: unsafe {
                     NonNull::new_unchecked(mmap_anonymous(
@@ -245,21 +492,35 @@ mod compat {
         Ok(())
     }
 
-    #[cfg_attr(feature = "tracing", tracing::instrument(level = "trace", skip(tasks)))]
-    fn root_worker_thread(tasks: Receiver<TreeNode>) -> Result<(), Error> {
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip(scheduler))
+    )]
+    fn root_worker_thread(
+        scheduler: &Scheduler<Task>,
+        force_permissions: ForcePermissions,
+        io_uring: bool,
+        threads: usize,
+        buffer_size: usize,
+        io_priority: Option<IoPriority>,
+        throttle: Option<&RateLimiter>,
+    ) -> Result<(), Error> {
         unshare_io()?;
+        set_io_priority(io_priority)?;
 
-        let mut available_parallelism =
-            thread::available_parallelism().map_or(1, NonZeroUsize::get) - 1;
+        let mut available_parallelism = threads - 1;
 
         thread::scope(|scope| {
             let mut threads = Vec::with_capacity(available_parallelism);
 
             {
-                let mut buf = DirBuf::new().map_io_err(|| "Failed to allocate dir buf.")?;
-                for message in &tasks {
+                let queue = scheduler.local_queue();
+                let mut buf =
+                    DirBuf::new(buffer_size).map_io_err(|| "Failed to allocate dir buf.")?;
+                let mut descent_bufs: [Option<DirBuf>; MAX_DESCENT] = Default::default();
+                while let Some((message, _finished)) = queue.next() {
                     let mut maybe_spawn = || {
-                        if available_parallelism > 0 && !tasks.is_empty() {
+                        if available_parallelism > 0 && queue.has_work() {
                             #[cfg(feature = "tracing")]
                             tracing::event!(
                                 tracing::Level::TRACE,
@@ -268,15 +529,46 @@ mod compat {
                             );
 
                             available_parallelism -= 1;
-                            threads.push(scope.spawn({
-                                let tasks = tasks.clone();
-                                || worker_thread(tasks)
+                            threads.push(scope.spawn(move || {
+                                worker_thread(
+                                    scheduler,
+                                    force_permissions,
+                                    io_uring,
+                                    buffer_size,
+                                    io_priority,
+                                    throttle,
+                                )
                             }));
                         }
                     };
                     maybe_spawn();
 
-                    delete_dir(message, &mut buf, maybe_spawn)?;
+                    match message {
+                        Task::Dir(node) => {
+                            delete_dir(
+                                node,
+                                &mut buf,
+                                &mut descent_bufs,
+                                force_permissions,
+                                io_uring,
+                                &queue,
+                                throttle,
+                                &mut maybe_spawn,
+                            )?;
+                        }
+                        Task::Files(batch) => {
+                            delete_files(
+                                batch,
+                                &mut buf,
+                                &mut descent_bufs,
+                                force_permissions,
+                                io_uring,
+                                &queue,
+                                throttle,
+                                &mut maybe_spawn,
+                            )?;
+                        }
+                    }
                 }
             }
 
@@ -287,84 +579,192 @@ mod compat {
         })
     }
 
-    #[cfg_attr(feature = "tracing", tracing::instrument(level = "trace", skip(tasks)))]
-    fn worker_thread(tasks: Receiver<TreeNode>) -> Result<(), Error> {
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip(scheduler))
+    )]
+    fn worker_thread(
+        scheduler: &Scheduler<Task>,
+        force_permissions: ForcePermissions,
+        io_uring: bool,
+        buffer_size: usize,
+        io_priority: Option<IoPriority>,
+        throttle: Option<&RateLimiter>,
+    ) -> Result<(), Error> {
         unshare_io()?;
-
-        let mut buf = DirBuf::new().map_io_err(|| "Failed to allocate dir buf.")?;
-        for message in tasks {
-            delete_dir(message, &mut buf, || {})?;
+        set_io_priority(io_priority)?;
+
+        let queue = scheduler.local_queue();
+        let mut buf = DirBuf::new(buffer_size).map_io_err(|| "Failed to allocate dir buf.")?;
+        let mut descent_bufs: [Option<DirBuf>; MAX_DESCENT] = Default::default();
+        while let Some((message, _finished)) = queue.next() {
+            match message {
+                Task::Dir(node) => {
+                    delete_dir(
+                        node,
+                        &mut buf,
+                        &mut descent_bufs,
+                        force_permissions,
+                        io_uring,
+                        &queue,
+                        throttle,
+                        &mut || {},
+                    )?;
+                }
+                Task::Files(batch) => {
+                    delete_files(
+                        batch,
+                        &mut buf,
+                        &mut descent_bufs,
+                        force_permissions,
+                        io_uring,
+                        &queue,
+                        throttle,
+                        &mut || {},
+                    )?;
+                }
+            }
         }
         Ok(())
     }
 
+    #[allow(clippy::too_many_arguments)]
     #[cfg_attr(
         feature = "tracing",
-        tracing::instrument(level = "info", skip(buf, maybe_spawn))
+        tracing::instrument(level = "info", skip(buf, descent_bufs, queue, maybe_spawn))
     )]
     fn delete_dir(
-        mut node: TreeNode,
+        node: TreeNode,
         buf: &mut [MaybeUninit<u8>],
-        mut maybe_spawn: impl FnMut(),
+        descent_bufs: &mut [Option<DirBuf>],
+        force_permissions: ForcePermissions,
+        io_uring: bool,
+        queue: &LocalQueue<Task>,
+        throttle: Option<&RateLimiter>,
+        maybe_spawn: &mut dyn FnMut(),
     ) -> Result<(), Error> {
+        let mut next = Some(node);
         // This retry loop is pretty cursed. All popular Linux file systems handle
         // getdents64 and unlink interleavings correctly, but it's technically not POSIX
         // compliant and thus can fail. We catch the failures by hanlding directory
         // NOTEMPTY errors.
-        loop {
-            let dir = openat(
-                CWD,
-                &node.path,
-                OFlags::RDONLY | OFlags::DIRECTORY | OFlags::NOFOLLOW,
-                Mode::empty(),
-            )
-            .map_io_err(|| format!("Failed to open directory: {:?}", node.path))?;
-            let node_ = delete_dir_contents(node, dir, buf, &mut maybe_spawn)?;
-            match delete_empty_dir_chain(node_)? {
-                UnlinkDirOutcome::Ok => return Ok(()),
-                UnlinkDirOutcome::DirNotEmpty(node_) => node = node_,
+        while let Some(node) = next {
+            let path = node.path();
+            let dir = open_dir(&path, force_permissions)?;
+            let node = delete_dir_contents(
+                node,
+                &path,
+                dir,
+                buf,
+                descent_bufs,
+                force_permissions,
+                io_uring,
+                queue,
+                throttle,
+                maybe_spawn,
+            )?;
+            next = match delete_empty_dir_chain(node, force_permissions, throttle)? {
+                UnlinkDirOutcome::Ok => None,
+                UnlinkDirOutcome::DirNotEmpty(node) => Some(node),
+            };
+        }
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "info", skip(buf, descent_bufs, queue, maybe_spawn))
+    )]
+    fn delete_files(
+        FileBatch { dir, files }: FileBatch,
+        buf: &mut [MaybeUninit<u8>],
+        descent_bufs: &mut [Option<DirBuf>],
+        force_permissions: ForcePermissions,
+        io_uring: bool,
+        queue: &LocalQueue<Task>,
+        throttle: Option<&RateLimiter>,
+        maybe_spawn: &mut dyn FnMut(),
+    ) -> Result<(), Error> {
+        {
+            // File descriptors can't be shared with the thread that read the directory
+            // because every worker has its own FD table.
+            let path = dir.path();
+            let fd = open_dir(&path, force_permissions)?;
+            let mut deleter = FileDeleter::new(throttle, io_uring);
+            for file in files {
+                deleter.delete_known(&fd, &path, file, force_permissions)?;
             }
+            #[cfg(feature = "io_uring")]
+            deleter.flush(&fd, &path, force_permissions)?;
         }
+        match delete_empty_dir_chain(Arc::into_inner(dir), force_permissions, throttle)? {
+            UnlinkDirOutcome::Ok => Ok(()),
+            UnlinkDirOutcome::DirNotEmpty(node) => delete_dir(
+                node,
+                buf,
+                descent_bufs,
+                force_permissions,
+                io_uring,
+                queue,
+                throttle,
+                maybe_spawn,
+            ),
+        }
+    }
+
+    fn open_dir(path: &CString, force_permissions: ForcePermissions) -> Result<OwnedFd, Error> {
+        with_permissions_fallback(
+            force_permissions.enabled(),
+            || {
+                openat(
+                    CWD,
+                    path,
+                    OFlags::RDONLY | OFlags::DIRECTORY | OFlags::NOFOLLOW,
+                    Mode::empty(),
+                )
+            },
+            || {
+                let mode =
+                    Mode::from_raw_mode(statat(CWD, path, AtFlags::SYMLINK_NOFOLLOW)?.st_mode);
+                chmodat(CWD, path, mode | Mode::RUSR | Mode::XUSR, AtFlags::empty())
+            },
+        )
+        .map_io_err(|| format!("Failed to open directory: {path:?}"))
     }
 
+    /// Directories with more files than this have the rest of their files
+    /// deleted in batches which other workers can pick up.
+    const FILE_BATCH_THRESHOLD: usize = 1024;
+    const FILE_BATCH_SIZE: usize = 1024;
+
+    #[allow(clippy::too_many_arguments)]
     #[cfg_attr(
         feature = "tracing",
-        tracing::instrument(level = "trace", skip(dir, buf, maybe_spawn))
+        tracing::instrument(level = "trace", skip(dir, buf, descent_bufs, queue, maybe_spawn))
     )]
     fn delete_dir_contents(
         node: TreeNode,
+        path: &CString,
         dir: OwnedFd,
         buf: &mut [MaybeUninit<u8>],
-        mut maybe_spawn: impl FnMut(),
+        descent_bufs: &mut [Option<DirBuf>],
+        force_permissions: ForcePermissions,
+        io_uring: bool,
+        queue: &LocalQueue<Task>,
+        throttle: Option<&RateLimiter>,
+        maybe_spawn: &mut dyn FnMut(),
     ) -> Result<Option<TreeNode>, Error> {
-        enum Arcable<T> {
-            Raw(T),
-            Arced(Arc<T>),
-        }
-
-        impl<T> Arcable<T> {
-            fn into_inner(this: Self) -> Option<T> {
-                match this {
-                    Self::Raw(t) => Some(t),
-                    Self::Arced(arc) => Arc::into_inner(arc),
-                }
-            }
-        }
-
-        impl<T> AsRef<T> for Arcable<T> {
-            fn as_ref(&self) -> &T {
-                match self {
-                    Self::Raw(node) => node,
-                    Self::Arced(arc) => arc,
-                }
-            }
-        }
-
         let mut node = Arcable::Raw(node);
-        let mut raw_dir = RawDir::new(&dir, buf);
-        while let Some(file) = raw_dir.next() {
-            let file =
-                file.map_io_err(|| format!("Failed to read directory: {:?}", node.as_ref().path))?;
+        let mut mount_point = None;
+        let mut deleter = FileDeleter::new(throttle, io_uring);
+        let mut files = 0;
+        let mut batch = Vec::new();
+        let buf_len = buf.len();
+        let mut dir_entries = DirEntries::new(&dir, buf, node.as_ref().entry_order)
+            .map_io_err(|| format!("Failed to read directory: {path:?}"))?;
+        while let Some(file) = dir_entries.next() {
+            let file = file.map_io_err(|| format!("Failed to read directory: {path:?}"))?;
             {
                 let name = file.file_name();
                 if name == c"." || name == c".." {
@@ -372,49 +772,102 @@ mod compat {
                 }
             }
 
-            if file.file_type() != FileType::Directory {
-                let file = file.file_name();
-                match delete_file(&dir, file) {
-                    Ok(()) => continue,
-                    Err(Errno::ISDIR) => (),
-                    Err(error) => {
-                        return Err(Error::Io {
-                            error: error.into(),
-                            context: format!(
-                                "Failed to delete file: {:?}",
-                                join_cstr_paths(&node.as_ref().path, file)
-                            )
-                            .into(),
-                        });
+            let file_type = file.file_type();
+            if file_type != FileType::Directory && file_type != FileType::Unknown {
+                if files < FILE_BATCH_THRESHOLD {
+                    files += 1;
+                } else {
+                    batch.push(file.file_name().to_owned());
+                    if batch.len() < FILE_BATCH_SIZE {
+                        continue;
                     }
+                    maybe_spawn();
+
+                    let parent;
+                    (node, parent) = Arcable::share(node);
+                    if let Err(Task::Files(FileBatch { dir: _, files })) =
+                        queue.try_push(Task::Files(FileBatch {
+                            dir: parent,
+                            files: mem::replace(&mut batch, Vec::with_capacity(FILE_BATCH_SIZE)),
+                        }))
+                    {
+                        // The queue is full, so delete the files ourselves.
+                        for file in files {
+                            deleter.delete_known(&dir, path, file, force_permissions)?;
+                        }
+                    }
+                    continue;
                 }
             }
+            if file_type != FileType::Directory
+                && deleter.delete(&dir, path, file.file_name(), file_type, force_permissions)?
+            {
+                continue;
+            }
 
-            if node.as_ref().path.as_bytes_with_nul().len() + file.file_name().count_bytes() >= 4096
+            if path.as_bytes_with_nul().len() + file.file_name().count_bytes() >= 4096 {
+                long_path_fallback_deletion(path, file.file_name())?;
+                continue;
+            }
+
+            if let Some(root_dev) = node.as_ref().root_dev
+                && is_mount_point(&dir, file.file_name(), AtFlags::SYMLINK_NOFOLLOW, root_dev)
+                    .map_io_err(|| {
+                        format!(
+                            "Failed to stat directory: {:?}",
+                            join_cstr_paths(path, file.file_name())
+                        )
+                    })?
             {
-                long_path_fallback_deletion(&node.as_ref().path, file.file_name())?;
+                // Keep deleting everything else so we fail with as little left over as
+                // possible.
+                mount_point.get_or_insert_with(|| join_cstr_paths(path, file.file_name()));
                 continue;
             }
 
+            let parent;
+            (node, parent) = Arcable::share(node);
+            let child = TreeNode {
+                name: file.file_name().to_owned(),
+                root_dev: parent.root_dev,
+                entry_order: parent.entry_order,
+                parent: Some(parent),
+            };
             maybe_spawn();
-
-            let node = match node {
-                Arcable::Raw(raw) => {
-                    let arc = Arc::new(raw);
-                    node = Arcable::Arced(arc.clone());
-                    arc
+            if let Err(Task::Dir(child)) = queue.try_push(Task::Dir(child)) {
+                // The queue is full, so delete the directory now instead of holding on
+                // to it. Only the directories on the way down stay open.
+                if let Some((buf, descent_bufs)) = descent_bufs.split_first_mut() {
+                    let buf = match buf {
+                        Some(buf) => buf,
+                        None => buf.insert(
+                            DirBuf::new(buf_len).map_io_err(|| "Failed to allocate dir buf.")?,
+                        ),
+                    };
+                    delete_dir(
+                        child,
+                        buf,
+                        descent_bufs,
+                        force_permissions,
+                        io_uring,
+                        queue,
+                        throttle,
+                        maybe_spawn,
+                    )?;
+                } else {
+                    queue.inject(Task::Dir(child));
                 }
-                Arcable::Arced(ref node) => node.clone(),
-            };
-            node.messages
-                .send(TreeNode {
-                    path: concat_cstrs(&node.path, file.file_name()),
-                    parent: Some(node.clone()),
-                    messages: node.messages.clone(),
-                })
-                .map_err(|_| Error::Internal)?;
+            }
         }
 
+        for file in batch {
+            deleter.delete_known(&dir, path, file, force_permissions)?;
+        }
+        #[cfg(feature = "io_uring")]
+        deleter.flush(&dir, path, force_permissions)?;
+        if let Some(file) = mount_point {
+            return Err(Error::MountPoint { file });
+        }
         Ok(Arcable::into_inner(node))
     }
 
@@ -424,19 +877,46 @@ mod compat {
     }
 
     #[cfg_attr(feature = "tracing", tracing::instrument(level = "trace"))]
-    fn delete_empty_dir_chain(mut node: Option<TreeNode>) -> Result<UnlinkDirOutcome, Error> {
+    fn delete_empty_dir_chain(
+        mut node: Option<TreeNode>,
+        force_permissions: ForcePermissions,
+        throttle: Option<&RateLimiter>,
+    ) -> Result<UnlinkDirOutcome, Error> {
         let mut result = Ok(());
         while let Some(node_) = node {
             if result.is_ok() {
+                if let Some(throttle) = throttle {
+                    throttle.acquire(1);
+                }
+                let path = node_.path();
                 // We don't use ? here and also don't break out of the loop so that we continue
                 // to drain the linked list without overflowing the drop stack
-                match unlinkat(CWD, &node_.path, AtFlags::REMOVEDIR) {
+                match with_permissions_fallback(
+                    force_permissions.enabled() && node_.parent.is_some(),
+                    || unlinkat(CWD, &path, AtFlags::REMOVEDIR),
+                    || {
+                        let parent = node_.parent.as_ref().ok_or(Errno::INVAL)?;
+                        let parent = openat(
+                            CWD,
+                            parent.path(),
+                            OFlags::RDONLY | OFlags::DIRECTORY | OFlags::NOFOLLOW,
+                            Mode::empty(),
+                        )?;
+                        unlock_dir(parent, None, force_permissions)
+                    },
+                ) {
                     Err(Errno::NOTEMPTY) => {
                         return Ok(UnlinkDirOutcome::DirNotEmpty(node_));
                     }
+                    Err(Errno::BUSY)
+                        if is_mount_root(Path::new(OsStr::from_bytes(path.as_bytes()))) =>
+                    {
+                        result = Err(Error::MountPoint {
+                            file: PathBuf::from(OsStr::from_bytes(path.as_bytes())),
+                        });
+                    }
                     r => {
-                        result = r
-                            .map_io_err(|| format!("Failed to delete directory: {:?}", node_.path));
+                        result = r.map_io_err(|| format!("Failed to delete directory: {path:?}"));
                     }
                 }
             }
@@ -445,11 +925,243 @@ mod compat {
         result.map(|()| UnlinkDirOutcome::Ok)
     }
 
+    /// Deletes the non-directory entries of a directory, holding them back to
+    /// be submitted together when `io_uring` is available.
+    struct FileDeleter<'a> {
+        throttle: Option<&'a RateLimiter>,
+        #[cfg(feature = "io_uring")]
+        io_uring: bool,
+        #[cfg(feature = "io_uring")]
+        queued: Vec<CString>,
+    }
+
+    impl<'a> FileDeleter<'a> {
+        #[cfg_attr(not(feature = "io_uring"), allow(unused_variables))]
+        const fn new(throttle: Option<&'a RateLimiter>, io_uring: bool) -> Self {
+            Self {
+                throttle,
+                #[cfg(feature = "io_uring")]
+                io_uring,
+                #[cfg(feature = "io_uring")]
+                queued: Vec::new(),
+            }
+        }
+
+        fn charge(&self) {
+            if let Some(throttle) = self.throttle {
+                throttle.acquire(1);
+            }
+        }
+
+        /// Batches would be unlinked all at once regardless of the rate limit.
+        #[cfg(feature = "io_uring")]
+        fn batches(&self) -> bool {
+            self.io_uring && self.throttle.is_none() && io_uring::is_supported()
+        }
+    }
+
+    #[cfg_attr(
+        not(feature = "io_uring"),
+        allow(
+            unused_variables,
+            clippy::unused_self,
+            clippy::needless_pass_by_ref_mut
+        )
+    )]
+    impl FileDeleter<'_> {
+        /// Returns `false` if the file turned out to be a directory.
+        fn delete(
+            &mut self,
+            dir: &OwnedFd,
+            dir_path: &CString,
+            file: &CStr,
+            file_type: FileType,
+            force_permissions: ForcePermissions,
+        ) -> Result<bool, Error> {
+            self.charge();
+            #[cfg(feature = "io_uring")]
+            if file_type != FileType::Unknown && self.batches() {
+                self.queue(dir, dir_path, file.to_owned(), force_permissions)?;
+                return Ok(true);
+            }
+
+            delete_one_file(dir, dir_path, file, force_permissions)
+        }
+
+        /// Deletes a file which is known not to be a directory.
+        fn delete_known(
+            &mut self,
+            dir: &OwnedFd,
+            dir_path: &CString,
+            file: CString,
+            force_permissions: ForcePermissions,
+        ) -> Result<(), Error> {
+            self.charge();
+            #[cfg(feature = "io_uring")]
+            if self.batches() {
+                return self.queue(dir, dir_path, file, force_permissions);
+            }
+
+            delete_known_file(dir, dir_path, &file, force_permissions)
+        }
+
+        #[cfg(feature = "io_uring")]
+        fn queue(
+            &mut self,
+            dir: &OwnedFd,
+            dir_path: &CString,
+            file: CString,
+            force_permissions: ForcePermissions,
+        ) -> Result<(), Error> {
+            self.queued.push(file);
+            if self.queued.len() < io_uring::BATCH_SIZE {
+                return Ok(());
+            }
+            self.flush(dir, dir_path, force_permissions)
+        }
+
+        /// Deletes the files which were held back, falling back to the regular
+        /// path for any that couldn't be unlinked.
+        #[cfg(feature = "io_uring")]
+        fn flush(
+            &mut self,
+            dir: &OwnedFd,
+            dir_path: &CString,
+            force_permissions: ForcePermissions,
+        ) -> Result<(), Error> {
+            for file in io_uring::unlink_files(dir, &mut self.queued) {
+                delete_known_file(dir, dir_path, &file, force_permissions)?;
+            }
+            Ok(())
+        }
+    }
+
+    fn delete_known_file(
+        dir: &OwnedFd,
+        dir_path: &CString,
+        file: &CStr,
+        force_permissions: ForcePermissions,
+    ) -> Result<(), Error> {
+        if delete_one_file(dir, dir_path, file, force_permissions)? {
+            return Ok(());
+        }
+        Err(Error::Io {
+            error: Errno::ISDIR.into(),
+            context: format!(
+                "Failed to delete file: {:?}",
+                join_cstr_paths(dir_path, file)
+            )
+            .into(),
+        })
+    }
+
+    /// Returns `false` if the file turned out to be a directory.
+    fn delete_one_file(
+        dir: &OwnedFd,
+        dir_path: &CString,
+        file: &CStr,
+        force_permissions: ForcePermissions,
+    ) -> Result<bool, Error> {
+        match with_permissions_fallback(
+            force_permissions.enabled(),
+            || delete_file(dir, file),
+            || unlock_dir(dir, Some(file), force_permissions),
+        ) {
+            Ok(()) => Ok(true),
+            Err(Errno::ISDIR) => Ok(false),
+            Err(Errno::BUSY) if is_mount_root(&join_cstr_paths(dir_path, file)) => {
+                Err(Error::MountPoint {
+                    file: join_cstr_paths(dir_path, file),
+                })
+            }
+            Err(error) => Err(Error::Io {
+                error: error.into(),
+                context: format!(
+                    "Failed to delete file: {:?}",
+                    join_cstr_paths(dir_path, file)
+                )
+                .into(),
+            }),
+        }
+    }
+
     #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip(dir)))]
     fn delete_file(dir: impl AsFd, file: &CStr) -> rustix::io::Result<()> {
         unlinkat(&dir, file, AtFlags::empty())
     }
 
+    fn with_permissions_fallback<T>(
+        force_permissions: bool,
+        mut op: impl FnMut() -> rustix::io::Result<T>,
+        unlock: impl FnOnce() -> rustix::io::Result<()>,
+    ) -> rustix::io::Result<T> {
+        match op() {
+            Err(e @ (Errno::ACCESS | Errno::PERM)) if force_permissions => {
+                if unlock().is_err() {
+                    return Err(e);
+                }
+                op()
+            }
+            r => r,
+        }
+    }
+
+    /// Whether we may change the immutable and append-only attributes.
+    fn can_change_attributes() -> bool {
+        capabilities(None).is_ok_and(|caps| caps.effective.contains(CapabilitySet::LINUX_IMMUTABLE))
+    }
+
+    /// Makes the directory writable by its owner and, if asked to, clears the
+    /// attributes that prevent the directory or the file being removed from
+    /// being modified.
+    #[cold]
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip(dir)))]
+    fn unlock_dir(
+        dir: impl AsFd,
+        file: Option<&CStr>,
+        force_permissions: ForcePermissions,
+    ) -> rustix::io::Result<()> {
+        fn clear_immutable(fd: impl AsFd) {
+            let locked = IFlags::IMMUTABLE | IFlags::APPEND;
+            if let Ok(flags) = ioctl_getflags(&fd)
+                && flags.intersects(locked)
+            {
+                let _ = ioctl_setflags(fd, flags - locked);
+            }
+        }
+
+        if force_permissions != ForcePermissions::ModeAndAttributes {
+            return add_owner_access(dir);
+        }
+
+        // Opening devices or FIFOs can have side effects, and the attributes can
+        // only be changed through regular files and directories anyway.
+        if let Some(file) = file
+            && let Ok(metadata) = statx(&dir, file, AtFlags::SYMLINK_NOFOLLOW, StatxFlags::TYPE)
+            && matches!(
+                FileType::from_raw_mode(metadata.stx_mode.into()),
+                FileType::RegularFile | FileType::Directory
+            )
+            && let Ok(file) = openat(
+                &dir,
+                file,
+                OFlags::RDONLY | OFlags::NOFOLLOW | OFlags::NONBLOCK | OFlags::NOCTTY,
+                Mode::empty(),
+            )
+        {
+            clear_immutable(file);
+        }
+        clear_immutable(&dir);
+        add_owner_access(dir)
+    }
+
+    /// Lets the owner create and remove entries in the directory while
+    /// leaving the rest of its mode alone, in case removing it fails later.
+    fn add_owner_access(dir: impl AsFd) -> rustix::io::Result<()> {
+        let mode = Mode::from_raw_mode(fstat(&dir)?.st_mode);
+        fchmod(dir, mode | Mode::WUSR | Mode::XUSR)
+    }
+
     #[cold]
     #[cfg_attr(feature = "tracing", tracing::instrument(level = "trace"))]
     fn long_path_fallback_deletion(parent: &CString, child: &CStr) -> Result<(), Error> {
@@ -483,40 +1195,214 @@ mod compat {
         Ok(())
     }
 
+    enum Task {
+        Dir(TreeNode),
+        Files(FileBatch),
+    }
+
+    impl Queued for Task {
+        fn queued_size(&self) -> usize {
+            match self {
+                Self::Dir(node) => node.queued_size(),
+                Self::Files(FileBatch { dir: _, files }) => queued_batch_size(files),
+            }
+        }
+    }
+
     struct TreeNode {
-        path: CString,
+        /// The directory's name in its parent, or its path for the directories
+        /// passed in.
+        name: CString,
         parent: Option<Arc<Self>>,
-        messages: Sender<Self>,
+        root_dev: Option<DeviceId>,
+        entry_order: EntryOrder,
+    }
+
+    #[derive(Debug)]
+    struct FileBatch {
+        dir: Arc<TreeNode>,
+        files: Vec<CString>,
+    }
+
+    fn queued_batch_size(files: &[CString]) -> usize {
+        mem::size_of::<Task>()
+            + files
+                .iter()
+                .map(|name| mem::size_of::<CString>() + name.as_bytes_with_nul().len())
+                .sum::<usize>()
+    }
+
+    impl TreeNode {
+        fn queued_size(&self) -> usize {
+            mem::size_of::<Task>() + self.name.as_bytes_with_nul().len()
+        }
+
+        /// Joins the names of the directories leading up to this one.
+        fn path(&self) -> CString {
+            let mut names = vec![self.name.as_bytes()];
+            let mut node = self;
+            while let Some(ref parent) = node.parent {
+                names.push(parent.name.as_bytes());
+                node = parent;
+            }
+
+            let mut path = Vec::new();
+            for name in names.into_iter().rev() {
+                if !path.is_empty() {
+                    path.push(u8::try_from(MAIN_SEPARATOR).unwrap());
+                }
+                path.extend_from_slice(name);
+            }
+            unsafe { CString::from_vec_unchecked(path) }
+        }
     }
 
     impl Debug for TreeNode {
         fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-            self.path.fmt(f)
+            self.path().fmt(f)
+        }
+    }
+
+    /// Unlinks batches of files with a single `io_uring` submission instead of
+    /// one blocking syscall per file.
+    #[cfg(feature = "io_uring")]
+    mod io_uring {
+        use std::{
+            cell::RefCell,
+            ffi::CString,
+            mem,
+            os::fd::{AsRawFd, OwnedFd},
+            sync::OnceLock,
+        };
+
+        use rustix::{
+            fs::AtFlags,
+            io_uring::{
+                IoringFeatureFlags, IoringOp, addr_or_splice_off_in_union, io_uring_ptr,
+                io_uring_sqe, io_uring_user_data, op_flags_union,
+            },
+        };
+
+        use crate::ops::uring::{Ring, is_available};
+
+        const RING_ENTRIES: u32 = 256;
+        pub const BATCH_SIZE: usize = RING_ENTRIES as usize;
+
+        thread_local! {
+            static RING: RefCell<Option<Ring>> =
+                RefCell::new(Ring::new(RING_ENTRIES, &[IoringOp::Unlinkat], IoringFeatureFlags::empty()).ok());
+        }
+
+        pub fn is_supported() -> bool {
+            static SUPPORTED: OnceLock<bool> = OnceLock::new();
+            *SUPPORTED
+                .get_or_init(|| is_available(&[IoringOp::Unlinkat], IoringFeatureFlags::empty()))
+        }
+
+        /// Unlinks the named files, returning the ones which must go through
+        /// the regular path instead.
+        pub fn unlink_files(dir: &OwnedFd, names: &mut Vec<CString>) -> Vec<CString> {
+            if names.is_empty() {
+                return Vec::new();
+            }
+            RING.with_borrow_mut(|ring| {
+                let Some(ring_) = ring else {
+                    return mem::take(names);
+                };
+
+                for (i, name) in names.iter().enumerate() {
+                    unsafe {
+                        ring_.push(io_uring_sqe {
+                            opcode: IoringOp::Unlinkat,
+                            fd: dir.as_raw_fd(),
+                            addr_or_splice_off_in: addr_or_splice_off_in_union {
+                                addr: io_uring_ptr::new(name.as_ptr().cast_mut().cast()),
+                            },
+                            op_flags: op_flags_union {
+                                unlink_flags: AtFlags::empty(),
+                            },
+                            user_data: io_uring_user_data::from_u64(i as u64),
+                            ..Default::default()
+                        });
+                    }
+                }
+                let mut unlinked = vec![false; names.len()];
+                #[allow(clippy::cast_possible_truncation)]
+                let result = ring_.submit_and_wait(|data, res| {
+                    unlinked[data as usize] = res >= 0;
+                });
+                if result.is_err() {
+                    // Nothing is in flight anymore, but the ring can't be trusted after
+                    // failing, so everything is left to the regular path from now on.
+                    *ring = None;
+                }
+
+                let mut unlinked = unlinked.into_iter();
+                names.retain(|_| !unlinked.next().unwrap_or_default());
+                mem::take(names)
+            })
         }
     }
 }
 
 #[cfg(all(not(target_os = "linux"), not(target_os = "windows")))]
 mod compat {
-    use std::{borrow::Cow, fmt::Debug, fs, io, path::Path};
+    use std::{
+        borrow::Cow,
+        fmt::Debug,
+        fs, io,
+        os::unix::fs::{MetadataExt, PermissionsExt},
+        path::{Path, PathBuf},
+        sync::Arc,
+    };
 
     use rayon::prelude::*;
 
+    use super::{ForcePermissions, is_mount_root};
     use crate::{
         Error,
-        ops::{IoErr, compat::DirectoryOp},
+        ops::{EntryOrder, IoErr, Stats, Tuning, compat::DirectoryOp, throttle::RateLimiter},
     };
 
-    struct Impl;
+    struct Impl {
+        one_file_system: bool,
+        force_permissions: ForcePermissions,
+    }
 
-    pub fn remove_impl<'a>() -> impl DirectoryOp<Cow<'a, Path>> {
-        Impl
+    pub fn remove_impl<'a>(
+        one_file_system: bool,
+        force_permissions: ForcePermissions,
+        _io_uring: bool,
+        _entry_order: EntryOrder,
+        _tuning: Tuning,
+        _throttle: Option<Arc<RateLimiter>>,
+        _stats: Option<Arc<Stats>>,
+    ) -> impl DirectoryOp<Cow<'a, Path>> {
+        Impl {
+            one_file_system,
+            force_permissions,
+        }
     }
 
     impl DirectoryOp<Cow<'_, Path>> for Impl {
         #[cfg_attr(feature = "tracing", tracing::instrument(level = "trace", skip(self)))]
         fn run(&self, dir: Cow<Path>) -> Result<(), Error> {
-            remove_dir_all(&dir).map_io_err(|| format!("Failed to delete directory: {dir:?}"))
+            let root_dev = if self.one_file_system {
+                Some(
+                    dir.symlink_metadata()
+                        .map_io_err(|| format!("Failed to get device: {dir:?}"))?
+                        .dev(),
+                )
+            } else {
+                None
+            };
+            remove_dir_all(&dir, root_dev, self.force_permissions).map_err(|error| match error {
+                RemoveError::MountPoint(file) => Error::MountPoint { file },
+                RemoveError::Io(error) => Error::Io {
+                    error,
+                    context: format!("Failed to delete directory: {dir:?}").into(),
+                },
+            })
         }
 
         #[cfg_attr(feature = "tracing", tracing::instrument(level = "trace", skip(self)))]
@@ -525,38 +1411,119 @@ mod compat {
         }
     }
 
+    enum RemoveError {
+        MountPoint(PathBuf),
+        Io(io::Error),
+    }
+
+    impl From<io::Error> for RemoveError {
+        fn from(error: io::Error) -> Self {
+            Self::Io(error)
+        }
+    }
+
     #[cfg_attr(feature = "tracing", tracing::instrument(level = "info"))]
-    fn remove_dir_all<P: AsRef<Path> + Debug>(path: P) -> Result<(), io::Error> {
+    fn remove_dir_all<P: AsRef<Path> + Debug>(
+        path: P,
+        root_dev: Option<u64>,
+        force_permissions: ForcePermissions,
+    ) -> Result<(), RemoveError> {
         let path = path.as_ref();
-        path.read_dir()?
+        remove_dir_contents(path, root_dev, force_permissions)?;
+        remove_dir(path, None, force_permissions)
+    }
+
+    fn remove_dir_contents(
+        path: &Path,
+        root_dev: Option<u64>,
+        force_permissions: ForcePermissions,
+    ) -> Result<(), RemoveError> {
+        with_permissions_fallback(force_permissions, path, || path.read_dir())?
             .par_bridge()
-            .try_for_each(|dir_entry| -> io::Result<()> {
+            .try_for_each(|dir_entry| -> Result<(), RemoveError> {
                 let dir_entry = dir_entry?;
                 if dir_entry.file_type()?.is_dir() {
-                    remove_dir_all(dir_entry.path())?;
+                    if let Some(root_dev) = root_dev
+                        && dir_entry.metadata()?.dev() != root_dev
+                    {
+                        return Err(RemoveError::MountPoint(dir_entry.path()));
+                    }
+                    remove_dir_contents(&dir_entry.path(), root_dev, force_permissions)?;
+                    remove_dir(&dir_entry.path(), Some(path), force_permissions)?;
                 } else {
-                    fs::remove_file(dir_entry.path())?;
+                    with_permissions_fallback(force_permissions, path, || {
+                        fs::remove_file(dir_entry.path())
+                    })?;
                 }
                 Ok(())
-            })?;
-        fs::remove_dir(path)
+            })
+    }
+
+    fn remove_dir(
+        path: &Path,
+        parent: Option<&Path>,
+        force_permissions: ForcePermissions,
+    ) -> Result<(), RemoveError> {
+        let result = if let Some(parent) = parent {
+            with_permissions_fallback(force_permissions, parent, || fs::remove_dir(path))
+        } else {
+            fs::remove_dir(path)
+        };
+        match result {
+            Err(e) if e.kind() == io::ErrorKind::ResourceBusy && is_mount_root(path) => {
+                Err(RemoveError::MountPoint(path.to_path_buf()))
+            }
+            r => r.map_err(RemoveError::Io),
+        }
+    }
+
+    fn with_permissions_fallback<T>(
+        force_permissions: ForcePermissions,
+        dir: &Path,
+        op: impl Fn() -> io::Result<T>,
+    ) -> io::Result<T> {
+        match op() {
+            Err(e)
+                if force_permissions.enabled() && e.kind() == io::ErrorKind::PermissionDenied =>
+            {
+                // Only add to the mode in case removing the directory fails later.
+                let unlocked = fs::symlink_metadata(dir).and_then(|metadata| {
+                    let mode = metadata.permissions().mode() | 0o700;
+                    fs::set_permissions(dir, fs::Permissions::from_mode(mode))
+                });
+                if unlocked.is_err() {
+                    return Err(e);
+                }
+                op()
+            }
+            r => r,
+        }
     }
 }
 
 #[cfg(target_os = "windows")]
 mod compat {
-    use std::{borrow::Cow, path::Path};
+    use std::{borrow::Cow, path::Path, sync::Arc};
 
     use remove_dir_all::remove_dir_all;
 
+    use super::ForcePermissions;
     use crate::{
         Error,
-        ops::{IoErr, compat::DirectoryOp},
+        ops::{EntryOrder, IoErr, Stats, Tuning, compat::DirectoryOp, throttle::RateLimiter},
     };
 
     struct Impl;
 
-    pub fn remove_impl<'a>() -> impl DirectoryOp<Cow<'a, Path>> {
+    pub fn remove_impl<'a>(
+        _one_file_system: bool,
+        _force_permissions: ForcePermissions,
+        _io_uring: bool,
+        _entry_order: EntryOrder,
+        _tuning: Tuning,
+        _throttle: Option<Arc<RateLimiter>>,
+        _stats: Option<Arc<Stats>>,
+    ) -> impl DirectoryOp<Cow<'a, Path>> {
         Impl
     }
 
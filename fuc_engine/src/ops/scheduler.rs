@@ -0,0 +1,247 @@
+use std::{
+    iter,
+    num::NonZeroUsize,
+    sync::{
+        Condvar, Mutex, MutexGuard, PoisonError, RwLock,
+        atomic::{AtomicUsize, Ordering, fence},
+    },
+};
+
+use crossbeam_deque::{Injector, Steal, Stealer, Worker};
+
+/// How many levels deep a worker descends into the directories it finds
+/// while the queue is full before queueing them anyway. Each level keeps a
+/// directory open and a buffer allocated.
+pub const MAX_DESCENT: usize = 8;
+
+/// A task which knows roughly how much memory it holds while it's queued.
+pub trait Queued {
+    fn queued_size(&self) -> usize;
+}
+
+/// Distributes tasks between workers. Each worker pushes the tasks it
+/// discovers onto its own LIFO deque so that it keeps descending into the
+/// directories it just found while they're still hot in the cache. Idle
+/// workers steal the oldest (and usually largest) tasks from everyone else
+/// and park when there's nothing left to take.
+pub struct Scheduler<T> {
+    injector: Injector<T>,
+    stealers: RwLock<Vec<Stealer<T>>>,
+    budget: QueueBudget,
+    /// Tasks which have been queued but haven't finished running yet.
+    unfinished: AtomicUsize,
+    sleepers: AtomicUsize,
+    /// Whether more tasks may be injected.
+    closed: Mutex<bool>,
+    wakeup: Condvar,
+}
+
+impl<T: Queued> Scheduler<T> {
+    pub fn new(memory_limit: Option<NonZeroUsize>) -> Self {
+        Self {
+            injector: Injector::new(),
+            stealers: RwLock::default(),
+            budget: QueueBudget::new(memory_limit),
+            unfinished: AtomicUsize::new(0),
+            sleepers: AtomicUsize::new(0),
+            closed: Mutex::new(false),
+            wakeup: Condvar::new(),
+        }
+    }
+
+    /// Queues a task from outside the workers, regardless of the memory
+    /// budget.
+    pub fn inject(&self, task: T) {
+        self.unfinished.fetch_add(1, Ordering::SeqCst);
+        self.budget.reserve(task.queued_size());
+        self.injector.push(task);
+        self.wake_one();
+    }
+}
+
+impl<T> Scheduler<T> {
+    /// Returns the most memory that was ever held by queued tasks at once.
+    pub fn peak_queued_bytes(&self) -> usize {
+        self.budget.peak.load(Ordering::Relaxed)
+    }
+
+    /// Lets the workers exit once every queued task has finished.
+    pub fn close(&self) {
+        *self.lock() = true;
+        self.wakeup.notify_all();
+    }
+
+    /// Registers the calling thread as a worker.
+    pub fn local_queue(&self) -> LocalQueue<'_, T> {
+        let worker = Worker::new_lifo();
+        self.stealers
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(worker.stealer());
+        LocalQueue {
+            scheduler: self,
+            worker,
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, bool> {
+        self.closed.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn wake_one(&self) {
+        // Pairs with the fence in `LocalQueue::park` so that either the sleeper
+        // sees the new task or we see the sleeper.
+        fence(Ordering::SeqCst);
+        if self.sleepers.load(Ordering::SeqCst) > 0 {
+            drop(self.lock());
+            self.wakeup.notify_one();
+        }
+    }
+
+    fn is_done(&self, closed: bool) -> bool {
+        closed && self.unfinished.load(Ordering::SeqCst) == 0
+    }
+}
+
+/// A worker's handle to the scheduler.
+pub struct LocalQueue<'a, T> {
+    scheduler: &'a Scheduler<T>,
+    worker: Worker<T>,
+}
+
+impl<'a, T: Queued> LocalQueue<'a, T> {
+    /// Queues a task for this worker or anyone who steals it, handing it back
+    /// if the queue has run out of memory budget.
+    pub fn try_push(&self, task: T) -> Result<(), T> {
+        let scheduler = self.scheduler;
+        if !scheduler.budget.try_reserve(task.queued_size()) {
+            return Err(task);
+        }
+        scheduler.unfinished.fetch_add(1, Ordering::SeqCst);
+        self.worker.push(task);
+        scheduler.wake_one();
+        Ok(())
+    }
+
+    /// Queues a task regardless of the memory budget.
+    pub fn inject(&self, task: T) {
+        self.scheduler.inject(task);
+    }
+
+    /// Returns whether there are tasks which another worker could pick up.
+    pub fn has_work(&self) -> bool {
+        !self.worker.is_empty() || !self.scheduler.injector.is_empty()
+    }
+
+    /// Waits for the next task, returning `None` once the scheduler has been
+    /// closed and all work is done. The task counts as finished when the
+    /// returned guard is dropped.
+    pub fn next(&self) -> Option<(T, Finished<'a, T>)> {
+        let task = self.find().or_else(|| self.park())?;
+        self.scheduler.budget.release(task.queued_size());
+        Some((task, Finished(self.scheduler)))
+    }
+
+    fn find(&self) -> Option<T> {
+        self.worker.pop().or_else(|| {
+            iter::repeat_with(|| {
+                self.scheduler
+                    .injector
+                    .steal_batch_and_pop(&self.worker)
+                    .or_else(|| {
+                        self.scheduler
+                            .stealers
+                            .read()
+                            .unwrap_or_else(PoisonError::into_inner)
+                            .iter()
+                            .map(|stealer| stealer.steal_batch_and_pop(&self.worker))
+                            .collect()
+                    })
+            })
+            .find(|steal| !steal.is_retry())
+            .and_then(Steal::success)
+        })
+    }
+
+    #[cold]
+    #[allow(clippy::significant_drop_tightening)] // The guard is reused by every wait.
+    fn park(&self) -> Option<T> {
+        let scheduler = self.scheduler;
+        let mut closed = scheduler.lock();
+        loop {
+            scheduler.sleepers.fetch_add(1, Ordering::SeqCst);
+            fence(Ordering::SeqCst);
+            let task = self.find();
+            if task.is_some() || scheduler.is_done(*closed) {
+                scheduler.sleepers.fetch_sub(1, Ordering::SeqCst);
+                return task;
+            }
+            closed = scheduler
+                .wakeup
+                .wait(closed)
+                .unwrap_or_else(PoisonError::into_inner);
+            scheduler.sleepers.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Marks a task as finished when dropped, even if it failed.
+pub struct Finished<'a, T>(&'a Scheduler<T>);
+
+impl<T> Drop for Finished<'_, T> {
+    fn drop(&mut self) {
+        let scheduler = self.0;
+        if scheduler.unfinished.fetch_sub(1, Ordering::SeqCst) == 1 && *scheduler.lock() {
+            scheduler.wakeup.notify_all();
+        }
+    }
+}
+
+/// Keeps track of the memory held by work waiting in a queue. Once the budget
+/// is used up, workers stop queueing the directories they discover and
+/// descend into each one as soon as it's found instead, reusing a directory
+/// buffer per level. Past [`MAX_DESCENT`] levels, directories are queued over
+/// the limit rather than keeping ever more of them open. Tasks injected from
+/// outside the workers may also go over the limit.
+#[derive(Debug)]
+struct QueueBudget {
+    limit: usize,
+    used: AtomicUsize,
+    peak: AtomicUsize,
+}
+
+impl QueueBudget {
+    fn new(limit: Option<NonZeroUsize>) -> Self {
+        Self {
+            limit: limit.map_or(usize::MAX, NonZeroUsize::get),
+            used: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+        }
+    }
+
+    /// Claims room for a queued task regardless of the limit.
+    fn reserve(&self, bytes: usize) {
+        let used = self.used.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        self.peak.fetch_max(used, Ordering::Relaxed);
+    }
+
+    /// Claims room for a queued task, returning false if that would exceed the
+    /// limit.
+    fn try_reserve(&self, bytes: usize) -> bool {
+        let Ok(used) = self
+            .used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                used.checked_add(bytes).filter(|&used| used <= self.limit)
+            })
+        else {
+            return false;
+        };
+        self.peak.fetch_max(used + bytes, Ordering::Relaxed);
+        true
+    }
+
+    /// Gives back the room claimed by a task which was taken off the queue.
+    fn release(&self, bytes: usize) {
+        self.used.fetch_sub(bytes, Ordering::Relaxed);
+    }
+}
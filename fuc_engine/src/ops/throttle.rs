@@ -0,0 +1,171 @@
+use std::{
+    fmt::Debug,
+    num::NonZeroU64,
+    sync::{Mutex, PoisonError},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// How much unused capacity may build up while the limiter sits idle.
+const BURST: Duration = Duration::from_millis(100);
+
+/// Where the limiter gets the time from, so that tests can check the waits it
+/// asks for without sleeping through them.
+trait Clock: Debug + Send + Sync {
+    fn now(&self) -> Instant;
+
+    /// Blocks until `deadline` has passed.
+    fn sleep_until(&self, deadline: Instant);
+}
+
+#[derive(Debug)]
+struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep_until(&self, deadline: Instant) {
+        thread::sleep(deadline.saturating_duration_since(Instant::now()));
+    }
+}
+
+/// A token bucket shared by every worker of an operation. Rather than
+/// counting tokens, it tracks the point in time at which all the work handed
+/// out so far will have been paid for and makes each worker sleep until its
+/// own work is.
+#[derive(Debug)]
+pub struct RateLimiter {
+    per_second: NonZeroU64,
+    clock: Box<dyn Clock>,
+    paid_until: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(per_second: NonZeroU64) -> Self {
+        Self::with_clock(per_second, Box::new(SystemClock))
+    }
+
+    fn with_clock(per_second: NonZeroU64, clock: Box<dyn Clock>) -> Self {
+        Self {
+            per_second,
+            paid_until: Mutex::new(clock.now()),
+            clock,
+        }
+    }
+
+    /// Blocks until `amount` units of work may be done.
+    pub fn acquire(&self, amount: u64) {
+        let cost = Duration::from_nanos(
+            u64::try_from(u128::from(amount) * 1_000_000_000 / u128::from(self.per_second.get()))
+                .unwrap_or(u64::MAX),
+        );
+        let now = self.clock.now();
+        let deadline = {
+            let mut paid_until = self
+                .paid_until
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            let start = (*paid_until).max(now.checked_sub(BURST).unwrap_or(now));
+            *paid_until = start + cost;
+            *paid_until
+        };
+        if deadline > now {
+            self.clock.sleep_until(deadline);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    /// A clock which only moves forward when someone sleeps on it.
+    #[derive(Debug, Clone)]
+    struct MockClock {
+        start: Instant,
+        elapsed: Arc<Mutex<Duration>>,
+    }
+
+    impl MockClock {
+        fn new() -> Self {
+            Self {
+                start: Instant::now(),
+                elapsed: Arc::default(),
+            }
+        }
+
+        fn elapsed(&self) -> Duration {
+            *self.elapsed.lock().unwrap()
+        }
+
+        fn advance(&self, by: Duration) {
+            *self.elapsed.lock().unwrap() += by;
+        }
+    }
+
+    impl Clock for MockClock {
+        fn now(&self) -> Instant {
+            self.start + self.elapsed()
+        }
+
+        fn sleep_until(&self, deadline: Instant) {
+            let mut elapsed = self.elapsed.lock().unwrap();
+            *elapsed = (*elapsed).max(deadline - self.start);
+        }
+    }
+
+    fn limiter(per_second: u64) -> (RateLimiter, MockClock) {
+        let clock = MockClock::new();
+        (
+            RateLimiter::with_clock(
+                NonZeroU64::new(per_second).unwrap(),
+                Box::new(clock.clone()),
+            ),
+            clock,
+        )
+    }
+
+    #[test]
+    fn waits_for_work_to_be_paid_for() {
+        let (limiter, clock) = limiter(1000);
+
+        limiter.acquire(500);
+        assert_eq!(clock.elapsed(), Duration::from_millis(500));
+        limiter.acquire(250);
+        assert_eq!(clock.elapsed(), Duration::from_millis(750));
+    }
+
+    #[test]
+    fn idle_time_builds_up_a_limited_burst() {
+        let (limiter, clock) = limiter(1000);
+
+        clock.advance(Duration::from_secs(10));
+        limiter.acquire(100);
+        assert_eq!(clock.elapsed(), Duration::from_secs(10));
+        limiter.acquire(100);
+        assert_eq!(
+            clock.elapsed(),
+            Duration::from_secs(10) + Duration::from_millis(100)
+        );
+    }
+
+    #[test]
+    fn shared_between_threads() {
+        let (limiter, clock) = limiter(4 << 20);
+
+        thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for _ in 0..64 {
+                        limiter.acquire(16 << 10);
+                    }
+                });
+            }
+        });
+        assert_eq!(clock.elapsed(), Duration::from_secs(1));
+    }
+}
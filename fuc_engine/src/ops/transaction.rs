@@ -0,0 +1,69 @@
+use std::{
+    borrow::Cow,
+    mem,
+    path::PathBuf,
+    sync::{Mutex, PoisonError},
+};
+
+use crate::{Error, RemoveOp};
+
+/// The entries an operation created, so that they can be removed again if it
+/// fails. Entries inside created directories aren't recorded separately since
+/// removing their parent takes care of them.
+#[derive(Debug, Default)]
+pub struct Transaction {
+    created: Mutex<Vec<PathBuf>>,
+}
+
+impl Transaction {
+    /// Records an entry which is about to be created, so that a partially
+    /// created one is cleaned up as well.
+    pub fn record(&self, path: PathBuf) {
+        self.created
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(path);
+    }
+
+    /// Rolls back after the operation failed with `error`, which is what gets
+    /// returned either way since it's what the caller has to fix. If rolling
+    /// back fails too, that failure is added to an I/O error's context and
+    /// wraps any other error so that it's never lost.
+    pub fn roll_back_after(&self, error: Error) -> Error {
+        let Err(failure) = self.roll_back() else {
+            return error;
+        };
+        let failure = match failure {
+            Error::Io { error, context } => format!("{context}: {error}"),
+            failure => failure.to_string(),
+        };
+        #[cfg(feature = "tracing")]
+        tracing::error!(failure, "Failed to roll back.");
+        match error {
+            Error::Io { error, context } => Error::Io {
+                error,
+                context: format!("{context} (rolling back also failed: {failure})").into(),
+            },
+            error => Error::RollbackFailed {
+                error: Box::new(error),
+                failure,
+            },
+        }
+    }
+
+    /// Removes every recorded entry, leaving everything else untouched.
+    fn roll_back(&self) -> Result<(), Error> {
+        let created = mem::take(&mut *self.created.lock().unwrap_or_else(PoisonError::into_inner));
+        if created.is_empty() {
+            return Ok(());
+        }
+        RemoveOp::builder()
+            .files(created.into_iter().map(Cow::Owned))
+            // Entries which failed to be created don't exist.
+            .force(true)
+            // Copied directories may be read-only by now.
+            .force_permissions(true)
+            .build()
+            .run()
+    }
+}
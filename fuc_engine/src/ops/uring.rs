@@ -0,0 +1,262 @@
+//! A minimal `io_uring` ring for submitting batches of file operations and
+//! waiting for all of them to complete.
+
+use std::{
+    ffi::c_void,
+    mem::size_of,
+    os::fd::OwnedFd,
+    ptr,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use rustix::{
+    io::Errno,
+    io_uring::{
+        IORING_OFF_SQ_RING, IORING_OFF_SQES, IoringEnterFlags, IoringFeatureFlags, IoringOp,
+        IoringOpFlags, IoringRegisterOp, io_uring_cqe, io_uring_enter, io_uring_files_update,
+        io_uring_params, io_uring_probe, io_uring_probe_op, io_uring_ptr, io_uring_register,
+        io_uring_setup, io_uring_sqe,
+    },
+    mm::{MapFlags, ProtFlags, mmap, munmap},
+};
+
+/// Returns whether rings supporting every operation in `ops` and all of
+/// `features` can be set up.
+pub fn is_available(ops: &[IoringOp], features: IoringFeatureFlags) -> bool {
+    Ring::new(1, ops, features).is_ok()
+}
+
+pub struct Ring {
+    fd: OwnedFd,
+    rings: Mapping,
+    sqes: Mapping,
+    params: io_uring_params,
+    queued: u32,
+}
+
+struct Mapping {
+    ptr: *mut c_void,
+    len: usize,
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        let _ = unsafe { munmap(self.ptr, self.len) };
+    }
+}
+
+impl Mapping {
+    fn new(fd: &OwnedFd, len: usize, offset: u64) -> rustix::io::Result<Self> {
+        let ptr = unsafe {
+            mmap(
+                ptr::null_mut(),
+                len,
+                ProtFlags::READ | ProtFlags::WRITE,
+                MapFlags::SHARED | MapFlags::POPULATE,
+                fd,
+                offset,
+            )?
+        };
+        Ok(Self { ptr, len })
+    }
+
+    const fn at<T>(&self, offset: u32) -> *mut T {
+        unsafe { self.ptr.byte_add(offset as usize).cast() }
+    }
+}
+
+impl Ring {
+    /// Sets up a ring which can hold `entries` operations at once, failing
+    /// on kernels which don't support every operation in `ops` or lack any of
+    /// `features`.
+    pub fn new(
+        entries: u32,
+        ops: &[IoringOp],
+        features: IoringFeatureFlags,
+    ) -> rustix::io::Result<Self> {
+        let mut params = io_uring_params::default();
+        let fd = unsafe { io_uring_setup(entries, &mut params)? };
+        // Older kernels needed separate mappings for the two rings and could drop
+        // completions, neither of which is worth supporting.
+        if !params
+            .features
+            .contains(IoringFeatureFlags::SINGLE_MMAP | IoringFeatureFlags::NODROP | features)
+        {
+            return Err(Errno::NOSYS);
+        }
+
+        let rings = Mapping::new(
+            &fd,
+            (params.sq_off.array as usize + params.sq_entries as usize * size_of::<u32>()).max(
+                params.cq_off.cqes as usize
+                    + params.cq_entries as usize * size_of::<io_uring_cqe>(),
+            ),
+            IORING_OFF_SQ_RING,
+        )?;
+        let sqes = Mapping::new(
+            &fd,
+            params.sq_entries as usize * size_of::<io_uring_sqe>(),
+            IORING_OFF_SQES,
+        )?;
+        let ring = Self {
+            fd,
+            rings,
+            sqes,
+            params,
+            queued: 0,
+        };
+        if !ring.supports(ops)? {
+            return Err(Errno::NOSYS);
+        }
+        Ok(ring)
+    }
+
+    fn supports(&self, ops: &[IoringOp]) -> rustix::io::Result<bool> {
+        const MAX_OPS: u32 = 256;
+
+        #[repr(C)]
+        struct Probe {
+            header: io_uring_probe,
+            ops: [io_uring_probe_op; MAX_OPS as usize],
+        }
+
+        let mut probe = Probe {
+            header: io_uring_probe::default(),
+            ops: [io_uring_probe_op::default(); MAX_OPS as usize],
+        };
+        unsafe {
+            io_uring_register(
+                &self.fd,
+                IoringRegisterOp::RegisterProbe,
+                ptr::from_mut(&mut probe).cast(),
+                MAX_OPS,
+            )?;
+        }
+        let supported = &probe.ops[..usize::from(probe.header.ops_len)];
+        Ok(ops.iter().all(|&op| {
+            supported
+                .iter()
+                .any(|probed| probed.op == op && probed.flags.contains(IoringOpFlags::SUPPORTED))
+        }))
+    }
+
+    pub const fn capacity(&self) -> u32 {
+        self.params.sq_entries
+    }
+
+    /// Sets up `count` empty slots which files can be opened into directly,
+    /// so that later operations in the same chain can use them.
+    pub fn register_file_slots(&self, count: u32) -> rustix::io::Result<()> {
+        let empty = vec![-1i32; count as usize];
+        unsafe {
+            io_uring_register(
+                &self.fd,
+                IoringRegisterOp::RegisterFiles,
+                empty.as_ptr().cast(),
+                count,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Closes whatever was left open in the first `count` file slots.
+    pub fn clear_file_slots(&self, count: u32) -> rustix::io::Result<()> {
+        let empty = vec![-1i32; count as usize];
+        let mut update = io_uring_files_update::default();
+        update.fds = io_uring_ptr::new(empty.as_ptr().cast_mut().cast());
+        unsafe {
+            io_uring_register(
+                &self.fd,
+                IoringRegisterOp::RegisterFilesUpdate,
+                ptr::from_ref(&update).cast(),
+                count,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Queues an operation whose completion will be reported with its
+    /// `user_data`.
+    ///
+    /// # Safety
+    ///
+    /// Any memory referenced by the operation must stay valid until the
+    /// next call to [`Self::submit_and_wait`] returns.
+    pub unsafe fn push(&mut self, sqe: io_uring_sqe) {
+        assert!(self.queued < self.capacity(), "Submission queue overflow.");
+
+        let off = self.params.sq_off;
+        let tail = unsafe { &*self.rings.at::<AtomicU32>(off.tail) }.load(Ordering::Relaxed);
+        let index = tail & unsafe { *self.rings.at::<u32>(off.ring_mask) };
+        unsafe {
+            self.sqes
+                .at::<io_uring_sqe>(0)
+                .add(index as usize)
+                .write(sqe);
+            self.rings
+                .at::<u32>(off.array)
+                .add(index as usize)
+                .write(index);
+            (*self.rings.at::<AtomicU32>(off.tail)).store(tail.wrapping_add(1), Ordering::Release);
+        }
+        self.queued += 1;
+    }
+
+    /// Submits all queued operations and waits for every one of them to
+    /// complete, passing each completion's user data and result to
+    /// `on_complete`.
+    ///
+    /// If submitting fails, the operations which didn't make it in are
+    /// dropped and the ones which did are still waited for, so nothing is in
+    /// flight once this returns either way.
+    pub fn submit_and_wait(
+        &mut self,
+        mut on_complete: impl FnMut(u64, i32),
+    ) -> rustix::io::Result<()> {
+        let off = self.params.cq_off;
+        let mut to_submit = self.queued;
+        let mut remaining = self.queued;
+        self.queued = 0;
+        let mut result = Ok(());
+        while remaining > 0 {
+            match unsafe { io_uring_enter(&self.fd, to_submit, 1, IoringEnterFlags::GETEVENTS) } {
+                Ok(submitted) => to_submit -= submitted,
+                Err(Errno::INTR | Errno::AGAIN | Errno::BUSY) => {}
+                Err(e) if to_submit > 0 => {
+                    self.drop_unsubmitted();
+                    remaining -= to_submit;
+                    to_submit = 0;
+                    result = Err(e);
+                }
+                // The kernel may still be using the memory the submitted operations
+                // point to, so there's nothing to do but keep waiting for them.
+                Err(_) => {}
+            }
+
+            let head = unsafe { &*self.rings.at::<AtomicU32>(off.head) };
+            let tail = unsafe { &*self.rings.at::<AtomicU32>(off.tail) }.load(Ordering::Acquire);
+            let mask = unsafe { *self.rings.at::<u32>(off.ring_mask) };
+            let mut current = head.load(Ordering::Relaxed);
+            while current != tail {
+                let cqe = unsafe {
+                    &*self
+                        .rings
+                        .at::<io_uring_cqe>(off.cqes)
+                        .add((current & mask) as usize)
+                };
+                on_complete(cqe.user_data.u64_(), cqe.res);
+                current = current.wrapping_add(1);
+                remaining -= 1;
+            }
+            head.store(current, Ordering::Release);
+        }
+        result
+    }
+
+    /// Takes back the queued operations which the kernel hasn't picked up.
+    fn drop_unsubmitted(&self) {
+        let off = self.params.sq_off;
+        let head = unsafe { &*self.rings.at::<AtomicU32>(off.head) }.load(Ordering::Acquire);
+        unsafe { &*self.rings.at::<AtomicU32>(off.tail) }.store(head, Ordering::Release);
+    }
+}
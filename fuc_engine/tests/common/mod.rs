@@ -0,0 +1,38 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+/// A tmpfs mounted for the duration of a test.
+pub struct Tmpfs(PathBuf);
+
+impl Tmpfs {
+    /// Returns `None` without the privileges to mount file systems.
+    pub fn mount(path: &Path) -> Option<Self> {
+        fs::create_dir_all(path).unwrap();
+        Command::new("mount")
+            .args(["-t", "tmpfs", "tmpfs"])
+            .arg(path)
+            .stderr(Stdio::null())
+            .status()
+            .ok()?
+            .success()
+            .then(|| Self(path.to_path_buf()))
+    }
+}
+
+impl Drop for Tmpfs {
+    fn drop(&mut self) {
+        // Panicking while a failed test unwinds would abort the whole run, so a
+        // busy mount is detached lazily instead.
+        let unmounted = Command::new("umount")
+            .arg(&self.0)
+            .stderr(Stdio::null())
+            .status()
+            .is_ok_and(|status| status.success());
+        if !unmounted {
+            let _ = Command::new("umount").arg("--lazy").arg(&self.0).status();
+        }
+    }
+}
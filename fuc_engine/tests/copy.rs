@@ -1,8 +1,32 @@
-use std::{borrow::Cow, fs, fs::File};
+use std::{
+    borrow::Cow,
+    fs,
+    fs::File,
+    io::Write,
+    num::{NonZeroU64, NonZeroUsize},
+    path::{Path, PathBuf},
+    process::Command,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
 
+use fuc_engine::{
+    CachePolicy, Dereference, EntryOrder, Error, IoPriority, Stats, SymlinkStyle, SymlinkTargets,
+    SyncMode,
+};
 use rstest::rstest;
 use tempfile::tempdir;
 
+#[cfg(target_os = "linux")]
+mod common;
+
+#[cfg(target_os = "linux")]
+use common::Tmpfs;
+
 #[test]
 fn pre_existing_file_no_force() {
     let root = tempdir().unwrap();
@@ -234,7 +258,10 @@ fn preserve_directory_permissions_recursive() {
 
 #[rstest]
 #[cfg(unix)]
-fn dereference_symbolic_link_to_regular_file(#[values(false, true)] follow_symlinks: bool) {
+fn dereference_symbolic_link_to_regular_file(
+    #[values(Dereference::Never, Dereference::Always, Dereference::CommandLine)]
+    dereference: Dereference,
+) {
     let root = tempdir().unwrap();
     let from = root.path().join("from");
     File::create(from).unwrap();
@@ -244,21 +271,24 @@ fn dereference_symbolic_link_to_regular_file(#[values(false, true)] follow_symli
 
     fuc_engine::CopyOp::builder()
         .files([(Cow::Owned(link), Cow::Borrowed(to.as_path()))])
-        .follow_symlinks(follow_symlinks)
+        .dereference(dereference)
         .build()
         .run()
         .unwrap();
 
-    if follow_symlinks {
-        assert!(to.symlink_metadata().unwrap().is_file());
-    } else {
+    if dereference == Dereference::Never {
         assert!(to.symlink_metadata().unwrap().is_symlink());
+    } else {
+        assert!(to.symlink_metadata().unwrap().is_file());
     }
 }
 
 #[rstest]
 #[cfg(unix)]
-fn dereference_symbolic_link_to_regular_file_in_dir(#[values(false, true)] follow_symlinks: bool) {
+fn dereference_symbolic_link_to_regular_file_in_dir(
+    #[values(Dereference::Never, Dereference::Always, Dereference::CommandLine)]
+    dereference: Dereference,
+) {
     let root = tempdir().unwrap();
     let from = root.path().join("from");
     fs::create_dir(&from).unwrap();
@@ -268,13 +298,13 @@ fn dereference_symbolic_link_to_regular_file_in_dir(#[values(false, true)] follo
 
     fuc_engine::CopyOp::builder()
         .files([(Cow::Owned(from), Cow::Borrowed(to.as_path()))])
-        .follow_symlinks(follow_symlinks)
+        .dereference(dereference)
         .build()
         .run()
         .unwrap();
 
     assert!(to.join("file").symlink_metadata().unwrap().is_file());
-    if follow_symlinks {
+    if dereference == Dereference::Always {
         assert!(to.join("link").symlink_metadata().unwrap().is_file());
     } else {
         assert!(to.join("link").symlink_metadata().unwrap().is_symlink());
@@ -283,7 +313,10 @@ fn dereference_symbolic_link_to_regular_file_in_dir(#[values(false, true)] follo
 
 #[rstest]
 #[cfg(unix)]
-fn dereference_symbolic_link_to_dir_in_dir(#[values(false, true)] follow_symlinks: bool) {
+fn dereference_symbolic_link_to_dir_in_dir(
+    #[values(Dereference::Never, Dereference::Always, Dereference::CommandLine)]
+    dereference: Dereference,
+) {
     let root = tempdir().unwrap();
     let from = root.path().join("from");
     fs::create_dir(&from).unwrap();
@@ -294,14 +327,14 @@ fn dereference_symbolic_link_to_dir_in_dir(#[values(false, true)] follow_symlink
 
     fuc_engine::CopyOp::builder()
         .files([(Cow::Owned(from), Cow::Borrowed(to.as_path()))])
-        .follow_symlinks(follow_symlinks)
+        .dereference(dereference)
         .build()
         .run()
         .unwrap();
 
     assert!(to.join("subdir").symlink_metadata().unwrap().is_dir());
     assert!(to.join("subdir/file").symlink_metadata().unwrap().is_file());
-    if follow_symlinks {
+    if dereference == Dereference::Always {
         assert!(to.join("subdirlink").symlink_metadata().unwrap().is_dir());
         assert!(
             to.join("subdirlink/file")
@@ -318,3 +351,1314 @@ fn dereference_symbolic_link_to_dir_in_dir(#[values(false, true)] follow_symlink
         );
     }
 }
+
+#[test]
+fn one_file_system_same_device() {
+    let root = tempdir().unwrap();
+    let from = root.path().join("from");
+    fs::create_dir_all(from.join("a/b")).unwrap();
+    File::create(from.join("a/b/file")).unwrap();
+    let to = root.path().join("to");
+
+    fuc_engine::CopyOp::builder()
+        .files([(Cow::Owned(from), Cow::Borrowed(to.as_path()))])
+        .one_file_system(true)
+        .build()
+        .run()
+        .unwrap();
+
+    assert!(to.join("a/b/file").exists());
+}
+
+#[rstest]
+#[cfg(target_os = "linux")]
+fn one_file_system_mount_point(#[values(false, true)] one_file_system: bool) {
+    let root = tempdir().unwrap();
+    let from = root.path().join("from");
+    fs::create_dir_all(from.join("a")).unwrap();
+    File::create(from.join("a/file")).unwrap();
+    let Some(_mount) = Tmpfs::mount(&from.join("mnt")) else {
+        eprintln!("Skipping test: can't mount a tmpfs.");
+        return;
+    };
+    File::create(from.join("mnt/file")).unwrap();
+    let to = root.path().join("to");
+
+    fuc_engine::CopyOp::builder()
+        .files([(Cow::Borrowed(from.as_path()), Cow::Borrowed(to.as_path()))])
+        .one_file_system(one_file_system)
+        .build()
+        .run()
+        .unwrap();
+
+    assert!(to.join("a/file").exists());
+    assert!(to.join("mnt").is_dir());
+    assert_eq!(to.join("mnt/file").exists(), !one_file_system);
+}
+
+#[test]
+#[cfg(unix)]
+fn read_only_dirs() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let root = tempdir().unwrap();
+    let from = root.path().join("from");
+    fs::create_dir_all(from.join("a/b")).unwrap();
+    File::create(from.join("a/file")).unwrap();
+    File::create(from.join("a/b/file")).unwrap();
+    for path in ["a/b", "a", ""] {
+        fs::set_permissions(from.join(path), fs::Permissions::from_mode(0o555)).unwrap();
+    }
+    let to = root.path().join("to");
+
+    fuc_engine::copy_file(&from, &to).unwrap();
+
+    assert!(to.join("a/file").exists());
+    assert!(to.join("a/b/file").exists());
+    for path in ["a/b", "a", ""] {
+        let metadata = fs::metadata(to.join(path)).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o555);
+    }
+
+    for path in ["", "a", "a/b"] {
+        fs::set_permissions(to.join(path), fs::Permissions::from_mode(0o755)).unwrap();
+        fs::set_permissions(from.join(path), fs::Permissions::from_mode(0o755)).unwrap();
+    }
+}
+
+#[test]
+#[cfg(unix)]
+fn exact_mode() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let root = tempdir().unwrap();
+    let from = root.path().join("from");
+    fs::create_dir_all(from.join("sticky")).unwrap();
+    fs::write(from.join("setuid"), "contents").unwrap();
+    fs::write(from.join("sticky/writable"), "contents").unwrap();
+    fs::set_permissions(from.join("setuid"), fs::Permissions::from_mode(0o4755)).unwrap();
+    fs::set_permissions(
+        from.join("sticky/writable"),
+        fs::Permissions::from_mode(0o666),
+    )
+    .unwrap();
+    fs::set_permissions(from.join("sticky"), fs::Permissions::from_mode(0o1777)).unwrap();
+    let to = root.path().join("to");
+
+    fuc_engine::CopyOp::builder()
+        .files([(Cow::Owned(from), Cow::Borrowed(to.as_path()))])
+        .preserve_mode(fuc_engine::PreserveMode::Exact)
+        .build()
+        .run()
+        .unwrap();
+
+    for (path, mode) in [
+        ("setuid", 0o4755),
+        ("sticky", 0o1777),
+        ("sticky/writable", 0o666),
+    ] {
+        let metadata = fs::symlink_metadata(to.join(path)).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o7777, mode, "{path}");
+    }
+    assert_eq!(fs::read(to.join("setuid")).unwrap(), b"contents");
+}
+
+#[test]
+#[cfg(unix)]
+fn ignore_mode() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let root = tempdir().unwrap();
+    let from = root.path().join("from");
+    fs::create_dir_all(from.join("dir")).unwrap();
+    fs::write(from.join("dir/file"), "contents").unwrap();
+    fs::set_permissions(from.join("dir/file"), fs::Permissions::from_mode(0o700)).unwrap();
+    fs::set_permissions(from.join("dir"), fs::Permissions::from_mode(0o700)).unwrap();
+    let to = root.path().join("to");
+
+    fuc_engine::CopyOp::builder()
+        .files([(Cow::Owned(from), Cow::Borrowed(to.as_path()))])
+        .preserve_mode(fuc_engine::PreserveMode::Ignore)
+        .build()
+        .run()
+        .unwrap();
+
+    let dir = fs::metadata(to.join("dir")).unwrap().permissions().mode();
+    let file = fs::metadata(to.join("dir/file"))
+        .unwrap()
+        .permissions()
+        .mode();
+    assert_ne!(dir & 0o777, 0o700);
+    assert_eq!(file & 0o111, 0);
+    assert_eq!(fs::read(to.join("dir/file")).unwrap(), b"contents");
+}
+
+#[rstest]
+#[cfg(unix)]
+fn dereference_command_line_dir(#[values(false, true)] hard_link: bool) {
+    let root = tempdir().unwrap();
+    let from = root.path().join("from");
+    fs::create_dir(&from).unwrap();
+    File::create(from.join("file")).unwrap();
+    std::os::unix::fs::symlink("file", from.join("link")).unwrap();
+    let link = root.path().join("dirlink");
+    std::os::unix::fs::symlink("from", &link).unwrap();
+    let to = root.path().join("to");
+
+    fuc_engine::CopyOp::builder()
+        .files([(Cow::Owned(link), Cow::Borrowed(to.as_path()))])
+        .dereference(Dereference::CommandLine)
+        .hard_link(hard_link)
+        .build()
+        .run()
+        .unwrap();
+
+    assert!(to.symlink_metadata().unwrap().is_dir());
+    assert!(to.join("file").symlink_metadata().unwrap().is_file());
+    assert!(to.join("link").symlink_metadata().unwrap().is_symlink());
+}
+
+#[rstest]
+#[cfg(unix)]
+fn dangling_symlinks(#[values(false, true)] copy_dangling_symlinks: bool) {
+    let root = tempdir().unwrap();
+    let from = root.path().join("from");
+    fs::create_dir(&from).unwrap();
+    std::os::unix::fs::symlink("nowhere", from.join("dangling")).unwrap();
+    let to = root.path().join("to");
+
+    let result = fuc_engine::CopyOp::builder()
+        .files([(Cow::Owned(from), Cow::Borrowed(to.as_path()))])
+        .dereference(Dereference::Always)
+        .copy_dangling_symlinks(copy_dangling_symlinks)
+        .build()
+        .run();
+
+    if copy_dangling_symlinks {
+        result.unwrap();
+        assert_eq!(
+            fs::read_link(to.join("dangling")).unwrap(),
+            Path::new("nowhere")
+        );
+    } else {
+        result.unwrap_err();
+    }
+}
+
+#[rstest]
+#[cfg(unix)]
+fn hard_link_symlinks(
+    #[values(Dereference::Never, Dereference::Always)] dereference: Dereference,
+    #[values(false, true)] in_dir: bool,
+) {
+    use std::os::unix::fs::MetadataExt;
+
+    let root = tempdir().unwrap();
+    let from = root.path().join("from");
+    fs::create_dir(&from).unwrap();
+    File::create(from.join("file")).unwrap();
+    std::os::unix::fs::symlink("file", from.join("link")).unwrap();
+    let (from, to) = if in_dir {
+        (from, root.path().join("to"))
+    } else {
+        (from.join("link"), root.path().join("link"))
+    };
+
+    fuc_engine::CopyOp::builder()
+        .files([(Cow::Owned(from.clone()), Cow::Borrowed(to.as_path()))])
+        .dereference(dereference)
+        .hard_link(true)
+        .build()
+        .run()
+        .unwrap();
+
+    let (from, to) = if in_dir {
+        (from.join("link"), to.join("link"))
+    } else {
+        (from, to)
+    };
+    let expected = if dereference == Dereference::Always {
+        fs::metadata(&from).unwrap().ino()
+    } else {
+        fs::symlink_metadata(&from).unwrap().ino()
+    };
+    assert_eq!(fs::symlink_metadata(&to).unwrap().ino(), expected);
+}
+
+#[rstest]
+#[cfg(unix)]
+fn relocate_symlinks(
+    #[values(
+        SymlinkTargets::Verbatim,
+        SymlinkTargets::Relocate,
+        SymlinkTargets::RelocateRelative
+    )]
+    symlink_targets: SymlinkTargets,
+) {
+    let root = tempdir().unwrap();
+    let from = root.path().join("from");
+    fs::create_dir_all(from.join("a/b")).unwrap();
+    File::create(from.join("file")).unwrap();
+    std::os::unix::fs::symlink(from.join("file"), from.join("a/b/absolute")).unwrap();
+    std::os::unix::fs::symlink("../../../from/file", from.join("a/b/escaping")).unwrap();
+    std::os::unix::fs::symlink("../../file", from.join("a/b/relative")).unwrap();
+    std::os::unix::fs::symlink(root.path(), from.join("a/outside")).unwrap();
+    let to = root.path().join("to");
+
+    fuc_engine::CopyOp::builder()
+        .files([(Cow::Borrowed(from.as_path()), Cow::Borrowed(to.as_path()))])
+        .symlink_targets(symlink_targets)
+        .build()
+        .run()
+        .unwrap();
+
+    let expected: [(_, PathBuf); 4] = match symlink_targets {
+        SymlinkTargets::Verbatim => [
+            ("a/b/absolute", from.join("file")),
+            ("a/b/escaping", "../../../from/file".into()),
+            ("a/b/relative", "../../file".into()),
+            ("a/outside", root.path().into()),
+        ],
+        SymlinkTargets::Relocate => [
+            ("a/b/absolute", to.join("file")),
+            ("a/b/escaping", "../../file".into()),
+            ("a/b/relative", "../../file".into()),
+            ("a/outside", root.path().into()),
+        ],
+        SymlinkTargets::RelocateRelative => [
+            ("a/b/absolute", "../../file".into()),
+            ("a/b/escaping", "../../file".into()),
+            ("a/b/relative", "../../file".into()),
+            ("a/outside", root.path().into()),
+        ],
+    };
+    for (link, target) in expected {
+        assert_eq!(fs::read_link(to.join(link)).unwrap(), target, "{link}");
+    }
+}
+
+#[rstest]
+#[cfg(unix)]
+fn symbolic_link_farm(
+    #[values(SymlinkStyle::Absolute, SymlinkStyle::Relative)] style: SymlinkStyle,
+) {
+    let root = tempdir().unwrap();
+    let from = root.path().join("from");
+    fs::create_dir_all(from.join("a")).unwrap();
+    fs::write(from.join("a/file"), "contents").unwrap();
+    let to = root.path().join("to");
+
+    fuc_engine::CopyOp::builder()
+        .files([(Cow::Borrowed(from.as_path()), Cow::Borrowed(to.as_path()))])
+        .symbolic_link(style)
+        .build()
+        .run()
+        .unwrap();
+
+    assert!(to.join("a").symlink_metadata().unwrap().is_dir());
+    let target = fs::read_link(to.join("a/file")).unwrap();
+    match style {
+        SymlinkStyle::Absolute => assert_eq!(target, from.join("a/file")),
+        SymlinkStyle::Relative => assert_eq!(target, Path::new("../../from/a/file")),
+    }
+    assert_eq!(fs::read(to.join("a/file")).unwrap(), b"contents");
+}
+
+#[rstest]
+#[cfg(target_os = "linux")]
+fn hard_link_fallback_to_copy(#[values(false, true)] fallback_to_copy: bool) {
+    use std::os::unix::fs::MetadataExt;
+
+    let root = tempdir().unwrap();
+    let Ok(other_root) = tempfile::tempdir_in("/dev/shm") else {
+        return;
+    };
+    if fs::metadata(root.path()).unwrap().dev() == fs::metadata(other_root.path()).unwrap().dev() {
+        return;
+    }
+    let from = root.path().join("from");
+    fs::create_dir(&from).unwrap();
+    fs::write(from.join("file"), "contents").unwrap();
+    let to = other_root.path().join("to");
+
+    let result = fuc_engine::CopyOp::builder()
+        .files([(Cow::Borrowed(from.as_path()), Cow::Borrowed(to.as_path()))])
+        .hard_link(true)
+        .fallback_to_copy(fallback_to_copy)
+        .build()
+        .run();
+
+    if fallback_to_copy {
+        result.unwrap();
+        assert_eq!(fs::read(to.join("file")).unwrap(), b"contents");
+    } else {
+        result.unwrap_err();
+    }
+}
+
+#[test]
+#[cfg(unix)]
+fn link_dest_snapshots() {
+    use std::os::unix::fs::MetadataExt;
+
+    let root = tempdir().unwrap();
+    let from = root.path().join("from");
+    fs::create_dir_all(from.join("dir")).unwrap();
+    fs::write(from.join("dir/unchanged"), "contents").unwrap();
+    fs::write(from.join("dir/changed"), "contents").unwrap();
+    let first = root.path().join("first");
+    let second = root.path().join("second");
+
+    // A missing snapshot directory means everything gets copied.
+    fuc_engine::CopyOp::builder()
+        .files([(
+            Cow::Borrowed(from.as_path()),
+            Cow::Borrowed(first.as_path()),
+        )])
+        .link_dest(vec![root.path().join("missing")])
+        .build()
+        .run()
+        .unwrap();
+    fs::write(from.join("dir/changed"), "new contents").unwrap();
+    fuc_engine::CopyOp::builder()
+        .files([(
+            Cow::Borrowed(from.as_path()),
+            Cow::Borrowed(second.as_path()),
+        )])
+        .link_dest(vec![first.clone()])
+        .build()
+        .run()
+        .unwrap();
+
+    let ino = |path: PathBuf| fs::metadata(path).unwrap().ino();
+    assert_eq!(
+        ino(first.join("dir/unchanged")),
+        ino(second.join("dir/unchanged"))
+    );
+    assert_ne!(
+        ino(first.join("dir/changed")),
+        ino(second.join("dir/changed"))
+    );
+    assert_ne!(
+        ino(from.join("dir/unchanged")),
+        ino(second.join("dir/unchanged"))
+    );
+    assert_eq!(
+        fs::read(second.join("dir/changed")).unwrap(),
+        b"new contents"
+    );
+}
+
+#[rstest]
+#[cfg(unix)]
+fn link_dest_chained_snapshots(#[values(false, true)] preserve_mtime: bool) {
+    use std::os::unix::fs::MetadataExt;
+
+    let root = tempdir().unwrap();
+    let from = root.path().join("from");
+    fs::create_dir_all(from.join("dir")).unwrap();
+    fs::write(from.join("dir/file"), "contents").unwrap();
+    File::options()
+        .write(true)
+        .open(from.join("dir/file"))
+        .unwrap()
+        .set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(1 << 30))
+        .unwrap();
+    let snapshots = ["first", "second", "third"].map(|name| root.path().join(name));
+
+    // The first snapshot is a plain copy, so nothing but the option keeps the
+    // modification times the next snapshot matches against.
+    fuc_engine::CopyOp::builder()
+        .files([(
+            Cow::Borrowed(from.as_path()),
+            Cow::Borrowed(snapshots[0].as_path()),
+        )])
+        .preserve_mtime(preserve_mtime)
+        .build()
+        .run()
+        .unwrap();
+    for previous in snapshots.windows(2) {
+        fuc_engine::CopyOp::builder()
+            .files([(
+                Cow::Borrowed(from.as_path()),
+                Cow::Borrowed(previous[1].as_path()),
+            )])
+            .link_dest(vec![previous[0].clone()])
+            .build()
+            .run()
+            .unwrap();
+    }
+
+    let ino = |path: &Path| fs::metadata(path.join("dir/file")).unwrap().ino();
+    assert_eq!(ino(&snapshots[0]) == ino(&snapshots[1]), preserve_mtime);
+    // Snapshots made with --link-dest can always be linked against.
+    assert_eq!(ino(&snapshots[1]), ino(&snapshots[2]));
+    assert_ne!(ino(&from), ino(&snapshots[2]));
+}
+
+#[rstest]
+#[cfg(unix)]
+fn large_flat_dir(#[values(false, true)] hard_link: bool) {
+    use std::os::unix::fs::PermissionsExt;
+
+    let root = tempdir().unwrap();
+    let from = root.path().join("from");
+    fs::create_dir_all(from.join("dir")).unwrap();
+    for i in 0..5000 {
+        fs::write(from.join(format!("dir/{i}")), i.to_string()).unwrap();
+    }
+    fs::set_permissions(from.join("dir"), fs::Permissions::from_mode(0o555)).unwrap();
+    let to = root.path().join("to");
+
+    fuc_engine::CopyOp::builder()
+        .files([(Cow::Borrowed(from.as_path()), Cow::Borrowed(to.as_path()))])
+        .hard_link(hard_link)
+        .build()
+        .run()
+        .unwrap();
+
+    for i in 0..5000 {
+        assert_eq!(
+            fs::read_to_string(to.join(format!("dir/{i}"))).unwrap(),
+            i.to_string()
+        );
+    }
+    let metadata = fs::metadata(to.join("dir")).unwrap();
+    assert_eq!(metadata.permissions().mode() & 0o777, 0o555);
+
+    fs::set_permissions(to.join("dir"), fs::Permissions::from_mode(0o755)).unwrap();
+    fs::set_permissions(from.join("dir"), fs::Permissions::from_mode(0o755)).unwrap();
+}
+
+#[test]
+fn large_file() {
+    let root = tempdir().unwrap();
+    let from = root.path().join("from");
+    let to = root.path().join("to");
+    let contents = (0..(129 << 20) + 12345)
+        .map(|i: u32| ((i / 4096) ^ i).to_le_bytes()[0])
+        .collect::<Vec<_>>();
+    fs::write(&from, &contents).unwrap();
+
+    fuc_engine::copy_file(&from, &to).unwrap();
+
+    assert!(fs::read(&to).unwrap() == contents);
+}
+
+#[rstest]
+fn large_files_in_dir(#[values(1, 4)] threads: usize) {
+    let root = tempdir().unwrap();
+    let from = root.path().join("from");
+    let to = root.path().join("to");
+    let contents = (0..(129 << 20) + 12345)
+        .map(|i: u32| ((i / 4096) ^ i).to_le_bytes()[0])
+        .collect::<Vec<_>>();
+    fs::create_dir(&from).unwrap();
+    fs::write(from.join("dense"), &contents).unwrap();
+    // Large enough to be split up if it weren't sparse.
+    File::create(from.join("sparse"))
+        .unwrap()
+        .set_len((160 << 20) - 4)
+        .unwrap();
+    File::options()
+        .append(true)
+        .open(from.join("sparse"))
+        .unwrap()
+        .write_all(b"tail")
+        .unwrap();
+
+    fuc_engine::CopyOp::builder()
+        .files([(Cow::Borrowed(from.as_path()), Cow::Borrowed(to.as_path()))])
+        .threads(NonZeroUsize::new(threads).unwrap())
+        .build()
+        .run()
+        .unwrap();
+
+    assert!(fs::read(to.join("dense")).unwrap() == contents);
+    let sparse = fs::read(to.join("sparse")).unwrap();
+    assert_eq!(sparse.len(), 160 << 20);
+    assert_eq!(&sparse[sparse.len() - 4..], b"tail");
+    assert!(sparse[..sparse.len() - 4].iter().all(|&b| b == 0));
+}
+
+#[test]
+#[cfg(unix)]
+fn small_files() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let root = tempdir().unwrap();
+    let from = root.path().join("from");
+    let to = root.path().join("to");
+    let mode = |i| if i % 2 == 0 { 0o600 } else { 0o644 };
+    fs::create_dir(&from).unwrap();
+    for i in 0..100 {
+        let file = from.join(i.to_string());
+        fs::write(&file, i.to_string().repeat(i)).unwrap();
+        fs::set_permissions(&file, fs::Permissions::from_mode(mode(i))).unwrap();
+    }
+    let large = (0..100_000u32)
+        .map(|i| i.to_le_bytes()[0])
+        .collect::<Vec<_>>();
+    fs::write(from.join("large"), &large).unwrap();
+
+    fuc_engine::CopyOp::builder()
+        .files([(Cow::Borrowed(from.as_path()), Cow::Borrowed(to.as_path()))])
+        .build()
+        .run()
+        .unwrap();
+
+    for i in 0..100 {
+        let file = to.join(i.to_string());
+        assert_eq!(fs::read_to_string(&file).unwrap(), i.to_string().repeat(i));
+        assert_eq!(
+            fs::metadata(&file).unwrap().permissions().mode() & 0o777,
+            mode(i)
+        );
+    }
+    assert!(fs::read(to.join("large")).unwrap() == large);
+}
+
+#[rstest]
+#[cfg(all(unix, feature = "io_uring"))]
+fn io_uring_batches(
+    #[values(false, true)] io_uring: bool,
+    #[values(Dereference::Never, Dereference::Always)] dereference: Dereference,
+    // Up to and past a full batch, and enough to be split into file batches.
+    #[values(1, 32, 33, 2000)] files: usize,
+) {
+    use std::os::unix::fs::{PermissionsExt, symlink};
+
+    let root = tempdir().unwrap();
+    let from = root.path().join("from");
+    let to = root.path().join("to");
+    let mode = |i| if i % 2 == 0 { 0o600 } else { 0o644 };
+    fs::create_dir(&from).unwrap();
+    for i in 0..files {
+        let file = from.join(i.to_string());
+        fs::write(&file, i.to_string().repeat(i % 100)).unwrap();
+        fs::set_permissions(&file, fs::Permissions::from_mode(mode(i))).unwrap();
+    }
+    // Too large to be batched.
+    let large = (0..100_000u32)
+        .map(|i| i.to_le_bytes()[0])
+        .collect::<Vec<_>>();
+    fs::write(from.join("large"), &large).unwrap();
+    fs::write(from.join("small"), "small").unwrap();
+    symlink("large", from.join("large_link")).unwrap();
+    symlink("small", from.join("small_link")).unwrap();
+
+    fuc_engine::CopyOp::builder()
+        .files([(Cow::Borrowed(from.as_path()), Cow::Borrowed(to.as_path()))])
+        .dereference(dereference)
+        .io_uring(io_uring)
+        .build()
+        .run()
+        .unwrap();
+
+    for i in 0..files {
+        let file = to.join(i.to_string());
+        assert_eq!(
+            fs::read_to_string(&file).unwrap(),
+            i.to_string().repeat(i % 100)
+        );
+        assert_eq!(
+            fs::metadata(&file).unwrap().permissions().mode() & 0o777,
+            mode(i)
+        );
+    }
+    assert!(fs::read(to.join("large")).unwrap() == large);
+    for link in ["large_link", "small_link"] {
+        assert_eq!(
+            to.join(link).is_symlink(),
+            dereference == Dereference::Never
+        );
+    }
+    assert!(fs::read(to.join("large_link")).unwrap() == large);
+    assert_eq!(fs::read_to_string(to.join("small_link")).unwrap(), "small");
+}
+
+#[rstest]
+fn cache_policies(
+    #[values(CachePolicy::Normal, CachePolicy::DropBehind, CachePolicy::Direct)]
+    cache_policy: CachePolicy,
+) {
+    let root = tempdir().unwrap();
+    let from = root.path().join("from");
+    let to = root.path().join("to");
+    let contents = (0..(20 << 20) + 12345u32)
+        .map(|i| ((i / 4096) ^ i).to_le_bytes()[0])
+        .collect::<Vec<_>>();
+    fs::create_dir(&from).unwrap();
+    fs::write(from.join("large"), &contents).unwrap();
+    fs::write(from.join("small"), "small").unwrap();
+    fs::write(root.path().join("file"), &contents).unwrap();
+
+    fuc_engine::CopyOp::builder()
+        .files([
+            (Cow::Borrowed(from.as_path()), Cow::Borrowed(to.as_path())),
+            (
+                Cow::Owned(root.path().join("file")),
+                Cow::Owned(root.path().join("file-copy")),
+            ),
+        ])
+        .cache_policy(cache_policy)
+        .build()
+        .run()
+        .unwrap();
+
+    assert!(fs::read(to.join("large")).unwrap() == contents);
+    assert_eq!(fs::read_to_string(to.join("small")).unwrap(), "small");
+    assert!(fs::read(root.path().join("file-copy")).unwrap() == contents);
+}
+
+#[rstest]
+fn sync_modes(
+    #[values(
+        SyncMode::None,
+        SyncMode::Files,
+        SyncMode::Directories,
+        SyncMode::Filesystem
+    )]
+    sync_mode: SyncMode,
+) {
+    let root = tempdir().unwrap();
+    let from = root.path().join("from");
+    let to = root.path().join("to");
+    fs::create_dir_all(from.join("a/b")).unwrap();
+    fs::write(from.join("file"), "file").unwrap();
+    fs::write(from.join("a/b/nested"), "nested").unwrap();
+    fs::write(root.path().join("top"), "top").unwrap();
+
+    fuc_engine::CopyOp::builder()
+        .files([
+            (Cow::Borrowed(from.as_path()), Cow::Borrowed(to.as_path())),
+            (
+                Cow::Owned(root.path().join("top")),
+                Cow::Owned(root.path().join("top-copy")),
+            ),
+        ])
+        .sync_mode(sync_mode)
+        .build()
+        .run()
+        .unwrap();
+
+    assert_eq!(fs::read_to_string(to.join("file")).unwrap(), "file");
+    assert_eq!(fs::read_to_string(to.join("a/b/nested")).unwrap(), "nested");
+    assert_eq!(
+        fs::read_to_string(root.path().join("top-copy")).unwrap(),
+        "top"
+    );
+}
+
+#[rstest]
+fn preallocated(#[values(false, true)] preallocate: bool) {
+    let root = tempdir().unwrap();
+    let from = root.path().join("from");
+    let to = root.path().join("to");
+    let contents = (0..(1 << 20) + 123u32)
+        .map(|i| ((i / 4096) ^ i).to_le_bytes()[0])
+        .collect::<Vec<_>>();
+    fs::create_dir(&from).unwrap();
+    fs::write(from.join("dense"), &contents).unwrap();
+    fs::write(from.join("empty"), "").unwrap();
+    File::create(from.join("sparse"))
+        .unwrap()
+        .set_len((64 << 20) - 4)
+        .unwrap();
+    File::options()
+        .append(true)
+        .open(from.join("sparse"))
+        .unwrap()
+        .write_all(b"tail")
+        .unwrap();
+
+    fuc_engine::CopyOp::builder()
+        .files([(Cow::Borrowed(from.as_path()), Cow::Borrowed(to.as_path()))])
+        .preallocate(preallocate)
+        .build()
+        .run()
+        .unwrap();
+
+    assert!(fs::read(to.join("dense")).unwrap() == contents);
+    assert_eq!(
+        fs::metadata(to.join("dense")).unwrap().len(),
+        contents.len() as u64
+    );
+    assert!(fs::read(to.join("empty")).unwrap().is_empty());
+    let sparse = fs::read(to.join("sparse")).unwrap();
+    assert_eq!(sparse.len(), 64 << 20);
+    assert_eq!(&sparse[sparse.len() - 4..], b"tail");
+    assert!(sparse[..sparse.len() - 4].iter().all(|&b| b == 0));
+}
+
+#[test]
+fn small_file_sizes() {
+    let root = tempdir().unwrap();
+    let from = root.path().join("from");
+    let to = root.path().join("to");
+    let sizes = [0, 1, 4095, 4096, 16383, 16384, 16385, 100_000];
+    fs::create_dir(&from).unwrap();
+    for size in sizes {
+        fs::write(from.join(size.to_string()), "a".repeat(size)).unwrap();
+    }
+
+    fuc_engine::CopyOp::builder()
+        .files([(Cow::Borrowed(from.as_path()), Cow::Borrowed(to.as_path()))])
+        .build()
+        .run()
+        .unwrap();
+
+    for size in sizes {
+        assert_eq!(
+            fs::read_to_string(to.join(size.to_string())).unwrap(),
+            "a".repeat(size)
+        );
+    }
+}
+
+#[rstest]
+fn entry_orders(
+    #[values(
+        EntryOrder::Unordered,
+        EntryOrder::Inode,
+        EntryOrder::Physical,
+        EntryOrder::Auto
+    )]
+    entry_order: EntryOrder,
+) {
+    let root = tempdir().unwrap();
+    let from = root.path().join("from");
+    let to = root.path().join("to");
+    fs::create_dir_all(from.join("a/b")).unwrap();
+    for i in 0..2000 {
+        fs::write(from.join(i.to_string()), i.to_string().repeat(i % 7)).unwrap();
+    }
+    fs::write(from.join("a/b/nested"), "nested").unwrap();
+    fs::write(from.join("a/large"), "a".repeat(1 << 20)).unwrap();
+
+    fuc_engine::CopyOp::builder()
+        .files([(Cow::Borrowed(from.as_path()), Cow::Borrowed(to.as_path()))])
+        .entry_order(entry_order)
+        .build()
+        .run()
+        .unwrap();
+
+    for i in 0..2000 {
+        assert_eq!(
+            fs::read_to_string(to.join(i.to_string())).unwrap(),
+            i.to_string().repeat(i % 7)
+        );
+    }
+    assert_eq!(fs::read_to_string(to.join("a/b/nested")).unwrap(), "nested");
+    assert_eq!(
+        fs::read_to_string(to.join("a/large")).unwrap(),
+        "a".repeat(1 << 20)
+    );
+}
+
+#[rstest]
+fn tuned(
+    #[values(None, Some(1), Some(3))] threads: Option<usize>,
+    #[values(None, Some(1))] dir_buffer_size: Option<usize>,
+    #[values(false, true)] auto_tune: bool,
+) {
+    let root = tempdir().unwrap();
+    let from = root.path().join("from");
+    let to = root.path().join("to");
+    fs::create_dir_all(from.join("a/b")).unwrap();
+    for i in 0..2000 {
+        fs::write(from.join(i.to_string()), i.to_string()).unwrap();
+    }
+    fs::write(from.join("a/b/nested"), "nested").unwrap();
+
+    fuc_engine::CopyOp::builder()
+        .files([(Cow::Borrowed(from.as_path()), Cow::Borrowed(to.as_path()))])
+        .maybe_threads(threads.and_then(NonZeroUsize::new))
+        .maybe_dir_buffer_size(dir_buffer_size.and_then(NonZeroUsize::new))
+        .auto_tune(auto_tune)
+        .build()
+        .run()
+        .unwrap();
+
+    for i in 0..2000 {
+        assert_eq!(
+            fs::read_to_string(to.join(i.to_string())).unwrap(),
+            i.to_string()
+        );
+    }
+    assert_eq!(fs::read_to_string(to.join("a/b/nested")).unwrap(), "nested");
+}
+
+#[rstest]
+fn queue_memory_limits(#[values(None, Some(256), Some(16 << 10))] limit: Option<usize>) {
+    let root = tempdir().unwrap();
+    let from = root.path().join("from");
+    let to = root.path().join("to");
+    for i in 0..50 {
+        fs::create_dir_all(from.join(format!("{i}/nested"))).unwrap();
+        fs::write(from.join(format!("{i}/nested/file")), i.to_string()).unwrap();
+    }
+    for i in 0..2000 {
+        fs::write(from.join(format!("file{i}")), i.to_string()).unwrap();
+    }
+
+    let stats = Arc::new(Stats::default());
+    fuc_engine::CopyOp::builder()
+        .files([(Cow::Borrowed(from.as_path()), Cow::Borrowed(to.as_path()))])
+        .maybe_queue_memory_limit(limit.and_then(NonZeroUsize::new))
+        .stats(stats.clone())
+        .build()
+        .run()
+        .unwrap();
+
+    #[cfg(target_os = "linux")]
+    assert!(stats.peak_queued_bytes() > 0);
+    if let Some(limit) = limit {
+        assert!(stats.peak_queued_bytes() <= limit);
+    }
+
+    for i in 0..50 {
+        assert_eq!(
+            fs::read_to_string(to.join(format!("{i}/nested/file"))).unwrap(),
+            i.to_string()
+        );
+    }
+    for i in 0..2000 {
+        assert_eq!(
+            fs::read_to_string(to.join(format!("file{i}"))).unwrap(),
+            i.to_string()
+        );
+    }
+}
+
+#[test]
+fn queue_memory_limit_deep_tree() {
+    let root = tempdir().unwrap();
+    let from = root.path().join("from");
+    let to = root.path().join("to");
+    let mut deepest = PathBuf::new();
+    for i in 0..50 {
+        deepest.push(i.to_string());
+        for j in 0..3 {
+            fs::create_dir_all(from.join(&deepest).join(format!("sibling{j}"))).unwrap();
+        }
+    }
+    fs::write(from.join(&deepest).join("file"), "deep").unwrap();
+
+    fuc_engine::CopyOp::builder()
+        .files([(Cow::Borrowed(from.as_path()), Cow::Borrowed(to.as_path()))])
+        .queue_memory_limit(NonZeroUsize::new(1).unwrap())
+        .build()
+        .run()
+        .unwrap();
+
+    assert_eq!(
+        fs::read_to_string(to.join(&deepest).join("file")).unwrap(),
+        "deep"
+    );
+    for j in 0..3 {
+        assert!(to.join(&deepest).join(format!("sibling{j}")).is_dir());
+    }
+}
+
+#[test]
+fn many_roots_many_threads() {
+    let root = tempdir().unwrap();
+    let pairs = (0..4)
+        .map(|i| {
+            let from = root.path().join(format!("from{i}"));
+            for j in 0..100 {
+                fs::create_dir_all(from.join(format!("{j}/nested"))).unwrap();
+                fs::write(from.join(format!("{j}/nested/file")), j.to_string()).unwrap();
+            }
+            (from, root.path().join(format!("to{i}")))
+        })
+        .collect::<Vec<_>>();
+
+    fuc_engine::CopyOp::builder()
+        .files(
+            pairs
+                .iter()
+                .map(|(from, to)| (Cow::Borrowed(from.as_path()), Cow::Borrowed(to.as_path()))),
+        )
+        .threads(NonZeroUsize::new(8).unwrap())
+        .build()
+        .run()
+        .unwrap();
+
+    for (_, to) in pairs {
+        for j in 0..100 {
+            assert_eq!(
+                fs::read_to_string(to.join(format!("{j}/nested/file"))).unwrap(),
+                j.to_string()
+            );
+        }
+    }
+}
+
+#[rstest]
+fn throttled(
+    #[values(None, Some(IoPriority::BestEffort(7)), Some(IoPriority::Idle))] io_priority: Option<
+        IoPriority,
+    >,
+) {
+    let root = tempdir().unwrap();
+    let from = root.path().join("from");
+    let to = root.path().join("to");
+    fs::create_dir_all(from.join("nested")).unwrap();
+    for i in 0..8 {
+        fs::write(from.join(format!("nested/{i}")), vec![
+            u8::try_from(i)
+                .unwrap();
+            256 << 10
+        ])
+        .unwrap();
+    }
+    for i in 0..100 {
+        fs::write(from.join(format!("small{i}")), i.to_string()).unwrap();
+    }
+    let from_file = root.path().join("file");
+    let to_file = root.path().join("file_copy");
+    fs::write(&from_file, vec![42; 512 << 10]).unwrap();
+
+    let start = Instant::now();
+    fuc_engine::CopyOp::builder()
+        .files([
+            (Cow::Borrowed(from.as_path()), Cow::Borrowed(to.as_path())),
+            (
+                Cow::Borrowed(from_file.as_path()),
+                Cow::Borrowed(to_file.as_path()),
+            ),
+        ])
+        .threads(NonZeroUsize::new(4).unwrap())
+        .bandwidth_limit(NonZeroU64::new(4 << 20).unwrap())
+        .maybe_io_priority(io_priority)
+        .build()
+        .run()
+        .unwrap();
+
+    if cfg!(target_os = "linux") {
+        // 2.5 MiB at 4 MiB/s
+        assert!(start.elapsed() >= Duration::from_millis(500));
+    }
+    for i in 0..8 {
+        assert_eq!(
+            fs::read(to.join(format!("nested/{i}"))).unwrap(),
+            vec![u8::try_from(i).unwrap(); 256 << 10]
+        );
+    }
+    for i in 0..100 {
+        assert_eq!(
+            fs::read_to_string(to.join(format!("small{i}"))).unwrap(),
+            i.to_string()
+        );
+    }
+    assert_eq!(fs::read(&to_file).unwrap(), vec![42; 512 << 10]);
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn resumed() {
+    let root = tempdir().unwrap();
+    let from = root.path().join("from");
+    let to = root.path().join("to");
+    let journal = root.path().join("to.fuc-journal");
+    fs::create_dir_all(from.join("sub/nested")).unwrap();
+    fs::write(from.join("top"), "contents").unwrap();
+    fs::write(from.join("gone"), "contents").unwrap();
+    fs::write(from.join("sub/a"), "contents").unwrap();
+    fs::write(from.join("sub/nested/b"), "contents").unwrap();
+
+    // The missing root fails the copy after the first one was finished.
+    fuc_engine::CopyOp::builder()
+        .files([
+            (Cow::Borrowed(from.as_path()), Cow::Borrowed(to.as_path())),
+            (
+                Cow::Owned(root.path().join("missing")),
+                Cow::Owned(root.path().join("elsewhere")),
+            ),
+        ])
+        .journal(true)
+        .build()
+        .run()
+        .unwrap_err();
+    assert!(journal.exists());
+
+    fs::write(to.join("sub/a"), "untouched").unwrap();
+    fs::write(to.join("sub/nested/b"), "untouched").unwrap();
+    fs::remove_file(to.join("gone")).unwrap();
+    fs::write(from.join("top"), "new contents").unwrap();
+    fs::write(from.join("new"), "new").unwrap();
+    File::open(&from)
+        .unwrap()
+        .set_modified(std::time::SystemTime::UNIX_EPOCH)
+        .unwrap();
+
+    fuc_engine::CopyOp::builder()
+        .files([(Cow::Borrowed(from.as_path()), Cow::Borrowed(to.as_path()))])
+        .resume(true)
+        .build()
+        .run()
+        .unwrap();
+
+    assert!(!journal.exists());
+    assert_eq!(fs::read_to_string(to.join("sub/a")).unwrap(), "untouched");
+    assert_eq!(
+        fs::read_to_string(to.join("sub/nested/b")).unwrap(),
+        "untouched"
+    );
+    assert_eq!(fs::read_to_string(to.join("gone")).unwrap(), "contents");
+    assert_eq!(fs::read_to_string(to.join("top")).unwrap(), "new contents");
+    assert_eq!(fs::read_to_string(to.join("new")).unwrap(), "new");
+}
+
+#[test]
+#[cfg(unix)]
+fn transactional_rollback() {
+    let root = tempdir().unwrap();
+    let from = root.path().join("from");
+    let to = root.path().join("to");
+    fs::create_dir_all(from.join("sub")).unwrap();
+    fs::create_dir_all(from.join("new_dir/nested")).unwrap();
+    fs::write(from.join("existing"), "new contents").unwrap();
+    fs::write(from.join("new"), "contents").unwrap();
+    fs::write(from.join("sub/new"), "contents").unwrap();
+    fs::write(from.join("new_dir/nested/file"), "contents").unwrap();
+    std::os::unix::fs::symlink("missing", from.join("sub/broken")).unwrap();
+    fs::create_dir_all(to.join("sub")).unwrap();
+    fs::write(to.join("existing"), "contents").unwrap();
+    fs::write(to.join("sub/old"), "contents").unwrap();
+    let file = root.path().join("file");
+    fs::write(&file, "contents").unwrap();
+
+    fuc_engine::CopyOp::builder()
+        .files([
+            (Cow::Borrowed(from.as_path()), Cow::Borrowed(to.as_path())),
+            (
+                Cow::Borrowed(from.as_path()),
+                Cow::Owned(root.path().join("new_root")),
+            ),
+            (
+                Cow::Borrowed(file.as_path()),
+                Cow::Owned(root.path().join("new_file")),
+            ),
+        ])
+        .force(true)
+        .dereference(Dereference::Always)
+        .transactional(true)
+        .build()
+        .run()
+        .unwrap_err();
+
+    assert!(!root.path().join("new_root").exists());
+    assert!(!root.path().join("new_file").exists());
+    assert_eq!(names(&to), ["existing", "sub"]);
+    assert_eq!(names(&to.join("sub")), ["old"]);
+    assert_eq!(fs::read_to_string(to.join("sub/old")).unwrap(), "contents");
+}
+
+#[test]
+#[cfg(unix)]
+fn transactional_rollback_after_merge() {
+    let root = tempdir().unwrap();
+    let from = root.path().join("from");
+    let to = root.path().join("to");
+    fs::create_dir_all(from.join("sub/nested")).unwrap();
+    fs::write(from.join("existing"), "new contents").unwrap();
+    fs::write(from.join("sub/new"), "contents").unwrap();
+    fs::write(from.join("sub/nested/file"), "contents").unwrap();
+    fs::create_dir_all(to.join("sub")).unwrap();
+    fs::write(to.join("existing"), "contents").unwrap();
+    fs::write(to.join("sub/old"), "contents").unwrap();
+    let broken = root.path().join("broken");
+    std::os::unix::fs::symlink("missing", &broken).unwrap();
+
+    // The merge succeeds and only the copy after it fails.
+    fuc_engine::CopyOp::builder()
+        .files([
+            (Cow::Borrowed(from.as_path()), Cow::Borrowed(to.as_path())),
+            (
+                Cow::Borrowed(broken.as_path()),
+                Cow::Owned(root.path().join("new_file")),
+            ),
+        ])
+        .force(true)
+        .dereference(Dereference::Always)
+        .threads(NonZeroUsize::MIN)
+        .transactional(true)
+        .build()
+        .run()
+        .unwrap_err();
+
+    assert_eq!(names(root.path()), ["broken", "from", "to"]);
+    assert_eq!(names(&to), ["existing", "sub"]);
+    assert_eq!(names(&to.join("sub")), ["old"]);
+    // Overwritten files can't be restored.
+    assert_eq!(
+        fs::read_to_string(to.join("existing")).unwrap(),
+        "new contents"
+    );
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn transactional_rollback_failure() {
+    let root = tempdir().unwrap();
+    let from = root.path().join("from");
+    let to = root.path().join("to");
+    fs::create_dir(&from).unwrap();
+    let fifo = Fifo::new(&from.join("fifo"));
+    let broken = root.path().join("broken");
+    std::os::unix::fs::symlink("missing", &broken).unwrap();
+
+    let copy = {
+        let (from, to, broken) = (from.clone(), to.clone(), broken.clone());
+        let new_file = root.path().join("new_file");
+        thread::spawn(move || {
+            fuc_engine::CopyOp::builder()
+                .files([(from, to), (broken, new_file)])
+                .dereference(Dereference::Always)
+                .threads(NonZeroUsize::MIN)
+                .transactional(true)
+                .build()
+                .run()
+        })
+    };
+    // Rolling back can't remove a mount point, so mount one over the
+    // destination while the copy is stuck reading the FIFO.
+    let writer = fifo.wait_for_reader();
+    let mount = Tmpfs::mount(&to);
+    drop(writer);
+    let result = copy.join().unwrap();
+    let Some(_mount) = mount else {
+        eprintln!("Skipping test: can't mount file systems.");
+        return;
+    };
+
+    let Err(Error::Io { error, context }) = result else {
+        panic!("Expected an I/O error: {result:?}");
+    };
+    assert_eq!(error.kind(), std::io::ErrorKind::NotFound);
+    assert!(context.contains(&format!("{broken:?}")), "{context}");
+    assert!(context.contains("rolling back also failed"), "{context}");
+    assert!(to.exists());
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn transactional_cancel() {
+    let root = tempdir().unwrap();
+    let from = root.path().join("from");
+    let to = root.path().join("to");
+    fs::create_dir_all(from.join("sub")).unwrap();
+    fs::write(from.join("sub/file"), "contents").unwrap();
+    let fifo = Fifo::new(&from.join("fifo"));
+    let cancel = Arc::new(AtomicBool::new(false));
+
+    let copy = {
+        let (from, to, cancel) = (from.clone(), to.clone(), cancel.clone());
+        thread::spawn(move || {
+            fuc_engine::CopyOp::builder()
+                .files([(from, to)])
+                .threads(NonZeroUsize::MIN)
+                .transactional(true)
+                .cancel(cancel)
+                .build()
+                .run()
+        })
+    };
+    // The subdirectory is queued until the FIFO has been copied.
+    let writer = fifo.wait_for_reader();
+    cancel.store(true, Ordering::Relaxed);
+    drop(writer);
+
+    assert!(matches!(copy.join().unwrap(), Err(Error::Cancelled)));
+    assert!(!to.exists());
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn transactional_cancel_rollback_failure() {
+    let root = tempdir().unwrap();
+    let from = root.path().join("from");
+    let to = root.path().join("to");
+    fs::create_dir_all(from.join("sub")).unwrap();
+    let fifo = Fifo::new(&from.join("fifo"));
+    let cancel = Arc::new(AtomicBool::new(false));
+
+    let copy = {
+        let (from, to, cancel) = (from.clone(), to.clone(), cancel.clone());
+        thread::spawn(move || {
+            fuc_engine::CopyOp::builder()
+                .files([(from, to)])
+                .threads(NonZeroUsize::MIN)
+                .transactional(true)
+                .cancel(cancel)
+                .build()
+                .run()
+        })
+    };
+    // Cancel while the subdirectory is still queued and make rolling back
+    // fail by mounting over the destination.
+    let writer = fifo.wait_for_reader();
+    let mount = Tmpfs::mount(&to);
+    cancel.store(true, Ordering::Relaxed);
+    drop(writer);
+    let result = copy.join().unwrap();
+    let Some(_mount) = mount else {
+        eprintln!("Skipping test: can't mount file systems.");
+        return;
+    };
+
+    let Err(Error::RollbackFailed { error, failure }) = result else {
+        panic!("Expected a rollback failure: {result:?}");
+    };
+    assert!(matches!(*error, Error::Cancelled), "{error:?}");
+    assert!(failure.contains(&format!("{to:?}")), "{failure}");
+    assert!(to.exists());
+}
+
+#[test]
+fn cancel_before_start() {
+    let root = tempdir().unwrap();
+    let from = root.path().join("from");
+    fs::create_dir(&from).unwrap();
+    fs::write(from.join("file"), "contents").unwrap();
+    let to = root.path().join("to");
+
+    let result = fuc_engine::CopyOp::builder()
+        .files([(from.as_path(), to.as_path())])
+        .cancel(Arc::new(AtomicBool::new(true)))
+        .build()
+        .run();
+
+    assert!(matches!(result, Err(Error::Cancelled)));
+    assert!(!to.exists());
+}
+
+fn names(dir: &Path) -> Vec<String> {
+    let mut names = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect::<Vec<_>>();
+    names.sort();
+    names
+}
+
+/// A FIFO which holds up whoever copies it.
+#[cfg(target_os = "linux")]
+struct Fifo(PathBuf);
+
+#[cfg(target_os = "linux")]
+impl Fifo {
+    fn new(path: &Path) -> Self {
+        assert!(Command::new("mkfifo").arg(path).status().unwrap().success());
+        Self(path.to_path_buf())
+    }
+
+    /// Blocks until the copy opens the FIFO, which it then reads until the
+    /// returned writer is dropped.
+    fn wait_for_reader(&self) -> File {
+        File::options().write(true).open(&self.0).unwrap()
+    }
+}
@@ -1,10 +1,26 @@
-use std::{borrow::Cow, fs, fs::File, io, num::NonZeroU64};
+use std::{
+    borrow::Cow,
+    fs,
+    fs::File,
+    io,
+    num::{NonZeroU64, NonZeroUsize},
+    process::{Command, Stdio},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use ftzz::{Generator, NumFilesWithRatio};
+use fuc_engine::{EntryOrder, Error, IoPriority, Stats};
 use io_adapters::WriteExtension;
 use rstest::rstest;
 use tempfile::tempdir;
 
+#[cfg(target_os = "linux")]
+mod common;
+
+#[cfg(target_os = "linux")]
+use common::Tmpfs;
+
 #[test]
 fn non_existent_file_no_force() {
     let root = tempdir().unwrap();
@@ -139,3 +155,392 @@ fn uniform(#[values(1_000, 100_000)] num_files: u64) {
     assert!(!dir.exists());
     assert!(root.path().exists());
 }
+
+#[test]
+fn one_file_system_same_device() {
+    let root = tempdir().unwrap();
+    let dir = root.path().join("dir");
+    fs::create_dir_all(dir.join("a/b")).unwrap();
+    File::create(dir.join("a/b/file")).unwrap();
+
+    fuc_engine::RemoveOp::builder()
+        .files([Cow::Borrowed(dir.as_path())])
+        .one_file_system(true)
+        .build()
+        .run()
+        .unwrap();
+
+    assert!(!dir.exists());
+    assert!(root.path().exists());
+}
+
+#[rstest]
+#[cfg(target_os = "linux")]
+fn one_file_system_mount_point(#[values(false, true)] one_file_system: bool) {
+    let root = tempdir().unwrap();
+    let dir = root.path().join("dir");
+    fs::create_dir_all(dir.join("a")).unwrap();
+    File::create(dir.join("a/file")).unwrap();
+    let Some(_mount) = Tmpfs::mount(&dir.join("mnt")) else {
+        eprintln!("Skipping test: can't mount a tmpfs.");
+        return;
+    };
+    File::create(dir.join("mnt/file")).unwrap();
+
+    let result = fuc_engine::RemoveOp::builder()
+        .files([Cow::Borrowed(dir.as_path())])
+        .one_file_system(one_file_system)
+        .build()
+        .run();
+
+    assert!(
+        matches!(result, Err(Error::MountPoint { ref file }) if *file == dir.join("mnt")),
+        "{result:?}"
+    );
+    assert!(dir.join("mnt").is_dir());
+    // Without the option, everything but the mount point itself is gone.
+    assert_eq!(dir.join("mnt/file").exists(), one_file_system);
+}
+
+/// Whether we're subject to permission checks at all. Root (or anything else
+/// with `CAP_DAC_OVERRIDE`) can delete from read-only directories, so the
+/// permission fallbacks would never run.
+#[cfg(unix)]
+fn permissions_enforced() -> bool {
+    use std::os::unix::fs::PermissionsExt;
+
+    let root = tempdir().unwrap();
+    fs::set_permissions(root.path(), fs::Permissions::from_mode(0o555)).unwrap();
+    let enforced = File::create(root.path().join("file")).is_err();
+    fs::set_permissions(root.path(), fs::Permissions::from_mode(0o755)).unwrap();
+    enforced
+}
+
+#[test]
+#[cfg(unix)]
+fn force_permissions_read_only_dirs() {
+    use std::os::unix::fs::PermissionsExt;
+
+    if !permissions_enforced() {
+        eprintln!("Skipping test: permissions aren't enforced for this user.");
+        return;
+    }
+
+    let root = tempdir().unwrap();
+    let dir = root.path().join("dir");
+    fs::create_dir_all(dir.join("a/b")).unwrap();
+    File::create(dir.join("a/file")).unwrap();
+    File::create(dir.join("a/b/file")).unwrap();
+    for path in ["a/b", "a", ""] {
+        fs::set_permissions(dir.join(path), fs::Permissions::from_mode(0o555)).unwrap();
+    }
+
+    fuc_engine::RemoveOp::builder()
+        .files([Cow::Borrowed(dir.as_path())])
+        .force_permissions(true)
+        .build()
+        .run()
+        .unwrap();
+
+    assert!(!dir.exists());
+    assert!(root.path().exists());
+}
+
+#[rstest]
+#[cfg(target_os = "linux")]
+fn force_permissions_immutable(#[values(false, true)] clear_immutable: bool) {
+    use std::os::unix::fs::PermissionsExt;
+
+    let root = tempdir().unwrap();
+    let dir = root.path().join("dir");
+    fs::create_dir(&dir).unwrap();
+    let file = dir.join("file");
+    File::create(&file).unwrap();
+    if !Command::new("chattr")
+        .arg("+i")
+        .arg(&file)
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success())
+    {
+        eprintln!("Skipping test: files can't be made immutable.");
+        return;
+    }
+    fs::set_permissions(&dir, fs::Permissions::from_mode(0o555)).unwrap();
+
+    let result = fuc_engine::RemoveOp::builder()
+        .files([Cow::Borrowed(dir.as_path())])
+        .force_permissions(true)
+        .clear_immutable(clear_immutable)
+        .build()
+        .run();
+
+    if clear_immutable {
+        result.unwrap();
+        assert!(!dir.exists());
+    } else {
+        result.unwrap_err();
+        // Only the owner's missing permissions were added.
+        assert_eq!(
+            fs::metadata(&dir).unwrap().permissions().mode() & 0o777,
+            0o755
+        );
+        assert!(
+            Command::new("chattr")
+                .arg("-i")
+                .arg(&file)
+                .status()
+                .unwrap()
+                .success()
+        );
+    }
+    assert!(root.path().exists());
+}
+
+#[rstest]
+fn large_flat_dir(#[values(false, true)] force_permissions: bool) {
+    #[cfg(unix)]
+    if force_permissions && !permissions_enforced() {
+        eprintln!("Skipping test: permissions aren't enforced for this user.");
+        return;
+    }
+
+    let root = tempdir().unwrap();
+    let dir = root.path().join("dir");
+    fs::create_dir_all(dir.join("sub")).unwrap();
+    for i in 0..5000 {
+        File::create(dir.join(i.to_string())).unwrap();
+    }
+    File::create(dir.join("sub/file")).unwrap();
+    #[cfg(unix)]
+    if force_permissions {
+        use std::os::unix::fs::PermissionsExt;
+
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o555)).unwrap();
+    }
+
+    fuc_engine::RemoveOp::builder()
+        .files([Cow::Borrowed(dir.as_path())])
+        .force_permissions(force_permissions)
+        .build()
+        .run()
+        .unwrap();
+
+    assert!(!dir.exists());
+    assert!(root.path().exists());
+}
+
+#[rstest]
+#[cfg(feature = "io_uring")]
+fn io_uring_batches(
+    #[values(false, true)] io_uring: bool,
+    // Up to and past a full ring, and enough to be split into file batches.
+    #[values(1, 256, 257, 5000)] files: usize,
+) {
+    let root = tempdir().unwrap();
+    let dir = root.path().join("dir");
+    fs::create_dir_all(dir.join("sub")).unwrap();
+    for i in 0..files {
+        File::create(dir.join(i.to_string())).unwrap();
+    }
+    File::create(dir.join("sub/file")).unwrap();
+
+    fuc_engine::RemoveOp::builder()
+        .files([Cow::Borrowed(dir.as_path())])
+        .io_uring(io_uring)
+        .build()
+        .run()
+        .unwrap();
+
+    assert!(!dir.exists());
+    assert!(root.path().exists());
+}
+
+#[rstest]
+fn entry_orders(
+    #[values(
+        EntryOrder::Unordered,
+        EntryOrder::Inode,
+        EntryOrder::Physical,
+        EntryOrder::Auto
+    )]
+    entry_order: EntryOrder,
+) {
+    let root = tempdir().unwrap();
+    let dir = root.path().join("dir");
+    Generator::builder()
+        .root_dir(dir.clone())
+        .num_files_with_ratio(NumFilesWithRatio::from_num_files(
+            NonZeroU64::new(3000).unwrap(),
+        ))
+        .build()
+        .generate(&mut io::sink().write_adapter())
+        .unwrap();
+
+    fuc_engine::RemoveOp::builder()
+        .files([Cow::Borrowed(dir.as_path())])
+        .entry_order(entry_order)
+        .build()
+        .run()
+        .unwrap();
+
+    assert!(!dir.exists());
+    assert!(root.path().exists());
+}
+
+#[rstest]
+fn tuned(
+    #[values(None, Some(1), Some(3))] threads: Option<usize>,
+    #[values(None, Some(1))] dir_buffer_size: Option<usize>,
+    #[values(false, true)] auto_tune: bool,
+) {
+    let root = tempdir().unwrap();
+    let dir = root.path().join("dir");
+    Generator::builder()
+        .root_dir(dir.clone())
+        .num_files_with_ratio(NumFilesWithRatio::from_num_files(
+            NonZeroU64::new(3000).unwrap(),
+        ))
+        .build()
+        .generate(&mut io::sink().write_adapter())
+        .unwrap();
+
+    fuc_engine::RemoveOp::builder()
+        .files([Cow::Borrowed(dir.as_path())])
+        .maybe_threads(threads.and_then(NonZeroUsize::new))
+        .maybe_dir_buffer_size(dir_buffer_size.and_then(NonZeroUsize::new))
+        .auto_tune(auto_tune)
+        .build()
+        .run()
+        .unwrap();
+
+    assert!(!dir.exists());
+    assert!(root.path().exists());
+}
+
+#[rstest]
+fn queue_memory_limits(#[values(None, Some(256), Some(16 << 10))] limit: Option<usize>) {
+    let root = tempdir().unwrap();
+    let dir = root.path().join("dir");
+    Generator::builder()
+        .root_dir(dir.clone())
+        .num_files_with_ratio(NumFilesWithRatio::from_num_files(
+            NonZeroU64::new(3000).unwrap(),
+        ))
+        .build()
+        .generate(&mut io::sink().write_adapter())
+        .unwrap();
+    fs::create_dir(dir.join("wide")).unwrap();
+    for i in 0..2000 {
+        File::create(dir.join("wide").join(i.to_string())).unwrap();
+    }
+
+    let stats = Arc::new(Stats::default());
+    fuc_engine::RemoveOp::builder()
+        .files([Cow::Borrowed(dir.as_path())])
+        .maybe_queue_memory_limit(limit.and_then(NonZeroUsize::new))
+        .stats(stats.clone())
+        .build()
+        .run()
+        .unwrap();
+
+    #[cfg(target_os = "linux")]
+    assert!(stats.peak_queued_bytes() > 0);
+    if let Some(limit) = limit {
+        assert!(stats.peak_queued_bytes() <= limit);
+    }
+
+    assert!(!dir.exists());
+    assert!(root.path().exists());
+}
+
+#[test]
+fn queue_memory_limit_deep_tree() {
+    let root = tempdir().unwrap();
+    let dir = root.path().join("dir");
+    let mut deepest = dir.clone();
+    for i in 0..50 {
+        deepest.push(i.to_string());
+        for j in 0..3 {
+            fs::create_dir_all(deepest.join(format!("sibling{j}"))).unwrap();
+        }
+    }
+    File::create(deepest.join("file")).unwrap();
+
+    fuc_engine::RemoveOp::builder()
+        .files([Cow::Borrowed(dir.as_path())])
+        .queue_memory_limit(NonZeroUsize::new(1).unwrap())
+        .build()
+        .run()
+        .unwrap();
+
+    assert!(!dir.exists());
+    assert!(root.path().exists());
+}
+
+#[test]
+fn many_roots_many_threads() {
+    let root = tempdir().unwrap();
+    let dirs = (0..4)
+        .map(|i| {
+            let dir = root.path().join(i.to_string());
+            Generator::builder()
+                .root_dir(dir.clone())
+                .num_files_with_ratio(NumFilesWithRatio::from_num_files(
+                    NonZeroU64::new(2000).unwrap(),
+                ))
+                .build()
+                .generate(&mut io::sink().write_adapter())
+                .unwrap();
+            dir
+        })
+        .collect::<Vec<_>>();
+
+    fuc_engine::RemoveOp::builder()
+        .files(dirs.iter().map(|dir| Cow::Borrowed(dir.as_path())))
+        .threads(NonZeroUsize::new(8).unwrap())
+        .build()
+        .run()
+        .unwrap();
+
+    for dir in dirs {
+        assert!(!dir.exists());
+    }
+    assert!(root.path().exists());
+}
+
+#[rstest]
+fn throttled(
+    #[values(None, Some(IoPriority::BestEffort(7)), Some(IoPriority::Idle))] io_priority: Option<
+        IoPriority,
+    >,
+) {
+    let root = tempdir().unwrap();
+    let dir = root.path().join("dir");
+    for i in 0..10 {
+        fs::create_dir_all(dir.join(i.to_string())).unwrap();
+        for j in 0..100 {
+            File::create(dir.join(format!("{i}/{j}"))).unwrap();
+        }
+    }
+    let file = root.path().join("file");
+    File::create(&file).unwrap();
+
+    let start = Instant::now();
+    fuc_engine::RemoveOp::builder()
+        .files([Cow::Borrowed(dir.as_path()), Cow::Borrowed(file.as_path())])
+        .threads(NonZeroUsize::new(4).unwrap())
+        .rate_limit(NonZeroU64::new(2000).unwrap())
+        .maybe_io_priority(io_priority)
+        .build()
+        .run()
+        .unwrap();
+
+    if cfg!(target_os = "linux") {
+        // 1012 unlinks at 2000/s
+        assert!(start.elapsed() >= Duration::from_millis(400));
+    }
+    assert!(!dir.exists());
+    assert!(!file.exists());
+    assert!(root.path().exists());
+}
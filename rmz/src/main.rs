@@ -1,8 +1,11 @@
-use std::path::PathBuf;
+use std::{
+    num::{NonZeroU64, NonZeroUsize},
+    path::PathBuf,
+};
 
-use clap::{ArgAction, Parser, ValueHint};
+use clap::{ArgAction, Parser, ValueEnum, ValueHint};
 use error_stack::Report;
-use fuc_engine::{Error, RemoveOp};
+use fuc_engine::{EntryOrder, Error, IoPriority, RemoveOp};
 
 /// A zippy alternative to `rm`, a tool to remove files and directories
 #[derive(Parser, Debug)]
@@ -27,12 +30,133 @@ struct Rmz {
     #[arg(action = ArgAction::SetFalse)]
     preserve_root: bool,
 
+    /// Skip directories on other file systems than the one being removed
+    ///
+    /// Mount points are left in place and reported as an error once
+    /// everything else has been removed.
+    #[arg(long, default_value_t = false)]
+    one_file_system: bool,
+
+    /// Make read-only directories writable so their contents can be removed
+    #[arg(long, default_value_t = false)]
+    force_permissions: bool,
+
+    /// Also clear the immutable and append-only attributes of entries which
+    /// can't be removed otherwise
+    ///
+    /// Requires root or `CAP_LINUX_IMMUTABLE`. Only supported on Linux.
+    #[arg(long, default_value_t = false, requires = "force_permissions")]
+    clear_immutable: bool,
+
+    /// The order in which to remove the entries of each directory
+    ///
+    /// Sorting helps avoid seeking back and forth on spinning disks. Only
+    /// supported on Linux.
+    #[arg(long, value_enum, default_value_t = Order::Unordered)]
+    order: Order,
+
+    /// The number of threads to remove with
+    ///
+    /// Defaults to the number of CPUs. Only supported on Linux.
+    #[arg(long)]
+    threads: Option<NonZeroUsize>,
+
+    /// How many bytes of each directory to read at once
+    ///
+    /// Only supported on Linux.
+    #[arg(long)]
+    dir_buffer_size: Option<NonZeroUsize>,
+
+    /// Pick the thread count and buffer size based on the file system and
+    /// disk being removed
+    ///
+    /// Uses few threads for spinning disks and many for network file
+    /// systems. Explicit --threads and --dir-buffer-size values take
+    /// precedence. Only supported on Linux.
+    #[arg(long, default_value_t = false)]
+    auto_tune: bool,
+
+    /// Roughly how many bytes of directories waiting to be removed may be
+    /// queued
+    ///
+    /// Once reached, directories are removed as soon as they're found instead
+    /// of being queued, down to a fixed depth. Deeper directories and the
+    /// directories passed in are always queued. Unlimited by default. Only
+    /// supported on Linux.
+    #[arg(long, value_name = "BYTES")]
+    queue_memory_limit: Option<NonZeroUsize>,
+
+    /// The most files and directories to remove per second
+    ///
+    /// The limit is shared by all threads. Only supported on Linux.
+    #[arg(long, value_name = "PER_SEC")]
+    rate: Option<NonZeroU64>,
+
+    /// The I/O scheduling class to remove with, like `ionice`
+    ///
+    /// Only supported on Linux.
+    #[arg(long, value_enum)]
+    io_class: Option<IoClass>,
+
+    /// The priority within the I/O scheduling class, from 0 (highest) to 7
+    ///
+    /// Defaults to 4 and has no effect on the idle class.
+    #[arg(long, requires = "io_class")]
+    #[arg(value_parser = clap::value_parser!(u8).range(0..=7))]
+    io_level: Option<u8>,
+
+    /// Unlink files one syscall at a time instead of in `io_uring` batches
+    ///
+    /// Only has an effect on Linux builds with the `io_uring` feature.
+    #[arg(long, default_value_t = false)]
+    no_io_uring: bool,
+
     #[arg(short, long, short_alias = '?', global = true)]
     #[arg(action = ArgAction::Help, help = "Print help (use `--help` for more detail)")]
     #[arg(long_help = "Print help (use `-h` for a summary)")]
     help: Option<bool>,
 }
 
+#[derive(ValueEnum, Copy, Clone, Debug)]
+enum Order {
+    /// Use the order the file system lists entries in
+    Unordered,
+    /// Sort entries by inode number
+    Inode,
+    /// Sort entries by inode number on rotational disks
+    Auto,
+}
+
+impl From<Order> for EntryOrder {
+    fn from(order: Order) -> Self {
+        match order {
+            Order::Unordered => Self::Unordered,
+            Order::Inode => Self::Inode,
+            Order::Auto => Self::Auto,
+        }
+    }
+}
+
+#[derive(ValueEnum, Copy, Clone, Debug)]
+enum IoClass {
+    /// Get served before everyone else (requires root)
+    Realtime,
+    /// Share the disk with everyone else, the default for all processes
+    BestEffort,
+    /// Only get served when no one else needs the disk
+    Idle,
+}
+
+impl IoClass {
+    const fn with_level(self, level: u8) -> IoPriority {
+        match self {
+            Self::Realtime => IoPriority::RealTime(level),
+            Self::BestEffort => IoPriority::BestEffort(level),
+            Self::Idle => IoPriority::Idle,
+        }
+    }
+}
+
 #[derive(thiserror::Error, Debug)]
 enum CliError {
     #[error("{0}")]
@@ -159,10 +283,11 @@ fn main() -> Result<(), Report<CliError>> {
                 Report::from(error).attach(context).change_context(wrapper)
             }
             Error::NotFound { file: _ } => Report::from(wrapper).attach("Use --force to ignore."),
-            Error::PreserveRoot | Error::Join | Error::BadPath | Error::Internal => {
-                Report::from(wrapper)
+            Error::MountPoint { file: _ } => {
+                Report::from(wrapper).attach("Unmount the file system before removing it.")
             }
             Error::AlreadyExists { file: _ } => unreachable!(),
+            _ => Report::from(wrapper),
         }
     })
 }
@@ -172,6 +297,18 @@ fn remove(
         files,
         force,
         preserve_root,
+        one_file_system,
+        force_permissions,
+        clear_immutable,
+        order,
+        threads,
+        dir_buffer_size,
+        auto_tune,
+        queue_memory_limit,
+        rate,
+        io_class,
+        io_level,
+        no_io_uring,
         help: _,
     }: Rmz,
 ) -> Result<(), Error> {
@@ -179,6 +316,17 @@ fn remove(
         .files(files.into_iter())
         .force(force)
         .preserve_root(preserve_root)
+        .one_file_system(one_file_system)
+        .force_permissions(force_permissions)
+        .clear_immutable(clear_immutable)
+        .entry_order(order.into())
+        .maybe_threads(threads)
+        .maybe_dir_buffer_size(dir_buffer_size)
+        .auto_tune(auto_tune)
+        .maybe_queue_memory_limit(queue_memory_limit)
+        .maybe_rate_limit(rate)
+        .maybe_io_priority(io_class.map(|class| class.with_level(io_level.unwrap_or(4))))
+        .io_uring(!no_io_uring)
         .build()
         .run()
 }